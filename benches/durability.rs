@@ -0,0 +1,54 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::{BincodeCodec, Durability, KvStore, KvsEngine, StoreOptions};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// throughput of single sets at every durability level, fsync dominates everything else
+fn set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set");
+    for durability in [Durability::None, Durability::Flush, Durability::Fsync] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = StoreOptions{durability, ..StoreOptions::default()};
+        let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options).unwrap();
+        let mut key_id = 0u64;
+        group.bench_function(BenchmarkId::from_parameter(format!("{:?}", durability)), |b| {
+            b.iter(|| {
+                key_id += 1;
+                store.set(format!("key{}", key_id % 1000), "value".to_owned()).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+// 8 threads setting 100 keys each, where group commit shares the syncs between the threads
+fn concurrent_set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_set");
+    group.sample_size(10);
+    let group_commit = Durability::GroupCommit{window: Duration::from_millis(1), max_writes: 8};
+    for durability in [Durability::Fsync, group_commit] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = StoreOptions{durability, ..StoreOptions::default()};
+        let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options).unwrap();
+        group.bench_function(BenchmarkId::from_parameter(format!("{:?}", durability)), |b| {
+            b.iter(|| {
+                let handles = (0..8).map(|thread_id| {
+                    let store = store.clone();
+                    thread::spawn(move || {
+                        for key_id in 0..100 {
+                            store.set(format!("key{}_{}", thread_id, key_id), "value".to_owned()).unwrap();
+                        }
+                    })
+                }).collect::<Vec<_>>();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, set, concurrent_set);
+criterion_main!(benches);
@@ -0,0 +1,61 @@
+use crate::{Error, Result};
+use crate::codec::{JsonCodec, WireFormat};
+use crate::resource::{hello, read_message_len_async, read_message_payload_async, write_message_async, Request, Response};
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{BufReader, BufWriter};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+// same as KvsClient, for async code. speaks the same protocol, so it works against the same
+// servers, always in json.
+pub struct AsyncKvsClient {
+    request_stream: BufWriter<OwnedWriteHalf>,
+    response_stream: BufReader<OwnedReadHalf>,
+}
+
+impl AsyncKvsClient {
+    pub async fn connect(addr: SocketAddr) -> Result<AsyncKvsClient> {
+        let (read_half, write_half) = TcpStream::connect(addr).await?.into_split();
+        let mut client = AsyncKvsClient{
+            request_stream: BufWriter::new(write_half),
+            response_stream: BufReader::new(read_half),
+        };
+        // same handshake as KvsClient
+        match client.request(&hello::<String, String>(WireFormat::Json)).await? {
+            Response::Ack => Ok(client),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(Error::UnhandledError("unexpected response to hello".to_owned())),
+        }
+    }
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(&Request::Get{key}).await? {
+            Response::Value(val) => Ok(val),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(Error::UnhandledError("unexpected response to get".to_owned())),
+        }
+    }
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.request(&Request::Set{key, val: value}).await? {
+            Response::Ack => Ok(()),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(Error::UnhandledError("unexpected response to set".to_owned())),
+        }
+    }
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        match self.request(&Request::Rm{key}).await? {
+            Response::Ack => Ok(()),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(Error::UnhandledError("unexpected response to remove".to_owned())),
+        }
+    }
+
+    // sends a single request and waits for its response
+    async fn request(&mut self, req: &Request<String, String>) -> Result<Response<String>> {
+        write_message_async(&JsonCodec, &mut self.request_stream, req).await?;
+        match read_message_len_async(&mut self.response_stream).await? {
+            Some(len) => read_message_payload_async(&JsonCodec, &mut self.response_stream, len).await,
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by the server").into()),
+        }
+    }
+}
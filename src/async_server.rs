@@ -0,0 +1,193 @@
+use crate::{Error, Result, KvsEngine};
+use crate::codec::WireFormat;
+use crate::resource::{read_message_len_async, read_message_payload_async, write_message_async, RemoteError, Request, Response, WATCH_HEARTBEAT};
+use crate::server::{handle_request, select, DEFAULT_MAX_REQUEST_BYTES, REQUEST_LOG_TARGET};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use serde::{Serialize, de::DeserializeOwned};
+use std::fmt::Debug;
+use crossbeam_channel::RecvTimeoutError;
+use log::{error, info};
+use std::marker::PhantomData;
+use tokio::io::{AsyncWrite, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+// same as KvsServer, on a tokio runtime. every connection is a task rather than a thread of the
+// pool, so idle connections cost next to nothing. the engine stays blocking, its calls run on
+// tokio's blocking threads.
+pub struct AsyncKvsServer<K, V, E>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    E: KvsEngine<K, V>,
+{
+    // same as KvsServer's
+    databases: BTreeMap<u32, E>,
+    shutdown: CancellationToken,
+    max_request_bytes: u32,
+    _phantom: PhantomData<(K, V)>,
+}
+
+impl<K, V, E> AsyncKvsServer<K, V, E>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    E: KvsEngine<K, V>,
+{
+    pub fn new(engine: E) -> Self {
+        AsyncKvsServer{
+            databases: BTreeMap::from([(0, engine)]),
+            shutdown: CancellationToken::new(),
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+            _phantom: PhantomData,
+        }
+    }
+
+    // same as KvsServer::with_database
+    pub fn with_database(mut self, db: u32, engine: E) -> Self {
+        self.databases.insert(db, engine);
+        self
+    }
+
+    // same as KvsServer::with_max_request_bytes
+    pub fn with_max_request_bytes(mut self, max_request_bytes: u32) -> Self {
+        self.max_request_bytes = max_request_bytes;
+        self
+    }
+
+    // cancelling the token makes the server stop accepting connections. every connection then
+    // finishes the request it is serving and is closed, and run returns once all of them are.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    pub async fn run(self, addr: SocketAddr) -> Result<()> {
+        self.serve(TcpListener::bind(addr).await?).await
+    }
+
+    // accepts connections on the given listener until the shutdown token is cancelled
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        let connections = TaskTracker::new();
+        loop {
+            let stream = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                stream = listener.accept() => stream,
+            };
+            let stream = match stream {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    error!("failed to accept connection: {}", err);
+                    continue;
+                },
+            };
+            let databases = self.databases.clone();
+            let shutdown = self.shutdown.clone();
+            let max_request_bytes = self.max_request_bytes;
+            connections.spawn(async move {
+                let peer = stream.peer_addr();
+                if let Err(err) = handle_client::<K, V, E>(databases, stream, max_request_bytes, shutdown).await {
+                    error!("error while serving {:?}: {}", peer, err);
+                }
+            });
+        }
+
+        connections.close();
+        connections.wait().await;
+        Ok(())
+    }
+}
+
+async fn handle_client<K, V, E>(databases: BTreeMap<u32, E>, stream: TcpStream, max_request_bytes: u32, shutdown: CancellationToken) -> Result<()>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    E: KvsEngine<K, V>,
+{
+    let (read_half, write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+    let mut writer = BufWriter::new(write_half);
+
+    let mut engine = databases[&0].clone();
+    let mut codec = WireFormat::Json;
+    loop {
+        // only waiting for the next request is interrupted, a request that has arrived is served
+        let len = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            len = read_message_len_async(&mut reader) => len?,
+        };
+        let Some(len) = len else {
+            break;
+        };
+        if len > max_request_bytes {
+            let msg = format!("request of {} bytes exceeds the limit of {} bytes", len, max_request_bytes);
+            write_message_async(&codec, &mut writer, &Response::<V>::Err(RemoteError::Other(msg.clone()))).await?;
+            return Err(Error::UnhandledError(msg));
+        }
+
+        let req = read_message_payload_async::<Request<K, V>, _>(&codec, &mut reader, len).await?;
+        // every codec is accepted
+        let requested = match &req {
+            Request::Hello{codec, ..} => Some(*codec),
+            _ => None,
+        };
+        let req = match req {
+            Request::Watch{key} => return serve_watch::<K, V, E, _>(engine, key, codec, &mut writer, shutdown).await,
+            Request::Select{db} => {
+                let resp = select::<K, V, E>(&databases, db);
+                if matches!(resp, Response::Ack) {
+                    engine = databases[&db].clone();
+                }
+                write_message_async(&codec, &mut writer, &resp).await?;
+                continue;
+            },
+            req => req,
+        };
+        // the engine moves to the blocking thread and back, so the connection keeps the file
+        // handles it has opened instead of cloning the engine for every request
+        let (returned, resp) = tokio::task::spawn_blocking(move || {
+            let resp = handle_request(&engine, req);
+            (engine, resp)
+        })
+        .await
+        .map_err(|err| Error::UnhandledError(format!("engine task failed: {}", err)))?;
+        engine = returned;
+        write_message_async(&codec, &mut writer, &resp).await?;
+        if let (Some(requested), Response::Ack) = (requested, &resp) {
+            codec = requested;
+        }
+    }
+    Ok(())
+}
+
+// same as the sync server's, the channel is waited on from a blocking thread, one wait at a
+// time. shutting down leaves the pending wait to run out on its own.
+async fn serve_watch<K, V, E, W>(engine: E, key: K, codec: WireFormat, writer: &mut W, shutdown: CancellationToken) -> Result<()>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    E: KvsEngine<K, V>,
+    W: AsyncWrite + Unpin,
+{
+    info!(target: REQUEST_LOG_TARGET, "watch {:?}", key);
+    let mut events = engine.watch(key);
+    write_message_async(&codec, writer, &Response::<V>::Ack).await?;
+    loop {
+        let wait = tokio::task::spawn_blocking(move || {
+            let event = events.recv_timeout(WATCH_HEARTBEAT);
+            (events, event)
+        });
+        let (returned, event) = tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            res = wait => res.map_err(|err| Error::UnhandledError(format!("watch task failed: {}", err)))?,
+        };
+        events = returned;
+        let resp = match event {
+            Ok(event) => Response::<V>::Event(event),
+            Err(RecvTimeoutError::Timeout) => Response::<V>::Ack,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        write_message_async(&codec, writer, &resp).await?;
+    }
+}
@@ -1,6 +1,9 @@
-use clap::{Parser, Subcommand, value_parser};
-use kvs::{KvsClient, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum, value_parser};
+use kvs::{BincodeCodec, Endpoint, Entry, Error, JsonCodec, KvStore, KvsClient, LogRecord, Result};
+use serde::{Serialize, de::DeserializeOwned};
+use std::fmt::Debug;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
@@ -13,45 +16,188 @@ struct Opt {
     command: Command,
 }
 
+// where the server is, shared by every command
+#[derive(Args, Debug)]
+struct Server {
+    #[arg(
+        long,
+        help = "Sets the server address",
+        default_value(DEFAULT_LISTENING_ADDRESS),
+        value_parser(value_parser!(SocketAddr))
+    )]
+    addr: SocketAddr,
+    #[cfg(unix)]
+    #[arg(
+        long,
+        help = "Connects to the server's unix socket instead of its address",
+        value_name = "PATH",
+        conflicts_with = "addr",
+    )]
+    socket: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        help = "Sets the type of the keys the server stores",
+        default_value = "string",
+    )]
+    key_type: KeyType,
+    #[arg(
+        long,
+        value_enum,
+        help = "Sets the type of the values the server stores",
+        default_value = "string",
+    )]
+    value_type: ValueType,
+    #[arg(
+        long,
+        help = "Selects one of the server's databases",
+        default_value = "0",
+    )]
+    db: u32,
+}
+
+// the key types a server may be built for. json keys are left out, json values have no order.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum KeyType {
+    String,
+    I64,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ValueType {
+    String,
+    I64,
+    Json,
+}
+
+// the codecs a store may write its log files with
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LogCodec {
+    Json,
+    Bincode,
+}
+
+// a type keys or values are given as on the command line and printed as
+trait CliType: Sized {
+    fn parse(s: &str) -> Result<Self>;
+    fn print(&self) -> String;
+}
+
+impl CliType for String {
+    fn parse(s: &str) -> Result<Self> {
+        Ok(s.to_owned())
+    }
+
+    fn print(&self) -> String {
+        self.clone()
+    }
+}
+
+impl CliType for i64 {
+    fn parse(s: &str) -> Result<Self> {
+        s.parse().map_err(|err| Error::UnhandledError(format!("invalid i64 {:?}: {}", s, err)))
+    }
+
+    fn print(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl CliType for serde_json::Value {
+    fn parse(s: &str) -> Result<Self> {
+        serde_json::from_str(s).map_err(|err| Error::UnhandledError(format!("invalid JSON {:?}: {}", s, err)))
+    }
+
+    fn print(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl Server {
+    fn endpoint(&self) -> Endpoint {
+        #[cfg(unix)]
+        if let Some(path) = &self.socket {
+            return Endpoint::Unix(path.clone());
+        }
+        Endpoint::Tcp(self.addr)
+    }
+
+    // connects to the server, on the selected database
+    fn connect<K, V>(&self) -> Result<KvsClient<K, V>>
+    where
+        K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+        V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    {
+        let mut client = KvsClient::<K, V>::connect(self.endpoint())?;
+        if self.db != 0 {
+            client.select(self.db)?;
+        }
+        Ok(client)
+    }
+}
+
 #[derive(Debug, Subcommand)]
 enum Command {
-    #[command(id = "get", about = "Get the string value of a given string key")]
+    #[command(id = "get", about = "Get the value of a given key")]
     Get {
-        #[arg(id = "KEY", help = "A string key")]
+        #[arg(id = "KEY", help = "A key of the server's key type", allow_negative_numbers = true)]
         key: String,
-        #[arg(
-            long,
-            help = "Sets the server address",
-            default_value(DEFAULT_LISTENING_ADDRESS),
-            value_parser(value_parser!(SocketAddr))
-        )]
-        addr: SocketAddr,
+        #[command(flatten)]
+        server: Server,
     },
-    #[command(id = "set", about = "Set the value of a string key to a string")]
+    #[command(id = "set", about = "Set the value of a key")]
     Set {
-        #[arg(id = "KEY", help = "A string key")]
+        #[arg(id = "KEY", help = "A key of the server's key type", allow_negative_numbers = true)]
         key: String,
-        #[arg(id = "VALUE", help = "The string value of the key")]
+        #[arg(id = "VALUE", help = "The value of the key, of the server's value type", allow_negative_numbers = true)]
         value: String,
-        #[arg(
-            long,
-            help = "Sets the server address",
-            default_value(DEFAULT_LISTENING_ADDRESS),
-            value_parser(value_parser!(SocketAddr))
-        )]
-        addr: SocketAddr,
+        #[command(flatten)]
+        server: Server,
     },
-    #[command(id = "rm", about = "Remove a given string key")]
+    #[command(id = "rm", about = "Remove a given key")]
     Remove {
-        #[arg(id = "KEY", help = "A string key")]
+        #[arg(id = "KEY", help = "A key of the server's key type", allow_negative_numbers = true)]
         key: String,
+        #[command(flatten)]
+        server: Server,
+    },
+    #[command(id = "compact", about = "Compact the server's log files")]
+    Compact {
+        #[command(flatten)]
+        server: Server,
+    },
+    #[command(id = "stats", about = "Print statistics of the server's store")]
+    Stats {
+        #[arg(long, help = "Prints the statistics as JSON")]
+        json: bool,
+        #[command(flatten)]
+        server: Server,
+    },
+    #[command(id = "dump", about = "Print every entry of the log files in a data directory, without a server")]
+    Dump {
+        #[arg(long, help = "The data directory of the store", value_name = "PATH")]
+        dir: PathBuf,
         #[arg(
             long,
-            help = "Sets the server address",
-            default_value(DEFAULT_LISTENING_ADDRESS),
-            value_parser(value_parser!(SocketAddr))
+            value_enum,
+            help = "Sets the type of the keys the store holds",
+            default_value = "string",
         )]
-        addr: SocketAddr,
+        key_type: KeyType,
+        #[arg(
+            long,
+            value_enum,
+            help = "Sets the type of the values the store holds",
+            default_value = "string",
+        )]
+        value_type: ValueType,
+        #[arg(
+            long,
+            value_enum,
+            help = "Sets the codec the log files were written with",
+            default_value = "bincode",
+        )]
+        codec: LogCodec,
     },
 }
 
@@ -63,24 +209,110 @@ fn main() {
     }
 }
 
+impl Command {
+    // the types of the keys and values the command works with
+    fn types(&self) -> (KeyType, ValueType) {
+        match self {
+            Command::Get { server, .. }
+            | Command::Set { server, .. }
+            | Command::Remove { server, .. }
+            | Command::Compact { server }
+            | Command::Stats { server, .. } => (server.key_type, server.value_type),
+            Command::Dump { key_type, value_type, .. } => (*key_type, *value_type),
+        }
+    }
+}
+
+// every command connects with the types of the server, the handshake fails otherwise. dump
+// decodes the log files with the types given, the entries fail to decode otherwise.
 fn run(opt: Opt) -> Result<()> {
-    match opt.command {
-        Command::Get { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+    let (key_type, value_type) = opt.command.types();
+    match key_type {
+        KeyType::String => run_with_key_type::<String>(opt.command, value_type),
+        KeyType::I64 => run_with_key_type::<i64>(opt.command, value_type),
+    }
+}
+
+fn run_with_key_type<K>(command: Command, value_type: ValueType) -> Result<()>
+where
+    K: CliType + Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+{
+    match value_type {
+        ValueType::String => run_typed::<K, String>(command),
+        ValueType::I64 => run_typed::<K, i64>(command),
+        ValueType::Json => run_typed::<K, serde_json::Value>(command),
+    }
+}
+
+fn run_typed<K, V>(command: Command) -> Result<()>
+where
+    K: CliType + Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: CliType + Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    match command {
+        Command::Get { key, server } => {
+            let key = K::parse(&key)?;
+            let mut client = server.connect::<K, V>()?;
             if let Some(value) = client.get(key)? {
-                println!("{}", value);
+                println!("{}", value.print());
             } else {
                 println!("Key not found");
             }
         }
-        Command::Set { key, value, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+        Command::Set { key, value, server } => {
+            let (key, value) = (K::parse(&key)?, V::parse(&value)?);
+            let mut client = server.connect::<K, V>()?;
             client.set(key, value)?;
         }
-        Command::Remove { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+        Command::Remove { key, server } => {
+            let key = K::parse(&key)?;
+            let mut client = server.connect::<K, V>()?;
             client.remove(key)?;
         }
+        Command::Compact { server } => {
+            let mut client = server.connect::<K, V>()?;
+            client.compact()?;
+        }
+        Command::Stats { json, server } => {
+            let mut client = server.connect::<K, V>()?;
+            let stats = client.stats()?;
+            if json {
+                println!("{}", serde_json::to_string(&stats)?);
+            } else {
+                println!("Live keys: {}", stats.live_keys);
+                println!("Segments: {}", stats.segments);
+                println!("Uncompacted bytes: {}", stats.uncompacted_bytes);
+                println!("Disk bytes: {}", stats.disk_bytes);
+            }
+        }
+        Command::Dump { dir, codec: LogCodec::Bincode, .. } => KvStore::<K, V>::dump(&dir, &BincodeCodec, print_record)?,
+        Command::Dump { dir, codec: LogCodec::Json, .. } => KvStore::<K, V, JsonCodec>::dump(&dir, &JsonCodec, print_record)?,
+    }
+    Ok(())
+}
+
+// one line per entry: file, byte range, then the entry with its key and value as given on the
+// command line, quoted
+fn print_record<K, V>(record: LogRecord<K, V>) -> Result<()>
+where
+    K: CliType + Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: CliType + Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    let location = format!("{}.log {}..{}", record.file_id, record.start, record.end);
+    match record.entry {
+        Entry::Set{key, val, expires_at: None, version, written_at} => {
+            println!("{} set {:?} {:?} version={} written_at={}", location, key.print(), val.print(), version, written_at)
+        },
+        Entry::Set{key, val, expires_at: Some(expires_at), version, written_at} => {
+            println!(
+                "{} set {:?} {:?} version={} written_at={} expires_at={}",
+                location, key.print(), val.print(), version, written_at, expires_at,
+            )
+        },
+        Entry::Rm{key} => println!("{} rm {:?}", location, key.print()),
+        Entry::Clear => println!("{} clear", location),
+        Entry::Begin => println!("{} begin", location),
+        Entry::Commit => println!("{} commit", location),
     }
     Ok(())
 }
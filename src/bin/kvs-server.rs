@@ -1,13 +1,16 @@
 use clap::{Parser, ValueEnum};
 use kvs::*;
 use log::LevelFilter;
-use log::{error, info, warn};
+use log::{error, info};
 use simple_logger::SimpleLogger;
 use std::env::current_dir;
-use std::fs;
-use std::net::SocketAddr;
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
+use std::thread;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
@@ -22,12 +25,80 @@ struct Opt {
         default_value(DEFAULT_LISTENING_ADDRESS),
     )]
     addr: SocketAddr,
+    #[cfg(unix)]
+    #[arg(
+        long,
+        help = "Listens on a unix socket at PATH instead of the address",
+        value_name = "PATH",
+        conflicts_with = "addr",
+    )]
+    socket: Option<PathBuf>,
     #[arg(
         long,
         value_enum,
         help = "Sets the storage engine",
     )]
     engine: Option<Engine>,
+    #[arg(
+        long,
+        help = "Sets the number of worker threads [default: number of logical CPUs]",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    threads: Option<u32>,
+    #[arg(
+        long,
+        help = "Sets the directory holding the data [default: current directory]",
+        value_name = "PATH",
+    )]
+    dir: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Logs every request with its outcome and the time it took",
+    )]
+    log_requests: bool,
+    #[arg(
+        long,
+        value_enum,
+        help = "Sets the level of the messages logged",
+        default_value = "info",
+    )]
+    log_level: LogLevel,
+    #[arg(
+        long,
+        help = "Serves metrics in the Prometheus text format at http://IP:PORT/metrics",
+        value_name = "IP:PORT",
+    )]
+    metrics_addr: Option<SocketAddr>,
+    #[arg(
+        long,
+        help = "Sets the number of databases clients can select, database N > 0 is kept in dbN under the data directory",
+        value_name = "N",
+        default_value = "1",
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    databases: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> LevelFilter {
+        match level {
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
 }
 
 #[allow(non_camel_case_types)]
@@ -48,15 +119,25 @@ impl FromStr for Engine {
 }
 
 fn main() {
-    SimpleLogger::new().with_level(LevelFilter::Info).init().unwrap();
     let mut opt = Opt::parse();
-    let res = current_engine().and_then(move |curr_engine| {
+    let level = LevelFilter::from(opt.log_level);
+    // requests are only logged when asked for, whatever the level of everything else
+    let request_level = if opt.log_requests { LevelFilter::Info } else { level.min(LevelFilter::Warn) };
+    SimpleLogger::new()
+        .with_level(level)
+        .with_module_level(REQUEST_LOG_TARGET, request_level)
+        .init()
+        .unwrap();
+    let res = data_dir(&opt).and_then(|dir| current_engine(&dir)).and_then(move |curr_engine| {
         if opt.engine.is_none() {
             opt.engine = curr_engine;
         }
-        if curr_engine.is_some() && opt.engine != curr_engine {
-            error!("Wrong engine!");
-            exit(1);
+        if let (Some(curr_engine), Some(engine)) = (curr_engine, opt.engine) {
+            if curr_engine != engine {
+                return Err(Error::UnhandledError(format!(
+                    "Wrong engine: the data was written by {:?}, not {:?}", curr_engine, engine,
+                )));
+            }
         }
         run(opt)
     });
@@ -68,35 +149,99 @@ fn main() {
 
 fn run(opt: Opt) -> Result<()> {
     let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+    let threads = opt.threads.map_or_else(default_threads, |n| n as usize);
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {:?}", engine);
-    info!("Listening on {}", opt.addr);
+    info!("Worker threads: {}", threads);
+    let endpoint = endpoint(&opt);
+    info!("Listening on {}", endpoint);
+    let dir = data_dir(&opt)?;
+    info!("Data directory: {}", dir.display());
+    info!("Databases: {}", opt.databases);
 
-    // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{:?}", engine))?;
+    fs::create_dir_all(&dir)?;
+    write_engine(&dir, engine)?;
 
     match engine {
-        Engine::kvs => run_with_engine(KvStore::open(&current_dir()?)?, opt.addr),
+        Engine::kvs => {
+            let engines = (0..opt.databases)
+                .map(|db| KvStore::open(&database_dir(&dir, db)))
+                .collect::<Result<Vec<_>>>()?;
+            run_with_engine(engines, endpoint, threads, opt.metrics_addr)
+        },
+    }
+}
+
+fn data_dir(opt: &Opt) -> Result<PathBuf> {
+    match &opt.dir {
+        Some(dir) => Ok(dir.clone()),
+        None => Ok(current_dir()?),
+    }
+}
+
+// database 0 is kept in the data directory itself, so that a server started with more databases
+// keeps serving the data it had as the first
+fn database_dir(dir: &Path, db: u32) -> PathBuf {
+    match db {
+        0 => dir.to_owned(),
+        db => dir.join(format!("db{}", db)),
+    }
+}
+
+fn endpoint(opt: &Opt) -> Endpoint {
+    #[cfg(unix)]
+    if let Some(path) = &opt.socket {
+        return Endpoint::Unix(path.clone());
     }
+    Endpoint::Tcp(opt.addr)
 }
 
-fn run_with_engine<E: KvsEngine<String, String>>(engine: E, addr: SocketAddr) -> Result<()> {
-    let pool = ThreadPool::new(10);
-    let server = KvsServer::<String, String, E>::new(engine, pool);
-    server.run(addr)
+// serves the engines as databases 0, 1 and so on, metrics are only exported for database 0
+fn run_with_engine<E: KvsEngine<String, String>>(engines: Vec<E>, endpoint: Endpoint, threads: usize, metrics_addr: Option<SocketAddr>) -> Result<()> {
+    let engine = engines[0].clone();
+    // bound up front, so that an unavailable address fails the start rather than the first scrape
+    if let Some(addr) = metrics_addr {
+        let listener = TcpListener::bind(addr)?;
+        info!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+        let exporter = MetricsExporter::<String, String, E>::new(engine.clone());
+        thread::spawn(move || {
+            if let Err(e) = exporter.serve(listener) {
+                error!("metrics exporter failed: {}", e);
+            }
+        });
+    }
+    let pool = ThreadPool::new(threads);
+    let server = (1..).zip(engines.into_iter().skip(1))
+        .fold(KvsServer::<String, String, E>::new(engine, pool), |server, (db, engine)| server.with_database(db, engine));
+    server.run(endpoint)
 }
 
-fn current_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join("engine");
+fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+fn current_engine(dir: &Path) -> Result<Option<Engine>> {
+    let engine = dir.join("engine");
     if !engine.exists() {
         return Ok(None);
     }
 
-    match fs::read_to_string(engine)?.parse() {
+    // an unreadable marker may hide data of another engine, so it is never silently replaced
+    match fs::read_to_string(&engine)?.parse() {
         Ok(engine) => Ok(Some(engine)),
-        Err(e) => {
-            warn!("The content of engine file is invalid: {}", e);
-            Ok(None)
-        }
+        Err(e) => Err(Error::UnhandledError(format!(
+            "The content of engine file {} is invalid, fix or remove it: {}", engine.display(), e,
+        ))),
     }
 }
+
+// replaces the engine file through a rename, so a crash leaves either the old or the new one
+fn write_engine(dir: &Path, engine: Engine) -> Result<()> {
+    let tmp = dir.join("engine.tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(format!("{:?}", engine).as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join("engine"))?;
+
+    Ok(())
+}
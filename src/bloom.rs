@@ -0,0 +1,67 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+// a set of byte strings that may report items it does not hold, at roughly the rate it was
+// sized for, but never misses an item it holds
+#[derive(Clone, Debug)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    len: usize,
+    capacity: usize,
+}
+
+impl BloomFilter {
+    // sizes the filter so that it reports absent items with the given probability once it holds
+    // capacity items, more items raise the rate
+    pub fn new(capacity: usize, false_positive_rate: f64) -> BloomFilter {
+        let capacity = capacity.max(1);
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(capacity as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / capacity as f64) * ln2).round().max(1.0) as u32;
+
+        BloomFilter{
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+            len: 0,
+            capacity,
+        }
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        for bit in self.bit_positions(item) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    // false if the item was never inserted, true if it was or, rarely, if it was not
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.bit_positions(item).all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    // number of insertions, counting repeated items every time
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // derives every position from two hashes of the item, h1 + i * h2
+    fn bit_positions(&self, item: &[u8]) -> impl Iterator<Item = u64> {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(item);
+        let h1 = hasher.finish();
+        let h2 = u64::from(crc32fast::hash(item)) | 1;
+        let num_bits = self.num_bits;
+        (0..u64::from(self.num_hashes)).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+}
@@ -1,55 +1,457 @@
-use crate::{Error, Result};
-use crate::resource::{Request, Response};
-use std::io::{BufReader, Write};
-use serde::Deserialize;
-use serde_json::de::{Deserializer, IoRead};
-use std::net::{SocketAddr, TcpStream};
-
-pub struct KvsClient {
-    request_stream: TcpStream,
-    response_stream: Deserializer<IoRead<BufReader<TcpStream>>>,
-}
-
-impl KvsClient {
-    pub fn connect(addr: SocketAddr) -> Result<KvsClient> {
-        let request_stream = TcpStream::connect(addr)?;
-        let response_stream = Deserializer::from_reader(BufReader::new(request_stream.try_clone()?));
-        Ok(KvsClient{
-            request_stream,
+use crate::{Endpoint, Error, Event, Result, StoreStats};
+use crate::codec::WireFormat;
+use crate::endpoint::Stream;
+use crate::resource::{hello, read_message, write_message, write_message_unflushed, RemoteError, Request, Response};
+use serde::{Serialize, de::DeserializeOwned};
+use std::fmt::Debug;
+use std::io::Write;
+use std::io::{self, BufReader, BufWriter};
+use std::marker::PhantomData;
+use std::thread;
+use std::time::Duration;
+
+// talks to a server storing keys of type K and values of type V, which the handshake checks
+pub struct KvsClient<K = String, V = String>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    connector: Connector,
+    retry: Option<Retry>,
+    // the codec of the connection, json until the server has accepted the one asked for
+    codec: WireFormat,
+    request_stream: BufWriter<Stream>,
+    response_stream: BufReader<Stream>,
+    _phantom: PhantomData<fn() -> (K, V)>,
+}
+
+// everything needed to open the connection again after losing it
+#[derive(Clone)]
+struct Connector {
+    endpoint: Endpoint,
+    timeout: Option<Duration>,
+    // sent first on every new connection once authenticate succeeded
+    auth_token: Option<String>,
+    // selected again on every new connection, after authenticating
+    db: u32,
+    // asked for in the hello of every new connection
+    codec: WireFormat,
+    #[cfg(feature = "tls")]
+    tls: Option<crate::tls::ClientTls>,
+}
+
+impl Connector {
+    fn new(endpoint: Endpoint, timeout: Option<Duration>) -> Connector {
+        Connector{
+            endpoint,
+            timeout,
+            auth_token: None,
+            db: 0,
+            codec: WireFormat::Json,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    fn connect(&self) -> Result<Stream> {
+        let stream = Stream::connect(&self.endpoint, self.timeout).map_err(timeout_error)?;
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            return tls.connect(stream).map_err(timeout_error);
+        }
+        Ok(stream)
+    }
+}
+
+// how often and how patiently a client reconnects after losing its connection
+#[derive(Clone, Copy)]
+struct Retry {
+    max_retries: u32,
+    backoff: Duration,
+}
+
+impl Retry {
+    // exponential backoff, doubling the initial delay with every attempt
+    fn delay(&self, attempt: u32) -> Duration {
+        self.backoff.saturating_mul(1 << attempt.min(16))
+    }
+}
+
+impl<K, V> KvsClient<K, V>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    // connects over tcp when given a SocketAddr, or to any Endpoint
+    pub fn connect(endpoint: impl Into<Endpoint>) -> Result<KvsClient<K, V>> {
+        KvsClient::open(Connector::new(endpoint.into(), None))
+    }
+    // same as connect, but gives up on connecting, sending a request or waiting for a response
+    // after the given timeout with Error::Timeout. the connection is left in an unknown state by
+    // a timeout, so the client should be dropped afterwards.
+    pub fn connect_with_timeout(endpoint: impl Into<Endpoint>, timeout: Duration) -> Result<KvsClient<K, V>> {
+        KvsClient::open(Connector::new(endpoint.into(), Some(timeout)))
+    }
+    // same as connect, but talks to the server in the given codec once the handshake is done. a
+    // server that does not accept it fails the connect with Error::Incompatible.
+    pub fn connect_with_codec(endpoint: impl Into<Endpoint>, codec: WireFormat) -> Result<KvsClient<K, V>> {
+        let mut connector = Connector::new(endpoint.into(), None);
+        connector.codec = codec;
+        KvsClient::open(connector)
+    }
+    // same as connect, but retries connecting up to max_retries times, waiting backoff before the
+    // first retry and twice as long before each following one. the client then reconnects the
+    // same way whenever it finds its connection broken, and resends the request that failed.
+    pub fn connect_with_retry(endpoint: impl Into<Endpoint>, max_retries: u32, backoff: Duration) -> Result<KvsClient<K, V>> {
+        let connector = Connector::new(endpoint.into(), None);
+        let retry = Retry{max_retries, backoff};
+        let mut attempt = 0;
+        loop {
+            match KvsClient::open(connector.clone()) {
+                Ok(mut client) => {
+                    client.retry = Some(retry);
+                    return Ok(client);
+                },
+                Err(err) if attempt < max_retries && is_connection_error(&err) => {
+                    thread::sleep(retry.delay(attempt));
+                    attempt += 1;
+                },
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    // same as connect, but encrypts the connection with tls. the server has to present a
+    // certificate for server_name that chains up to one of the roots.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls(endpoint: impl Into<Endpoint>, server_name: &str, roots: rustls::RootCertStore) -> Result<KvsClient<K, V>> {
+        let mut connector = Connector::new(endpoint.into(), None);
+        connector.tls = Some(crate::tls::ClientTls::new(server_name, roots)?);
+        KvsClient::open(connector)
+    }
+    fn open(connector: Connector) -> Result<KvsClient<K, V>> {
+        let stream = connector.connect()?;
+        let response_stream = BufReader::new(stream.try_clone()?);
+        let mut client = KvsClient{
+            connector,
+            retry: None,
+            codec: WireFormat::Json,
+            request_stream: BufWriter::new(stream),
             response_stream,
+            _phantom: PhantomData,
+        };
+        // a server storing other types would fail to decode requests, or decode them as
+        // something else
+        match client.try_request(&hello::<K, V>(client.connector.codec))? {
+            Response::Ack => client.codec = client.connector.codec,
+            Response::Err(err) => return Err(err.into()),
+            _ => return Err(Error::UnhandledError("unexpected response to hello".to_owned())),
+        }
+        // a reconnecting client proves itself again before resending anything
+        if let Some(token) = client.connector.auth_token.clone() {
+            match client.try_request(&Request::Auth{token})? {
+                Response::Ack => {},
+                Response::Err(err) => return Err(err.into()),
+                _ => return Err(Error::UnhandledError("unexpected response to auth".to_owned())),
+            }
+        }
+        // and goes back to the database it was using
+        if client.connector.db != 0 {
+            match client.try_request(&Request::Select{db: client.connector.db})? {
+                Response::Ack => {},
+                Response::Err(err) => return Err(err.into()),
+                _ => return Err(Error::UnhandledError("unexpected response to select".to_owned())),
+            }
+        }
+        Ok(client)
+    }
+    // sends the server's secret, which a server built with_auth_token expects before any other
+    // request. a wrong token fails with Error::AuthFailed and the server closes the connection.
+    // the token is kept to authenticate again after reconnecting.
+    pub fn authenticate(&mut self, token: &str) -> Result<()> {
+        match self.request(&Request::Auth{token: token.to_owned()})? {
+            Response::Ack => {
+                self.connector.auth_token = Some(token.to_owned());
+                Ok(())
+            },
+            Response::Err(err) => Err(err.into()),
+            _ => Err(Error::UnhandledError("unexpected response to auth".to_owned())),
+        }
+    }
+    // switches to another of the server's databases, which keys are separate from those of the
+    // others. a client starts on database 0 and keeps the selected one across reconnects.
+    pub fn select(&mut self, db: u32) -> Result<()> {
+        match self.request(&Request::Select{db})? {
+            Response::Ack => {
+                self.connector.db = db;
+                Ok(())
+            },
+            Response::Err(err) => Err(err.into()),
+            _ => Err(Error::UnhandledError("unexpected response to select".to_owned())),
+        }
+    }
+    pub fn get(&mut self, key: K) -> Result<Option<V>> {
+        match self.request(&Request::Get{key})? {
+            Response::Value(val) => Ok(val),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(Error::UnhandledError("unexpected response to get".to_owned())),
+        }
+    }
+    // fetches several keys in a single round trip, the i-th value belongs to the i-th key
+    pub fn get_many(&mut self, keys: Vec<K>) -> Result<Vec<Option<V>>> {
+        match self.request(&Request::GetMany{keys})? {
+            Response::Many(vals) => Ok(vals),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(Error::UnhandledError("unexpected response to get_many".to_owned())),
+        }
+    }
+    // checks whether the key exists, the value stays on the server
+    pub fn exists(&mut self, key: K) -> Result<bool> {
+        match self.request(&Request::Exists{key})? {
+            Response::Exists(exists) => Ok(exists),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(Error::UnhandledError("unexpected response to exists".to_owned())),
+        }
+    }
+    pub fn remove(&mut self, key: K) -> Result<()> {
+        match self.request_resent(&Request::Rm{key})? {
+            (Response::Ack, _) => Ok(()),
+            // the first attempt may have removed the key before the connection broke
+            (Response::Err(RemoteError::DoesNotExist{..}), true) => Ok(()),
+            (Response::Err(err), _) => Err(err.into()),
+            _ => Err(Error::UnhandledError("unexpected response to remove".to_owned())),
+        }
+    }
+    pub fn set(&mut self, key: K, value: V) -> Result<()> {
+        match self.request(&Request::Set{key, val: value})? {
+            Response::Ack => Ok(()),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(Error::UnhandledError("unexpected response to set".to_owned())),
+        }
+    }
+    // asks the server to compact its log files now
+    pub fn compact(&mut self) -> Result<()> {
+        match self.request(&Request::Compact)? {
+            Response::Ack => Ok(()),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(Error::UnhandledError("unexpected response to compact".to_owned())),
+        }
+    }
+    // checks that the server is up and answering requests, without touching its store
+    pub fn ping(&mut self) -> Result<()> {
+        match self.request(&Request::Ping)? {
+            Response::Ack => Ok(()),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(Error::UnhandledError("unexpected response to ping".to_owned())),
+        }
+    }
+    // fetches the statistics of the server's store
+    pub fn stats(&mut self) -> Result<StoreStats> {
+        match self.request(&Request::Stats)? {
+            Response::Stats(stats) => Ok(stats),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(Error::UnhandledError("unexpected response to stats".to_owned())),
+        }
+    }
+
+    // turns the connection into a stream of the changes of the key, read through the returned
+    // iterator. the server sends an Ack at least every WATCH_HEARTBEAT, which the iterator skips,
+    // so a client built with a timeout shorter than that fails with Error::Timeout while the key
+    // does not change.
+    pub fn watch(mut self, key: K) -> Result<Watch<K, V>> {
+        match self.request(&Request::Watch{key})? {
+            Response::Ack => Ok(Watch{client: self}),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(Error::UnhandledError("unexpected response to watch".to_owned())),
+        }
+    }
+
+    // flushes anything not sent yet and ends the connection cleanly, then waits for the server
+    // to close its end, which it does once it has served every request it was sent. dropping
+    // the client waits for neither. responses that were not read are discarded.
+    pub fn close(mut self) -> Result<()> {
+        self.request_stream.flush().map_err(timeout_error)?;
+        self.request_stream.get_ref().shutdown_write().map_err(timeout_error)?;
+        match io::copy(&mut self.response_stream, &mut io::sink()) {
+            Ok(_) => Ok(()),
+            // a tls session the server closed without a close_notify
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+            Err(err) => Err(timeout_error(err)),
+        }
+    }
+
+    // queues requests to be sent together, without waiting for each response before sending
+    // the next one
+    pub fn pipeline(&mut self) -> Pipeline<'_, K, V> {
+        Pipeline{client: self, requests: Vec::new()}
+    }
+
+    // sends a single request and waits for its response, reconnecting and resending the very
+    // same request if the connection turns out to be broken and the client was built to retry
+    fn request(&mut self, req: &Request<K, V>) -> Result<Response<V>> {
+        self.request_resent(req).map(|(resp, _)| resp)
+    }
+
+    // like request, also telling whether the request was resent, in which case an earlier
+    // attempt may have been applied as well
+    fn request_resent(&mut self, req: &Request<K, V>) -> Result<(Response<V>, bool)> {
+        let mut attempt = 0;
+        loop {
+            match self.try_request(req) {
+                Err(err) if is_connection_error(&err) => {
+                    let retry = match self.retry {
+                        Some(retry) if attempt < retry.max_retries => retry,
+                        _ => return Err(err),
+                    };
+                    thread::sleep(retry.delay(attempt));
+                    attempt += 1;
+                    // a failed reconnect leaves the broken streams in place, so the next attempt
+                    // fails right away and counts against the retries as well
+                    if let Ok(client) = KvsClient::<K, V>::open(self.connector.clone()) {
+                        self.codec = client.codec;
+                        self.request_stream = client.request_stream;
+                        self.response_stream = client.response_stream;
+                    }
+                },
+                res => return res.map(|resp| (resp, attempt > 0)),
+            }
+        }
+    }
+
+    fn try_request(&mut self, req: &Request<K, V>) -> Result<Response<V>> {
+        write_message(&self.codec, &mut self.request_stream, req).map_err(timeout_error)?;
+        read_message(&self.codec, &mut self.response_stream).map_err(timeout_error)?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by the server").into()
         })
     }
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let payload = serde_json::to_string(&Request::<String, String>::Get{key})?;
-        let b = payload.as_bytes();
-        self.request_stream.write_all(b)?;
-        self.request_stream.flush()?;
-        let response = Response::<String>::deserialize(&mut self.response_stream)?;
-        match response {
-            Response::Ok(val) => Ok(val),
-            Response::Err(err) => Err(Error::UnhandledError(err)),
-        }
-    }
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        let payload = serde_json::to_string(&Request::<String, String>::Rm{key})?;
-        let b = payload.as_bytes();
-        self.request_stream.write_all(b)?;
-        self.request_stream.flush()?;
-        let response = Response::<String>::deserialize(&mut self.response_stream)?;
-        match response {
-            Response::Ok(_) => Ok(()),
-            Response::Err(err) => Err(Error::UnhandledError(err)),
-        }
-    }
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let payload = serde_json::to_string(&Request::<String, String>::Set{key, val: value})?;
-        let b = payload.as_bytes();
-        self.request_stream.write_all(b)?;
-        self.request_stream.flush()?;
-        let response = Response::<String>::deserialize(&mut self.response_stream)?;
-        match response {
-            Response::Ok(_) => Ok(()),
-            Response::Err(err) => Err(Error::UnhandledError(err)),
+}
+
+// requests queued on a client by KvsClient::pipeline, sent by flush. the server answers them one
+// by one in order, so the whole batch costs a single round trip. responses pile up in the socket
+// buffers until flush reads them, so a batch should stay in the thousands of small requests at
+// most, or both sides end up waiting for each other to read.
+pub struct Pipeline<'a, K = String, V = String>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    client: &'a mut KvsClient<K, V>,
+    requests: Vec<Request<K, V>>,
+}
+
+impl<K, V> Pipeline<'_, K, V>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    pub fn get(&mut self, key: K) -> &mut Self {
+        self.requests.push(Request::Get{key});
+        self
+    }
+    pub fn set(&mut self, key: K, value: V) -> &mut Self {
+        self.requests.push(Request::Set{key, val: value});
+        self
+    }
+    pub fn remove(&mut self, key: K) -> &mut Self {
+        self.requests.push(Request::Rm{key});
+        self
+    }
+
+    // number of queued requests
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    // sends every queued request at once, then reads their responses. the i-th result belongs to
+    // the i-th request, the value for a get and None for a set or remove. the outer error means
+    // the connection failed, leaving it unknown which requests were applied, so the batch is
+    // never resent and the client should be dropped.
+    pub fn flush(&mut self) -> Result<Vec<Result<Option<V>>>> {
+        let requests = std::mem::take(&mut self.requests);
+        let client = &mut *self.client;
+        for req in &requests {
+            write_message_unflushed(&client.codec, &mut client.request_stream, req).map_err(timeout_error)?;
+        }
+        client.request_stream.flush().map_err(timeout_error)?;
+
+        let mut results = Vec::with_capacity(requests.len());
+        for req in &requests {
+            let resp = read_message::<Response<V>, _>(&client.codec, &mut client.response_stream).map_err(timeout_error)?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by the server"))?;
+            results.push(match (req, resp) {
+                (Request::Get{..}, Response::Value(val)) => Ok(val),
+                (_, Response::Ack) => Ok(None),
+                (_, Response::Err(err)) => Err(err.into()),
+                _ => Err(Error::UnhandledError("unexpected response in pipeline".to_owned())),
+            });
+        }
+
+        Ok(results)
+    }
+}
+
+// the changes of a watched key, as returned by KvsClient::watch. blocks until the next change and
+// ends once the server closes the connection. a broken connection is not reopened, since the
+// changes made in the meantime would be lost.
+pub struct Watch<K, V>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    client: KvsClient<K, V>,
+}
+
+impl<K, V> Iterator for Watch<K, V>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    type Item = Result<Event<V>>;
+
+    fn next(&mut self) -> Option<Result<Event<V>>> {
+        loop {
+            let resp = match read_message::<Response<V>, _>(&self.client.codec, &mut self.client.response_stream).map_err(timeout_error) {
+                Ok(Some(resp)) => resp,
+                Ok(None) => return None,
+                Err(err) => return Some(Err(err)),
+            };
+            match resp {
+                Response::Event(event) => return Some(Ok(event)),
+                // a heartbeat
+                Response::Ack => continue,
+                Response::Err(err) => return Some(Err(err.into())),
+                _ => return Some(Err(Error::UnhandledError("unexpected response to watch".to_owned()))),
+            }
         }
     }
 }
+
+// errors after which the connection is known to be unusable, but the server may be reachable
+// again through a new one
+fn is_connection_error(err: &Error) -> bool {
+    match err {
+        Error::Io(err, _) => matches!(
+            err.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::UnexpectedEof
+                // the socket file of a restarting server is briefly missing
+                | io::ErrorKind::NotFound
+        ),
+        _ => false,
+    }
+}
+
+// socket timeouts surface as WouldBlock or TimedOut depending on the platform
+fn timeout_error<E: Into<Error>>(err: E) -> Error {
+    match err.into() {
+        Error::Io(err, _) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => Error::Timeout,
+        err => err,
+    }
+}
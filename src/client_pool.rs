@@ -0,0 +1,84 @@
+use crate::{Endpoint, KvsClient, Result};
+use crossbeam_channel::{Receiver, Sender};
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+
+// how the connections of a pool opened by connect are retried, see KvsClient::connect_with_retry
+const POOL_MAX_RETRIES: u32 = 5;
+const POOL_BACKOFF: Duration = Duration::from_millis(50);
+
+// a fixed set of connections to a server, shared by any number of threads. every request takes
+// a connection out of the pool for as long as the returned guard lives, waiting if all of them
+// are taken.
+pub struct KvsClientPool {
+    idle_sender: Sender<KvsClient>,
+    idle_receiver: Receiver<KvsClient>,
+    size: usize,
+}
+
+// a connection taken out of a pool, put back when dropped
+pub struct PooledClient<'a> {
+    pool: &'a KvsClientPool,
+    client: Option<KvsClient>,
+}
+
+impl KvsClientPool {
+    // opens size connections up front, 0 counts as 1. they reconnect on their own when they find
+    // their connection broken, so a server restart costs the requests in flight a retry rather
+    // than the pool a connection.
+    pub fn connect(endpoint: impl Into<Endpoint>, size: usize) -> Result<KvsClientPool> {
+        let endpoint = endpoint.into();
+        let clients = (0..size.max(1))
+            .map(|_| KvsClient::connect_with_retry(endpoint.clone(), POOL_MAX_RETRIES, POOL_BACKOFF))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(KvsClientPool::from_clients(clients))
+    }
+
+    // pools clients opened by the caller, e.g. over tls or authenticated. only clients built
+    // with connect_with_retry replace a broken connection by themselves.
+    pub fn from_clients(clients: Vec<KvsClient>) -> KvsClientPool {
+        let (idle_sender, idle_receiver) = crossbeam_channel::unbounded();
+        let size = clients.len();
+        for client in clients {
+            idle_sender.send(client).unwrap();
+        }
+
+        KvsClientPool{idle_sender, idle_receiver, size}
+    }
+
+    // takes a connection, waiting for one to be put back if all of them are taken. waits forever
+    // on a pool built from no clients.
+    pub fn get(&self) -> PooledClient<'_> {
+        // the pool holds a sender, so the channel never disconnects
+        let client = self.idle_receiver.recv().unwrap();
+        PooledClient{pool: self, client: Some(client)}
+    }
+
+    // number of connections, taken or not
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            let _ = self.pool.idle_sender.send(client);
+        }
+    }
+}
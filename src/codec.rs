@@ -0,0 +1,146 @@
+use crate::Result;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Deserializer;
+use std::fmt;
+use std::io::{self, Read};
+
+// encodes and decodes the entries written to the log files.
+// log files carry no separators between entries, so a codec must be able to decode back-to-back
+// values from a stream and report how many bytes each one took.
+pub trait Codec: Clone + Default + Send + Sync + 'static {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
+
+    fn decode<T: DeserializeOwned>(&self, b: &[u8]) -> Result<T>;
+
+    // decodes the next value from the reader and returns it along with the number of bytes it
+    // occupied, returns None once the reader is exhausted
+    fn decode_next<T: DeserializeOwned, R: Read>(&self, reader: R) -> Result<Option<(T, u64)>>;
+}
+
+// compact binary encoding, used by default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BincodeCodec;
+
+// self-describing JSON encoding, the original on-disk format
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+impl Codec for BincodeCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, b: &[u8]) -> Result<T> {
+        Ok(bincode::deserialize(b)?)
+    }
+
+    fn decode_next<T: DeserializeOwned, R: Read>(&self, reader: R) -> Result<Option<(T, u64)>> {
+        let mut reader = CountingReader{reader, count: 0};
+        match bincode::deserialize_from(&mut reader) {
+            Ok(value) => Ok(Some((value, reader.count))),
+            Err(err) => match *err {
+                bincode::ErrorKind::Io(ref io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof && reader.count == 0 => Ok(None),
+                _ => Err(err.into()),
+            },
+        }
+    }
+}
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, b: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(b)?)
+    }
+
+    fn decode_next<T: DeserializeOwned, R: Read>(&self, reader: R) -> Result<Option<(T, u64)>> {
+        let mut stream = Deserializer::from_reader(reader).into_iter::<T>();
+        match stream.next() {
+            Some(value) => Ok(Some((value?, stream.byte_offset() as u64))),
+            None => Ok(None),
+        }
+    }
+}
+
+// encodes and decodes the requests and responses sent over a connection. every message on the
+// wire is preceded by its length, so unlike a Codec, a wire codec only decodes whole messages.
+pub trait WireCodec {
+    fn encode_message<T: Serialize>(&self, message: &T) -> Result<Vec<u8>>;
+
+    fn decode_message<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T>;
+}
+
+impl WireCodec for JsonCodec {
+    fn encode_message<T: Serialize>(&self, message: &T) -> Result<Vec<u8>> {
+        self.encode(message)
+    }
+
+    fn decode_message<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T> {
+        self.decode(payload)
+    }
+}
+
+impl WireCodec for BincodeCodec {
+    fn encode_message<T: Serialize>(&self, message: &T) -> Result<Vec<u8>> {
+        self.encode(message)
+    }
+
+    fn decode_message<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T> {
+        self.decode(payload)
+    }
+}
+
+// the wire codecs a client can ask for in its Hello, every connection starts out in json.
+// bincode takes fewer bytes and less time, but like on disk, it cannot decode values whose
+// type only shows once they are read, such as serde_json::Value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    Bincode,
+}
+
+impl WireFormat {
+    pub const ALL: [WireFormat; 2] = [WireFormat::Json, WireFormat::Bincode];
+}
+
+impl WireCodec for WireFormat {
+    fn encode_message<T: Serialize>(&self, message: &T) -> Result<Vec<u8>> {
+        match self {
+            WireFormat::Json => JsonCodec.encode_message(message),
+            WireFormat::Bincode => BincodeCodec.encode_message(message),
+        }
+    }
+
+    fn decode_message<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T> {
+        match self {
+            WireFormat::Json => JsonCodec.decode_message(payload),
+            WireFormat::Bincode => BincodeCodec.decode_message(payload),
+        }
+    }
+}
+
+impl fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireFormat::Json => write!(f, "json"),
+            WireFormat::Bincode => write!(f, "bincode"),
+        }
+    }
+}
+
+// keeps track of the number of bytes read through the wrapped reader
+struct CountingReader<R: Read> {
+    reader: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
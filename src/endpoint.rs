@@ -0,0 +1,168 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::time::Duration;
+#[cfg(feature = "tls")]
+use std::sync::{Arc, Mutex};
+
+// where a server listens and a client connects to it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    // a unix domain socket at the given path. it skips the tcp stack and opens no port, so it
+    // suits clients on the same machine.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Endpoint {
+        Endpoint::Tcp(addr)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// a connection over either kind of endpoint
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    // an encrypted session over one of the others. rustls keeps a single state for both
+    // directions, so the reading and the writing half share it.
+    #[cfg(feature = "tls")]
+    Tls(Arc<Mutex<Box<dyn Duplex>>>),
+}
+
+#[cfg(feature = "tls")]
+pub(crate) trait Duplex: Read + Write + Send {
+    // same as Stream::shutdown_write
+    fn shutdown_write(&mut self) -> io::Result<()>;
+}
+
+impl Stream {
+    // connects to the endpoint, the timeout applies to connecting and to every read and write
+    pub(crate) fn connect(endpoint: &Endpoint, timeout: Option<Duration>) -> io::Result<Stream> {
+        let stream = match endpoint {
+            Endpoint::Tcp(addr) => match timeout {
+                Some(timeout) => Stream::Tcp(TcpStream::connect_timeout(addr, timeout)?),
+                None => Stream::Tcp(TcpStream::connect(addr)?),
+            },
+            // connecting to a local socket does not wait on the network
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Stream::Unix(UnixStream::connect(path)?),
+        };
+        stream.set_timeout(timeout)?;
+
+        Ok(stream)
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => Ok(Stream::Tcp(stream.try_clone()?)),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Ok(Stream::Unix(stream.try_clone()?)),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => Ok(Stream::Tls(Arc::clone(stream))),
+        }
+    }
+
+    // tells the other end that nothing more will be written, it reads the end of the stream once
+    // it has read everything written before. reading is still possible afterwards.
+    pub(crate) fn shutdown_write(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Write),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Write),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.lock().unwrap().shutdown_write(),
+        }
+    }
+
+    // makes reads on every handle of the stream return the end of the stream, waking up a
+    // blocked one. writing is still possible afterwards.
+    pub(crate) fn shutdown_read(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Read),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Read),
+            // only the stream under a session is shut down, the session then reads its end
+            #[cfg(feature = "tls")]
+            Stream::Tls(_) => Ok(()),
+        }
+    }
+
+    // names the other end for logs, clients of a unix socket are usually unnamed
+    pub(crate) fn peer(&self) -> String {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().map_or_else(|_| "unknown peer".to_owned(), |addr| addr.to_string()),
+            #[cfg(unix)]
+            Stream::Unix(_) => "unix socket peer".to_owned(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(_) => "tls peer".to_owned(),
+        }
+    }
+
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            },
+            #[cfg(unix)]
+            Stream::Unix(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            },
+            // sessions are only set up over streams that already have their timeouts
+            #[cfg(feature = "tls")]
+            Stream::Tls(_) => Ok(()),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.lock().unwrap().read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.lock().unwrap().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.lock().unwrap().flush(),
+        }
+    }
+}
@@ -1,19 +1,31 @@
-use super::{store, KvsEngine};
-use crate::entry::Entry;
+use super::{store, Event, KvsEngine, Metadata, Op, Snapshot, StoreStats};
+use crate::codec::{BincodeCodec, Codec};
+use crate::entry::{self, Entry, EntryOffset, IndexSlot, LogRecord};
 use crate::error::Result;
+use crate::metrics::MetricsSnapshot;
+use crate::options::StoreOptions;
 use std::path::Path;
-use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 use serde::{Serialize, de::DeserializeOwned};
 use std::fmt::Debug;
+use crossbeam_skiplist::map;
+use crossbeam_channel::Receiver;
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
 
 
 #[derive(Clone)]
-pub struct KvStore<K, V>
+pub struct KvStore<K, V, C = BincodeCodec>
 where
     K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
     V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    C: Codec,
 {
-    store: store::Store<K, V>,
+    store: store::Store<K, V, C>,
+    // dropped before the compaction guard, a sweep may start a compaction
+    _sweeper: Option<Arc<store::Sweeper>>,
+    _compaction: Arc<store::CompactionGuard>,
 }
 
 impl<K, V> KvStore<K, V>
@@ -22,26 +34,292 @@ where
     V: Clone + Serialize + DeserializeOwned + Send + 'static,
 {
     pub fn open(dir: &Path) -> Result<KvStore<K, V>> {
-        let _ = fs::create_dir_all(dir);
-        let store = store::Store::new(dir)?;
+        KvStore::open_with_codec(dir, BincodeCodec)
+    }
+
+    // opens an existing store for reading only, see StoreOptions::read_only
+    pub fn open_read_only(dir: &Path) -> Result<KvStore<K, V>> {
+        KvStore::open_with_options(dir, BincodeCodec, StoreOptions{read_only: true, ..StoreOptions::default()})
+    }
+}
+
+impl<K, V, C> KvStore<K, V, C>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    C: Codec,
+{
+    // opens the store using the given codec for the log entries, the codec must match the one
+    // the existing log files in the directory were written with
+    pub fn open_with_codec(dir: &Path, codec: C) -> Result<KvStore<K, V, C>> {
+        KvStore::open_with_options(dir, codec, StoreOptions::default())
+    }
+
+    // calls f with every entry of the log files in dir and where it is stored, in the order
+    // they are replayed. reads the files as they are, without opening the store, so it also
+    // works on a store that fails to open or is in use. stops at the first entry that cannot be
+    // read, after f has seen all the ones before it.
+    pub fn dump(dir: &Path, codec: &C, f: impl FnMut(LogRecord<K, V>) -> Result<()>) -> Result<()> {
+        store::dump(dir, codec, f)
+    }
+
+    // same as open_with_codec, with the given tunables
+    pub fn open_with_options(dir: &Path, codec: C, options: StoreOptions) -> Result<KvStore<K, V, C>> {
+        let store = store::Store::new(dir, codec, options)?;
 
         Ok(KvStore{
+            _sweeper: store.start_sweeper()?.map(Arc::new),
+            _compaction: Arc::new(store.compaction_guard()),
             store,
         })
     }
+
+    // reads the values of the given index entries in order, skipping expired ones
+    fn read_entries<'a>(&'a self, entries: impl Iterator<Item = map::Entry<'a, K, IndexSlot>>) -> Result<Vec<(K, V)>> {
+        let _files = self.store.pin_files();
+        let mut pairs = Vec::new();
+        for entry in entries {
+            let offset = entry.value().get();
+            if offset.is_expired() {
+                continue;
+            }
+            if let Some(val) = self.store.read(offset.file_id, offset.start, offset.end)? {
+                pairs.push((entry.key().clone(), val));
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    // reads the values of the given index entries in order as the caller gets to them, skipping
+    // expired ones. files are pinned per pair rather than for the whole iteration, which is
+    // driven by the caller and may take arbitrarily long.
+    fn read_lazily<'a>(&'a self, entries: impl Iterator<Item = map::Entry<'a, K, IndexSlot>> + 'a) -> impl Iterator<Item = Result<(K, V)>> + 'a {
+        entries.filter_map(move |entry| {
+            let _files = self.store.pin_files();
+            // a key removed before the pin may point into a file compaction has deleted since
+            if entry.is_removed() {
+                return None;
+            }
+            let offset = entry.value().get();
+            if offset.is_expired() {
+                return None;
+            }
+            match self.store.read(offset.file_id, offset.start, offset.end) {
+                Ok(val) => val.map(|val| Ok((entry.key().clone(), val))),
+                Err(err) => Some(Err(err)),
+            }
+        })
+    }
+
+    // returns at most limit pairs in ascending key order, starting just past start_after or at the
+    // first key. passing the last key of a page as start_after gets the next one, and a page
+    // shorter than limit is the last. pages are read independently, so keys written between two
+    // calls show up if they sort after the cursor.
+    pub fn scan_page(&self, start_after: Option<K>, limit: usize) -> Result<Vec<(K, V)>> {
+        let start = match start_after {
+            Some(key) => Bound::Excluded(key),
+            None => Bound::Unbounded,
+        };
+        let entries = self.store.index
+            .range((start, Bound::Unbounded))
+            .filter(|entry| !entry.value().get().is_expired())
+            .take(limit);
+        self.read_entries(entries)
+    }
+
+    // returns a read-only view of the store as it is now, which keeps serving the current values
+    // while the store moves on. taking it copies the index under all writer locks, so writes wait
+    // for a moment proportional to the number of keys.
+    pub fn snapshot(&self) -> Result<Snapshot<K, V, C>> {
+        let (index, pin) = self.store.snapshot();
+        Ok(Snapshot::new(index, pin, self.store.codec.clone()))
+    }
+
+    // every entry of the log files in the order it was written, live or not: sets, removes,
+    // clears and the bounds of transactions, including those that never committed. unlike iter,
+    // which follows the index, this replays the files by id and offset, as they are when it is
+    // called. compaction rewrites the log: the entries written before the last one are gone, and
+    // the live sets it copied show up first, in key order.
+    pub fn iter_log_order(&self) -> impl Iterator<Item = Result<Entry<K, V>>> {
+        self.store.log_order()
+    }
+
+    // writes a point-in-time copy of every live pair to a single file, without the stale entries
+    // of the log files. the file can be loaded into another store with import.
+    pub fn export(&self, path: &Path) -> Result<()> {
+        self.store.export(path)
+    }
+
+    // sets every pair of a file written by export, using the same codec, overwriting keys that
+    // already exist
+    pub fn import(&self, path: &Path) -> Result<()> {
+        self.store.import(path)
+    }
+
+    // every live key in ascending order, taken from the index alone. much cheaper than scan or
+    // iter when values are large, since no log file is read.
+    pub fn keys(&self) -> Vec<K> {
+        self.store.index.iter()
+            .filter(|entry| !entry.value().get().is_expired())
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    // smallest live key, found at the front of the index. expired keys still in the index are
+    // stepped over, so this only walks past the front in that case.
+    pub fn first_key(&self) -> Option<K> {
+        self.store.index.iter()
+            .find(|entry| !entry.value().get().is_expired())
+            .map(|entry| entry.key().clone())
+    }
+
+    // same as first_key, for the largest live key
+    pub fn last_key(&self) -> Option<K> {
+        self.store.index.iter()
+            .rev()
+            .find(|entry| !entry.value().get().is_expired())
+            .map(|entry| entry.key().clone())
+    }
+
+    // number of live keys within the range, counted in the index without reading any value
+    pub fn count_range<R: RangeBounds<K>>(&self, range: R) -> usize {
+        self.store.index.range(range)
+            .filter(|entry| !entry.value().get().is_expired())
+            .count()
+    }
+
+    // number of keys in the index, expired keys included until a sweep or a compaction drops
+    // them. stats counts the live keys only.
+    pub fn len(&self) -> usize {
+        self.store.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.index.is_empty()
+    }
+
+    // writes a tombstone for every expired key, as the sweeper started by
+    // StoreOptions::ttl_sweep_interval does, and returns how many there were
+    pub fn sweep_expired(&self) -> Result<usize> {
+        self.store.sweep_expired()
+    }
+
+    // compacts like KvsEngine::compact, calling progress with the entries copied so far and the
+    // entries to copy. setting cancel, e.g. from another thread, stops the copy before the next
+    // entry and returns Error::Cancelled, leaving the old files in place.
+    pub fn compact_with(&self, cancel: Arc<AtomicBool>, mut progress: impl FnMut(usize, usize)) -> Result<()> {
+        self.store.compact_with(&cancel, &mut progress)
+    }
+
+    // number of bytes in the log files taken up by overwritten, removed or expired entries that
+    // the next compaction would reclaim
+    pub fn uncompacted_bytes(&self) -> u64 {
+        self.store.uncompacted_bytes()
+    }
+
+    // number of bytes the log files take up on disk, live data included, unlike
+    // uncompacted_bytes. the same as stats().disk_bytes.
+    pub fn disk_usage(&self) -> Result<u64> {
+        self.store.disk_usage()
+    }
+
+    // number of log files this handle holds open for reading, at most
+    // StoreOptions::max_open_readers plus the active files
+    pub fn open_readers(&self) -> usize {
+        self.store.open_readers()
+    }
+
+    // get without counting it, hits and misses are counted once the outcome is known
+    fn get_uncounted(&self, key: K) -> Result<Option<V>> {
+        self.read_live(key, |offset| self.store.read(offset.file_id, offset.start, offset.end))
+    }
+
+    // calls read with the offset of the key unless it is missing or expired, with the files
+    // pinned until it returns
+    fn read_live<T>(&self, key: K, read: impl FnOnce(&EntryOffset) -> Result<Option<T>>) -> Result<Option<T>> {
+        if !self.store.may_contain(&key)? {
+            return Ok(None);
+        }
+
+        // the offset is taken in a single lookup, a concurrent remove may drop the key at any
+        // point between two of them
+        let _files = self.store.pin_files();
+        let Some(offset) = self.store.index.get(&key).map(|slot| slot.value().get()) else {
+            return Ok(None);
+        };
+        if offset.is_expired() {
+            return Ok(None);
+        }
+        read(&offset)
+    }
 }
 
-impl<K, V> KvsEngine<K, V> for KvStore<K, V>
+impl<V, C> KvStore<String, V, C>
+where
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    C: Codec,
+{
+    // returns all pairs whose key starts with the given prefix, in ascending key order. the
+    // index is sorted, so this only visits the matching keys.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, V)>> {
+        let entries = self.store.index
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|entry| entry.key().starts_with(prefix));
+        self.read_entries(entries)
+    }
+}
+
+impl<K, V, C> KvsEngine<K, V> for KvStore<K, V, C>
 where
     K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
     V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    C: Codec,
 {
-    fn set(&self, key: K, val: V) -> Result<()> {
-        let cmd = Entry::init_set(key.clone(), val.clone());
-        let serialized = serde_json::to_string(&cmd).unwrap();
-        let b = serialized.as_bytes();
+    fn set(&self, key: K, val: V) -> Result<u64> {
+        self.store.write(key, val, None)
+    }
+
+    fn set_with_ttl(&self, key: K, val: V, ttl: Duration) -> Result<()> {
+        let expires_at = entry::now_millis() + ttl.as_millis() as u64;
+        self.store.write(key, val, Some(expires_at))?;
+
+        Ok(())
+    }
+
+    fn set_and_get(&self, key: K, val: V) -> Result<Option<V>> {
+        self.store.write_and_get(key, val, None)
+    }
+
+    fn set_if_version(&self, key: K, val: V, expected_version: u64) -> Result<bool> {
+        self.store.write_if_version(key, val, expected_version)
+    }
+
+    fn compare_and_swap(&self, key: K, expected: Option<V>, new: V) -> Result<bool>
+    where
+        V: PartialEq,
+    {
+        self.store.write_if(key, new, None, |curr| curr == expected)
+    }
+
+    fn merge<F>(&self, key: K, f: F) -> Result<V>
+    where
+        F: FnOnce(Option<V>) -> V,
+    {
+        self.store.update(key, f)
+    }
+
+    fn set_batch(&self, entries: Vec<(K, V)>) -> Result<()> {
+        self.store.write_batch(entries)
+    }
+
+    fn transaction(&self, ops: Vec<Op<K, V>>) -> Result<()> {
+        let ops = ops.into_iter().map(|op| match op {
+            Op::Set(key, val) => (key, Some(val)),
+            Op::Remove(key) => (key, None),
+        }).collect();
 
-        self.store.write(key, b)
+        self.store.transaction(ops)
     }
 
     fn remove(&self, key: K) -> Result<K> {
@@ -50,12 +328,71 @@ where
         Ok(key)
     }
 
+    fn remove_and_get(&self, key: K) -> Result<Option<V>> {
+        self.store.remove_and_get(key)
+    }
+
+    fn remove_if_exists(&self, key: K) -> Result<bool> {
+        self.store.remove_if_exists(key)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.store.clear()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.store.compact()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.store.flush()
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        self.store.stats()
+    }
+
+    // shared by all handles of the store
+    fn metrics(&self) -> MetricsSnapshot {
+        self.store.metrics()
+    }
+
+    fn watch(&self, key: K) -> Receiver<Event<V>> {
+        self.store.watch(key)
+    }
+
     fn get(&self, key: K) -> Result<Option<V>> {
-        if !self.store.index.contains_key(&key) {
-            return Ok(None);
+        let val = self.get_uncounted(key)?;
+        self.store.record(|metrics| metrics.record_get(val.is_some()));
+        Ok(val)
+    }
+
+    fn get_with_metadata(&self, key: K) -> Result<Option<(V, Metadata)>> {
+        let found = self.read_live(key, |offset| match self.store.read_entry(offset)? {
+            Entry::Set{val, version, written_at, ..} => Ok(Some((val, Metadata{version, written_at, file_id: offset.file_id}))),
+            _ => Ok(None),
+        })?;
+        self.store.record(|metrics| metrics.record_get(found.is_some()));
+        Ok(found)
+    }
+
+    fn contains_key(&self, key: K) -> Result<bool> {
+        if !self.store.may_contain(&key)? {
+            return Ok(false);
         }
 
-        let offset = self.store.index.get(&key).unwrap();
-        Ok(self.store.read(offset.value().file_id, offset.value().start, offset.value().end)?)
+        Ok(self.store.index.get(&key).is_some_and(|slot| !slot.value().get().is_expired()))
+    }
+
+    fn scan<R: RangeBounds<K>>(&self, range: R) -> Result<Vec<(K, V)>> {
+        self.read_entries(self.store.index.range(range))
+    }
+
+    fn iter(&self) -> impl Iterator<Item = Result<(K, V)>> + '_ {
+        self.read_lazily(self.store.index.iter())
+    }
+
+    fn scan_rev<'a, R: RangeBounds<K> + 'a>(&'a self, range: R) -> impl Iterator<Item = Result<(K, V)>> + 'a {
+        self.read_lazily(self.store.index.range(range).rev())
     }
 }
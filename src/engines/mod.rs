@@ -1,6 +1,9 @@
-use crate::Result;
-use serde::{Serialize, de::DeserializeOwned};
+use crate::{MetricsSnapshot, Result};
+use crossbeam_channel::Receiver;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
 use std::fmt::Debug;
+use std::ops::RangeBounds;
+use std::time::Duration;
 
 pub trait KvsEngine<K, V>: Clone + Send + 'static
 where
@@ -8,11 +11,110 @@ where
     V: Clone + Serialize + DeserializeOwned + Send + 'static,
 {
     fn get(&self, key: K) -> Result<Option<V>>;
-    fn set(&self, key: K, val: V) -> Result<()>;
+    // same as get, along with where and when the value was written
+    fn get_with_metadata(&self, key: K) -> Result<Option<(V, Metadata)>>;
+    // sets the value and returns its version. every set takes a higher version than any before
+    // it, so a key's version changes with every write of it.
+    fn set(&self, key: K, val: V) -> Result<u64>;
+    // sets a value that reads as missing once the ttl has passed
+    fn set_with_ttl(&self, key: K, val: V, ttl: Duration) -> Result<()>;
+    // sets the value and returns the one it replaced, if any
+    fn set_and_get(&self, key: K, val: V) -> Result<Option<V>>;
+    // sets the value only if the key is still at the expected version, 0 meaning the key must not
+    // exist. returns whether the value was set.
+    fn set_if_version(&self, key: K, val: V, expected_version: u64) -> Result<bool>;
+    // sets the key to new only if its current value equals expected, None meaning the key must
+    // not exist. returns whether the value was swapped.
+    fn compare_and_swap(&self, key: K, expected: Option<V>, new: V) -> Result<bool>
+    where
+        V: PartialEq;
+    // sets the key to the value f computes from its current one, None meaning the key does not
+    // exist. concurrent merges of the same key never lose an update. returns the new value.
+    fn merge<F>(&self, key: K, f: F) -> Result<V>
+    where
+        F: FnOnce(Option<V>) -> V;
+    // sets all the given pairs under a single lock and flush, later pairs win over earlier ones
+    // with the same key
+    fn set_batch(&self, entries: Vec<(K, V)>) -> Result<()>;
+    // applies all the operations or, if the store crashes before they are durable, none of them.
+    // later operations win over earlier ones on the same key, and removing a missing key is not
+    // an error.
+    fn transaction(&self, ops: Vec<Op<K, V>>) -> Result<()>;
     fn remove(&self, key: K) -> Result<K>;
+    // removes the key and returns the value it held. a missing or expired key is not an error,
+    // it returns None and writes nothing.
+    fn remove_and_get(&self, key: K) -> Result<Option<V>>;
+    // removes the key if it exists and returns whether it did. a missing or expired key is not
+    // an error and writes nothing.
+    fn remove_if_exists(&self, key: K) -> Result<bool>;
+    // checks for the presence of a key using only the in-memory index
+    fn contains_key(&self, key: K) -> Result<bool>;
+    // returns all key-value pairs whose keys fall within the given range, in ascending key order
+    fn scan<R: RangeBounds<K>>(&self, range: R) -> Result<Vec<(K, V)>>;
+    // yields every key-value pair in ascending key order, reading each value only when it is
+    // reached. pairs written or removed while iterating may or may not show up.
+    fn iter(&self) -> impl Iterator<Item = Result<(K, V)>> + '_;
+    // yields the pairs whose keys fall within the range in descending key order, lazily like
+    // iter. meant for "latest n" queries on keys that sort by time: take(n) reads n values.
+    fn scan_rev<'a, R: RangeBounds<K> + 'a>(&'a self, range: R) -> impl Iterator<Item = Result<(K, V)>> + 'a;
+    // removes every key
+    fn clear(&self) -> Result<()>;
+    // makes every write so far durable, whatever durability the engine was opened with
+    fn flush(&self) -> Result<()>;
+    // rewrites the live entries into a fresh log file and deletes the old ones, without waiting
+    // for the automatic threshold
+    fn compact(&self) -> Result<()>;
+    // reports the size of the store, meant for monitoring
+    fn stats(&self) -> Result<StoreStats>;
+    // counters of the operations on the engine since it was opened, meant for monitoring
+    fn metrics(&self) -> MetricsSnapshot;
+    // returns a channel receiving every change of the key from now on, in order. expiring
+    // sends nothing, nor does a compaction, which changes no value.
+    fn watch(&self, key: K) -> Receiver<Event<V>>;
+}
+
+// an operation of a transaction
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op<K, V> {
+    Set(K, V),
+    Remove(K),
+}
+
+// a change of a watched key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event<V> {
+    // the key was set to the value, by any kind of write
+    Set(V),
+    // the key was removed, evicted or cleared
+    Removed,
+}
+
+// describes the write that stored a value
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    // version of the write, as returned by set
+    pub version: u64,
+    // unix time in millis the value was written at, compaction keeps it
+    pub written_at: u64,
+    // log file the value is currently stored in, which changes when compaction moves it
+    pub file_id: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreStats {
+    // keys currently readable, expired keys are not counted
+    pub live_keys: u64,
+    // number of log files in the directory
+    pub segments: u64,
+    // bytes taken up by stale entries that the next compaction would reclaim
+    pub uncompacted_bytes: u64,
+    // total size of the log files in the directory
+    pub disk_bytes: u64,
 }
 
 mod kvs;
+mod snapshot;
 mod store;
 
 pub use self::kvs::KvStore;
+pub use self::snapshot::Snapshot;
@@ -0,0 +1,79 @@
+use super::store::{Reader, SnapshotPin};
+use crate::codec::{BincodeCodec, Codec};
+use crate::entry::EntryOffset;
+use crate::error::Result;
+use std::cell::RefCell;
+use std::collections::{hash_map, BTreeMap, HashMap};
+use serde::{Serialize, de::DeserializeOwned};
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+
+// a read-only view of a KvStore as it was when the snapshot was taken. later writes, removals
+// and compactions of the store do not show through, the files it reads from are kept around
+// until it is dropped.
+pub struct Snapshot<K, V, C = BincodeCodec>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    C: Codec,
+{
+    index: BTreeMap<K, EntryOffset>,
+    codec: C,
+    readers: RefCell<HashMap<u32, Reader>>,
+    // also tells where the files are
+    pin: SnapshotPin,
+    _phantom: PhantomData<V>,
+}
+
+impl<K, V, C> Snapshot<K, V, C>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    C: Codec,
+{
+    pub(crate) fn new(index: BTreeMap<K, EntryOffset>, pin: SnapshotPin, codec: C) -> Snapshot<K, V, C> {
+        Snapshot{
+            index,
+            codec,
+            readers: RefCell::new(HashMap::new()),
+            pin,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn get(&self, key: K) -> Result<Option<V>> {
+        match self.index.get(&key) {
+            Some(offset) if !offset.is_expired() => self.read(offset),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn contains_key(&self, key: K) -> Result<bool> {
+        Ok(self.index.get(&key).is_some_and(|offset| !offset.is_expired()))
+    }
+
+    // returns all pairs whose keys fall within the given range, in ascending key order
+    pub fn scan<R: RangeBounds<K>>(&self, range: R) -> Result<Vec<(K, V)>> {
+        let mut pairs = Vec::new();
+        for (key, offset) in self.index.range(range) {
+            if offset.is_expired() {
+                continue;
+            }
+            if let Some(val) = self.read(offset)? {
+                pairs.push((key.clone(), val));
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    fn read(&self, offset: &EntryOffset) -> Result<Option<V>> {
+        let mut readers = self.readers.borrow_mut();
+        let reader = match readers.entry(offset.file_id) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => entry.insert(Reader::new(&self.pin.log_file_name(offset.file_id))?),
+        };
+        reader.read::<K, V, C>(&self.codec, offset.start, offset.end)
+    }
+}
@@ -1,255 +1,1765 @@
-use crate::error::{Error, Result};
-use crate::entry::{Entry, EntryOffset};
+use crate::error::{Error, Result, ResultExt};
+use crate::entry::{self, Entry, EntryOffset, Frame, IndexSlot, LogRecord, COMPRESSED_LOG_HEADER, EXPORT_HEADER, FRAME_HEADER_LEN, LOG_HEADER};
+use crate::codec::Codec;
+use crate::engines::{Event, StoreStats};
+use crate::bloom::BloomFilter;
+use crate::metrics::{AtomicMetrics, Metrics, MetricsSnapshot};
+use crate::options::{Compression, Durability, SegmentLayout, StoreOptions, DEFAULT_BUFFER_SIZE};
+use crate::retry::RetryingWriter;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::path::{Path, PathBuf};
 use std::fs;
-use std::io::{copy, BufWriter, Write, BufReader, Read, Seek, SeekFrom, Take};
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::{Arc, Mutex};
-use serde_json::Deserializer;
+use std::io::{self, BufRead, BufWriter, Write, BufReader, Read, Seek, SeekFrom, Take};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use serde::{Serialize, de::DeserializeOwned};
 use std::fmt::Debug;
 use crossbeam_skiplist::SkipMap;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use std::marker::PhantomData;
+use log::{debug, error, info, trace, warn};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+// locked by every open store in its directory, so that two of them never write the same files
+const LOCK_FILE: &str = "LOCK";
 
 // holds the readers and writers impls for the log store
-pub struct Store<K, V>
+pub struct Store<K, V, C>
 where
     K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
     V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    C: Codec,
 {
     pub dir: Arc<PathBuf>,
-    pub readers: RefCell<HashMap<u32, Reader>>,
-    pub writer: Arc<Mutex<Writer>>,
-    pub index: Arc<SkipMap<K, EntryOffset>>,
+    pub codec: C,
+    pub options: StoreOptions,
+    readers: RefCell<ReaderCache>,
+    // one writer per shard, every key is written through the writer of its shard. locked in
+    // ascending order when several are needed.
+    pub writers: Arc<Vec<Mutex<Writer>>>,
+    // id of the next log file to create. ids only grow, so replaying the files in id order
+    // replays the entries of every key in the order they were written.
+    next_file_id: Arc<AtomicU32>,
+    pub index: Arc<SkipMap<K, IndexSlot>>,
     pub last_compaction_point: Arc<AtomicU32>,
+    // read-locked by lookups from the moment they take an offset from the index until they have
+    // read the entry, write-locked by compaction to delete old files. a file therefore outlives
+    // every lookup that may still read from it, even through a handle that has not opened it yet.
+    files: Arc<RwLock<()>>,
+    // held for the whole duration of a compaction, so that at most one runs at a time
+    compaction_lock: Arc<Mutex<()>>,
+    // set while a background compaction is pending or running
+    compacting: Arc<AtomicBool>,
+    background: Arc<Mutex<Option<JoinHandle<()>>>>,
+    // locked after files when both are needed
+    snapshots: Arc<Mutex<Snapshots>>,
+    // syncs the writes of concurrent writers together, only set under Durability::GroupCommit
+    committer: Option<Arc<Committer>>,
+    // bloom filters of the keys every log file holds, by file id, only set with bloom_filters.
+    // a key is added to the filter of its file before the index points at it.
+    filters: Option<Arc<SegmentFilters>>,
+    // total size of the entries the index points at
+    live_bytes: Arc<AtomicU64>,
+    // only set with max_keys or max_disk_bytes
+    eviction: Option<Arc<Eviction<K>>>,
+    // stale bytes found on open by a read-only store, which has no writer to count them against
+    read_only_uncompacted: u64,
+    // counters of the operations on the store, shared by all handles
+    metrics: Arc<AtomicMetrics>,
+    // the locked LOCK file of the directory, unlocked once the last handle is dropped
+    _lock: Option<Arc<fs::File>>,
+    // channels of the watched keys, notified under the writer lock of the key's shard
+    watchers: Arc<Watchers<K, V>>,
+    // version of the next set, shared by all keys. taken under the writer lock of the key's
+    // shard, so the versions of a key grow in the order its sets are written.
+    next_version: Arc<AtomicU64>,
     _phantom: PhantomData<V>,
 }
 
+// the keys of the index in the order they were inserted, for evicting the oldest ones once the
+// store grows beyond its limits
+struct Eviction<K> {
+    // slot number and key of every key when it was inserted, oldest first. a key removed or
+    // reinserted since has another slot number, which marks its entry here as stale.
+    insertions: Mutex<VecDeque<(u64, K)>>,
+    // held while evicting, so that concurrent writers do not evict more keys than needed.
+    // taken before any writer, while insertions is only ever held on its own.
+    running: Mutex<()>,
+}
+
+// the channels watching keys, by key. a channel whose receiver is gone is dropped on the next
+// change of its key.
+struct Watchers<K, V> {
+    // number of keys watched, lets writes skip the lock while nothing is watched
+    watched: AtomicUsize,
+    channels: Mutex<BTreeMap<K, Vec<Sender<Event<V>>>>>,
+}
+
+// a key a compaction copied, with the location it was copied from and the one it was copied to
+type Copied<K> = (K, EntryOffset, EntryOffset);
+
+// open snapshots and the log files that compactions retired while they were open
+#[derive(Default)]
+struct Snapshots {
+    open: usize,
+    retired: BTreeSet<u32>,
+}
+
+// keeps the log files a snapshot may read from, compactions leave them in place until the last
+// pin is dropped, which then deletes them
+pub struct SnapshotPin {
+    dir: Arc<PathBuf>,
+    layout: SegmentLayout,
+    files: Arc<RwLock<()>>,
+    snapshots: Arc<Mutex<Snapshots>>,
+}
+
+impl SnapshotPin {
+    pub fn log_file_name(&self, file_id: u32) -> PathBuf {
+        self.layout.file_name(&self.dir, file_id)
+    }
+}
+
+// the entries the log files of a store held when the iterator was created, read one file at a
+// time
+pub struct LogOrder<K, V, C>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    C: Codec,
+{
+    // every file with the length it is read up to
+    files: std::vec::IntoIter<(u32, u64, PathBuf)>,
+    entries: std::vec::IntoIter<Entry<K, V>>,
+    // returned once the entries before it are, ends the iteration
+    failed: Option<Error>,
+    codec: C,
+    _pin: Option<SnapshotPin>,
+}
+
+impl<K, V, C> Iterator for LogOrder<K, V, C>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    C: Codec,
+{
+    type Item = Result<Entry<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            if let Some(err) = self.failed.take() {
+                self.files = Vec::new().into_iter();
+                return Some(Err(err));
+            }
+            let (file_id, len, path) = self.files.next()?;
+            let mut entries = Vec::new();
+            let res = Reader::new(&path).and_then(|mut reader| {
+                reader.for_each_entry_until::<K, V, C>(&self.codec, file_id, len, |entry, _, _| {
+                    entries.push(entry);
+                    Ok(())
+                })
+            });
+            self.entries = entries.into_iter();
+            self.failed = res.err();
+        }
+    }
+}
+
+impl Drop for SnapshotPin {
+    fn drop(&mut self) {
+        let _files = self.files.write().unwrap();
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.open -= 1;
+        if snapshots.open > 0 {
+            return;
+        }
+
+        for file_id in std::mem::take(&mut snapshots.retired) {
+            match fs::remove_file(self.log_file_name(file_id)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    error!("failed to delete retired log file {}: {}", file_id, err);
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+// waits for the background compaction, if any, when dropped. KvStore keeps one behind an Arc so
+// that dropping the last handle to a store never leaves a compaction running on its directory.
+pub struct CompactionGuard {
+    background: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl Drop for CompactionGuard {
+    fn drop(&mut self) {
+        if let Some(handle) = self.background.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// runs sweep_expired every StoreOptions::ttl_sweep_interval on a thread of its own, and stops
+// and waits for it when dropped. KvStore keeps one behind an Arc, like the CompactionGuard.
+pub struct Sweeper {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        // dropping the sender wakes the thread up
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// false positive rate of the bloom filter of a log file, the filter grows with the file by adding
+// stages of twice the capacity and half the rate, which keeps the total below twice this rate
+const FILTER_FALSE_POSITIVE_RATE: f64 = 0.01;
+const FILTER_INITIAL_CAPACITY: usize = 1024;
+
+type SegmentFilters = RwLock<HashMap<u32, Mutex<SegmentFilter>>>;
+
+// the keys of a single log file, which may keep growing while it is active
+struct SegmentFilter {
+    stages: Vec<BloomFilter>,
+}
+
+impl SegmentFilter {
+    fn new() -> SegmentFilter {
+        SegmentFilter{
+            stages: vec![BloomFilter::new(FILTER_INITIAL_CAPACITY, FILTER_FALSE_POSITIVE_RATE / 2.0)],
+        }
+    }
+
+    fn insert(&mut self, key: &[u8]) {
+        let last = self.stages.last().unwrap();
+        if last.len() >= last.capacity() {
+            let rate = FILTER_FALSE_POSITIVE_RATE / 2f64.powi(self.stages.len() as i32 + 1);
+            let stage = BloomFilter::new(last.capacity() * 2, rate);
+            self.stages.push(stage);
+        }
+        self.stages.last_mut().unwrap().insert(key);
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.stages.iter().any(|stage| stage.contains(key))
+    }
+}
+
+// syncs the writes of concurrent writers together under Durability::GroupCommit. a writer
+// registers its write while it still holds its lock and waits after releasing it. the first
+// writer to wait leads the next group: it waits for the window to pass or max_writes writes to
+// arrive, syncs every file written to since the last sync once and wakes up every writer it
+// covered. writers arriving while it syncs form the group after.
+struct Committer {
+    window: Duration,
+    max_writes: u64,
+    state: Mutex<CommitState>,
+    // signalled when a write is registered, so a waiting leader can stop early
+    arrived: Condvar,
+    // signalled when a group has been synced
+    synced: Condvar,
+}
+
+#[derive(Default)]
+struct CommitState {
+    // number of writes registered and number of those known to be on disk
+    written: u64,
+    synced: u64,
+    // whether a leader is collecting or syncing a group
+    syncing: bool,
+    // handles to the files written to since the last sync, by file id
+    dirty: BTreeMap<u32, fs::File>,
+}
+
+impl Committer {
+    fn new(window: Duration, max_writes: usize) -> Committer {
+        Committer{
+            window,
+            max_writes: max_writes.max(1) as u64,
+            state: Mutex::new(CommitState::default()),
+            arrived: Condvar::new(),
+            synced: Condvar::new(),
+        }
+    }
+
+    // records a write that has been handed to the os and returns the ticket to wait for, must be
+    // called with the writer locked
+    fn register(&self, writer: &Writer) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        if let btree_map::Entry::Vacant(entry) = state.dirty.entry(writer.file_id) {
+            entry.insert(writer.writer.get_ref().get_ref().try_clone()?);
+        }
+        state.written += 1;
+        if state.written - state.synced >= self.max_writes {
+            self.arrived.notify_one();
+        }
+
+        Ok(state.written)
+    }
+
+    // returns once the write with the given ticket is on disk, syncing it if no one else does
+    fn wait(&self, ticket: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+
+            state.syncing = true;
+            let deadline = Instant::now() + self.window;
+            while state.written - state.synced < self.max_writes {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                state = self.arrived.wait_timeout(state, deadline - now).unwrap().0;
+            }
+            let (target, writes) = (state.written, state.written - state.synced);
+            let files = std::mem::take(&mut state.dirty);
+            drop(state);
+
+            trace!("syncing {} writes to {} files", writes, files.len());
+            let res = files.values().try_for_each(|file| file.sync_all());
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            match res {
+                Ok(()) => state.synced = target,
+                // the files stay dirty, so the next leader syncs them again
+                Err(_) => {
+                    for (file_id, file) in files {
+                        state.dirty.entry(file_id).or_insert(file);
+                    }
+                },
+            }
+            self.synced.notify_all();
+            res?;
+        }
+    }
+}
+
 // basic wrapper over buffered writer functionality
 pub struct Writer {
     pub file_id: u32,
-    pub writer: BufWriter<fs::File>,
+    pub writer: BufWriter<RetryingWriter<fs::File>>,
     pub pos: u64,
     pub uncompacted: u64,
+    compression: Option<Compression>,
+    durability: Durability,
 }
 
 // basic wrapper over buffered reader functionality
 // additionally, encapsulates a few common read operations
 pub struct Reader {
     pub reader: BufReader<fs::File>,
+    // whether the file starts with LOG_HEADER and holds framed entries
+    pub framed: bool,
+    // whether the file starts with COMPRESSED_LOG_HEADER, its entries are then framed as well
+    pub compressed: bool,
 }
 
-pub fn log_file_name(dir: &Path,file_id: u32) -> PathBuf {
-    dir.join(format!("{}.log", file_id))
+// the readers a store handle has opened, by file id. with a capacity, opening one more than that
+// first closes the least recently used reader of a file no writer appends to. the readers of the
+// active files stay open, they are read the most and would otherwise be reopened all the time.
+struct ReaderCache {
+    // every reader with the tick of its last use
+    readers: HashMap<u32, (Reader, u64)>,
+    tick: u64,
+    capacity: Option<usize>,
 }
 
-impl<K, V> Store<K, V>
+impl ReaderCache {
+    fn new(capacity: Option<usize>) -> ReaderCache {
+        ReaderCache{readers: HashMap::new(), tick: 0, capacity}
+    }
+
+    fn len(&self) -> usize {
+        self.readers.len()
+    }
+
+    // returns the reader of the file, opening it if needed. active tells the files whose
+    // readers are never closed to make room.
+    fn get_or_open(&mut self, file_id: u32, open: impl FnOnce() -> Result<Reader>, active: impl Fn(u32) -> bool) -> Result<&mut Reader> {
+        if !self.readers.contains_key(&file_id) {
+            let reader = open()?;
+            self.insert(file_id, reader, active);
+        }
+        self.tick += 1;
+        let (reader, used) = self.readers.get_mut(&file_id).unwrap();
+        *used = self.tick;
+        Ok(reader)
+    }
+
+    fn insert(&mut self, file_id: u32, reader: Reader, active: impl Fn(u32) -> bool) {
+        if let Some(capacity) = self.capacity {
+            while self.readers.len() >= capacity.max(1) {
+                let lru = self.readers.iter()
+                    .filter(|(&id, _)| !active(id))
+                    .min_by_key(|(_, (_, used))| *used)
+                    .map(|(&id, _)| id);
+                let Some(lru) = lru else {
+                    break;
+                };
+                trace!("closed reader of file {} to stay within {} open readers", lru, capacity);
+                self.readers.remove(&lru);
+            }
+        }
+        self.tick += 1;
+        self.readers.insert(file_id, (reader, self.tick));
+    }
+
+    fn retain(&mut self, mut f: impl FnMut(u32) -> bool) {
+        self.readers.retain(|&file_id, _| f(file_id));
+    }
+}
+
+impl<K, V, C> Store<K, V, C>
 where
     K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
     V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    C: Codec,
 {
-    pub fn new(dir: &Path) -> Result<Store<K, V>> {
-        let _ = fs::create_dir_all(dir);
+    pub fn new(dir: &Path, codec: C, options: StoreOptions) -> Result<Store<K, V, C>> {
+        if !options.read_only {
+            let _ = fs::create_dir_all(dir);
+        }
+        let lock = lock_dir(dir, options.read_only)?;
+        place_log_files(dir, options.segment_layout, options.read_only)?;
         let inactive_file_ids = get_inactive_file_ids(dir)?;
-        let index = SkipMap::new();
-        let mut readers = HashMap::new();
-        let mut new_file_id = 1;
-        if let Some(file_id) = inactive_file_ids.last() {
-            new_file_id = file_id + 1;
+        let index: SkipMap<K, IndexSlot> = SkipMap::new();
+        let mut readers = ReaderCache::new(options.max_open_readers);
+        let first_file_id = inactive_file_ids.last().map_or(1, |file_id| file_id + 1);
+        // a read-only store has no writers, hence no active files
+        let shards = if options.read_only { 0 } else { options.shards.max(1) as u32 };
+        let mut writers = Vec::with_capacity(shards as usize);
+        for file_id in first_file_id..first_file_id + shards {
+            let filename = options.segment_layout.file_name(dir, file_id);
+            writers.push(Mutex::new(Writer::new(file_id, &filename, &options)?));
+            readers.insert(file_id, Reader::with_capacity(&filename, options.buffer_size)?, |_| true);
         }
-        let new_filename = log_file_name(dir, new_file_id);
-        let writer = Arc::new(Mutex::new(Writer::new(new_file_id, &new_filename)?));
-        readers.insert(new_file_id, Reader::new(&new_filename)?);
 
-        let store = Store{
+        let committer = match options.durability {
+            Durability::GroupCommit{window, max_writes} => Some(Arc::new(Committer::new(window, max_writes))),
+            _ => None,
+        };
+
+        let filters = options.bloom_filters.then(|| Arc::new(RwLock::new(HashMap::new())));
+        let eviction = (options.max_keys.is_some() || options.max_disk_bytes.is_some()).then(|| Arc::new(Eviction{
+            insertions: Mutex::new(VecDeque::new()),
+            running: Mutex::new(()),
+        }));
+
+        let mut store = Store{
             dir: Arc::new(dir.to_path_buf()),
+            codec,
+            options,
             readers: RefCell::new(readers),
-            writer,
+            writers: Arc::new(writers),
+            next_file_id: Arc::new(AtomicU32::new(first_file_id + shards)),
             index: Arc::new(index),
             last_compaction_point: Arc::new(AtomicU32::new(0)),
+            files: Arc::new(RwLock::new(())),
+            compaction_lock: Arc::new(Mutex::new(())),
+            compacting: Arc::new(AtomicBool::new(false)),
+            background: Arc::new(Mutex::new(None)),
+            snapshots: Arc::new(Mutex::new(Snapshots::default())),
+            committer,
+            filters,
+            live_bytes: Arc::new(AtomicU64::new(0)),
+            eviction,
+            read_only_uncompacted: 0,
+            metrics: Arc::new(AtomicMetrics::default()),
+            _lock: lock.map(Arc::new),
+            watchers: Arc::new(Watchers{watched: AtomicUsize::new(0), channels: Mutex::new(BTreeMap::new())}),
+            next_version: Arc::new(AtomicU64::new(1)),
             _phantom: PhantomData,
         };
-        store.writer.lock().unwrap().uncompacted = store.load_inactive_files(Arc::clone(&store.index))?;
+        let (uncompacted, max_version) = store.load_inactive_files(Arc::clone(&store.index))?;
+        store.next_version.store(max_version + 1, Ordering::SeqCst);
+        match store.writers.first() {
+            Some(writer) => writer.lock().unwrap().uncompacted = uncompacted,
+            None => store.read_only_uncompacted = uncompacted,
+        }
+        // only the live keys need to be in the filters, they are all the filters are asked about
+        let mut live_bytes = 0;
+        for entry in store.index.iter() {
+            let offset = entry.value().get();
+            live_bytes += offset.end - offset.start;
+            store.filter_insert(offset.file_id, entry.key())?;
+        }
+        store.live_bytes.store(live_bytes, Ordering::SeqCst);
+        // slots are created in replay order, which is the order the keys were inserted in
+        if let Some(eviction) = &store.eviction {
+            let mut insertions = store.index.iter().map(|entry| (entry.value().seq(), entry.key().clone())).collect::<Vec<_>>();
+            insertions.sort_by_key(|(seq, _)| *seq);
+            *eviction.insertions.lock().unwrap() = insertions.into();
+        }
+        if !store.options.read_only {
+            store.enforce_limits()?;
+            // otherwise the stale entries found on open stay until enough new writes pile up
+            if store.options.compact_on_open && store.uncompacted_bytes() > COMPACTION_THRESHOLD {
+                info!("compacting on open, {} stale bytes found", store.uncompacted_bytes());
+                store.run_compaction(&AtomicBool::new(false), &mut |_, _| {})?;
+            }
+        }
 
         Ok(store)
     }
 
     // loads older inactive log files into the given index and adds the corresponding reader to
-    // internal map
-    pub fn load_inactive_files(&self, index: Arc<SkipMap<K, EntryOffset>>) -> Result<u64> {
+    // internal map. returns the stale bytes found and the highest version of any set, stale or
+    // not, so that no version is handed out twice.
+    pub fn load_inactive_files(&self, index: Arc<SkipMap<K, IndexSlot>>) -> Result<(u64, u64)> {
         let inactive_file_ids = get_inactive_file_ids(&self.dir)?;
-        let mut uncompacted = 0;
+        let (mut uncompacted, mut max_version) = (0, 0);
         for file_id in inactive_file_ids {
-            let filename = log_file_name(&self.dir, file_id);
-            let mut reader = Reader::new(&filename)?;
-            uncompacted += reader.load_index::<K, V>(file_id, Arc::clone(&index))?;
-            self.readers.borrow_mut().insert(file_id, reader);
+            let mut reader = self.open_reader(file_id)?;
+            let (stale, end, version) = reader.load_index::<K, V, C>(&self.codec, file_id, Arc::clone(&index))?;
+            uncompacted += stale;
+            max_version = max_version.max(version);
+            self.repair_tail(file_id, end)?;
+            self.readers.borrow_mut().insert(file_id, reader, |file_id| self.is_active(file_id));
         }
 
-        Ok(uncompacted)
+        Ok((uncompacted, max_version))
+    }
+
+    // cuts off what a torn write left behind the last complete entry of the file, which ends at
+    // end. replay skips it either way, but it would otherwise stay on disk until the next
+    // compaction and be reported as damage again on every open. read-only stores leave it.
+    fn repair_tail(&self, file_id: u32, end: u64) -> Result<()> {
+        let filename = self.log_file_name(file_id);
+        let len = fs::metadata(&filename)?.len();
+        if end >= len {
+            return Ok(());
+        }
+        if self.options.read_only {
+            warn!("log file {} has {} bytes of a torn write after offset {}, left in place by a read-only open", file_id, len - end, end);
+            return Ok(());
+        }
+
+        let file = fs::OpenOptions::new().write(true).open(&filename)?;
+        file.set_len(end)?;
+        if self.options.durability.syncs_writes() {
+            file.sync_all()?;
+        }
+        warn!("truncated log file {} to {} bytes, discarding {} bytes of a torn write", file_id, end, len - end);
+
+        Ok(())
+    }
+
+    // adds the key to the bloom filter of the given file, if the store keeps filters
+    fn filter_insert(&self, file_id: u32, key: &K) -> Result<()> {
+        let Some(filters) = &self.filters else {
+            return Ok(());
+        };
+
+        let key = self.codec.encode(key)?;
+        if let Some(filter) = filters.read().unwrap().get(&file_id) {
+            filter.lock().unwrap().insert(&key);
+            return Ok(());
+        }
+        filters.write().unwrap().entry(file_id).or_insert_with(|| Mutex::new(SegmentFilter::new())).get_mut().unwrap().insert(&key);
+
+        Ok(())
+    }
+
+    // false if no log file holds the key, which the bloom filters tell without looking at the
+    // index. always true if the store keeps no filters.
+    pub fn may_contain(&self, key: &K) -> Result<bool> {
+        let Some(filters) = &self.filters else {
+            return Ok(true);
+        };
+
+        let key = self.codec.encode(key)?;
+        Ok(filters.read().unwrap().values().any(|filter| filter.lock().unwrap().contains(&key)))
+    }
+
+    pub fn compaction_guard(&self) -> CompactionGuard {
+        CompactionGuard{
+            background: Arc::clone(&self.background),
+        }
+    }
+
+    // starts the ttl sweeper if the options ask for one. the thread holds a handle of its own,
+    // so the store stays open until the sweeper is dropped.
+    pub fn start_sweeper(&self) -> Result<Option<Sweeper>> {
+        let Some(interval) = self.options.ttl_sweep_interval.filter(|_| !self.options.read_only) else {
+            return Ok(None);
+        };
+
+        let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+        let store = self.clone();
+        let handle = thread::Builder::new()
+            .name("kvs-ttl-sweeper".to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    match store.sweep_expired() {
+                        Ok(0) => {},
+                        Ok(swept) => debug!("swept {} expired keys", swept),
+                        Err(err) => error!("ttl sweep failed: {}", err),
+                    }
+                }
+            })?;
+        Ok(Some(Sweeper{stop: Some(stop), handle: Some(handle)}))
+    }
+
+    // keeps the log files from being deleted while the guard is alive, must be held while looking
+    // up and reading an offset
+    pub fn pin_files(&self) -> RwLockReadGuard<'_, ()> {
+        self.files.read().unwrap()
     }
 
     pub fn read(&self, file_id: u32, start: u64, end: u64) -> Result<Option<V>> {
-        self.close_stale_fds()?;
+        self.with_reader(file_id, |reader| reader.read::<K, V, C>(&self.codec, start, end))
+    }
+
+    // same as read, but returns the whole entry
+    pub fn read_entry(&self, offset: &EntryOffset) -> Result<Entry<K, V>> {
+        let payload = self.with_reader(offset.file_id, |reader| reader.read_payload(offset.start, offset.end))?;
+        self.codec.decode(&payload)
+    }
+
+    // runs f on the reader for the given file, opening it if this handle has not done so yet or
+    // has closed it since to stay within max_open_readers
+    fn with_reader<T>(&self, file_id: u32, f: impl FnOnce(&mut Reader) -> Result<T>) -> Result<T> {
+        self.close_stale_fds();
         let mut readers = self.readers.borrow_mut();
-        if !readers.contains_key(&file_id) {
-            let filename = log_file_name(&self.dir, file_id);
-            readers.insert(file_id, Reader::new(&filename)?);
+        f(readers.get_or_open(file_id, || self.open_reader(file_id), |file_id| self.is_active(file_id))?)
+    }
+
+    // whether a writer appends to the file, judged by the writers having the highest ids. a
+    // writer that has moved to a new file on its own with segment_max_bytes pushes the others
+    // out of that range, which only costs their readers a reopen, as does a file counting as
+    // inactive while the writers move.
+    fn is_active(&self, file_id: u32) -> bool {
+        file_id >= self.next_file_id.load(Ordering::SeqCst).saturating_sub(self.writers.len() as u32)
+    }
+
+    fn log_file_name(&self, file_id: u32) -> PathBuf {
+        self.options.segment_layout.file_name(&self.dir, file_id)
+    }
+
+    fn open_reader(&self, file_id: u32) -> Result<Reader> {
+        Reader::with_capacity(&self.log_file_name(file_id), self.options.buffer_size)
+    }
+
+    // number of log files this handle holds open for reading
+    pub fn open_readers(&self) -> usize {
+        self.readers.borrow().len()
+    }
+
+    // index of the writer the entries of the key go through. the shard only decides which
+    // writer a key waits for, it is not recorded anywhere, so it may change between opens.
+    fn shard(&self, key: &K) -> Result<usize> {
+        if self.writers.len() == 1 {
+            return Ok(0);
         }
-        let reader = readers.get_mut(&file_id).unwrap();
-        reader.read::<K, V>(start, end)
+
+        Ok(crc32fast::hash(&self.codec.encode(key)?) as usize % self.writers.len())
+    }
+
+    // fails on a read-only store, must be checked by every write before taking writer locks
+    fn check_writable(&self) -> Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn lock_writer(&self, key: &K) -> Result<MutexGuard<'_, Writer>> {
+        self.check_writable()?;
+        Ok(self.writers[self.shard(key)?].lock().unwrap())
+    }
+
+    // locks every writer, which stops all writes and index changes until the guards are dropped
+    fn lock_writers(&self) -> Vec<MutexGuard<'_, Writer>> {
+        self.writers.iter().map(|writer| writer.lock().unwrap()).collect()
+    }
+
+    // moves every writer to a new active file, in shard order, so that every file that exists
+    // now sorts below every file written from now on. must be called with every writer locked.
+    fn roll_writers(&self, writers: &mut [MutexGuard<'_, Writer>]) -> Result<()> {
+        for writer in writers.iter_mut() {
+            let file_id = self.next_file_id.fetch_add(1, Ordering::SeqCst);
+            **writer = Writer::new(file_id, &self.log_file_name(file_id), &self.options)?;
+        }
+
+        Ok(())
+    }
+
+    // moves the writer to a new active file if its current one has reached segment_max_bytes,
+    // must be called with the writer locked before it writes. the new file takes the next id,
+    // which sorts after every file the key's earlier entries are in.
+    fn roll_if_full(&self, writer: &mut Writer) -> Result<()> {
+        if self.options.segment_max_bytes.is_none_or(|max_bytes| writer.pos < max_bytes) {
+            return Ok(());
+        }
+
+        let file_id = self.next_file_id.fetch_add(1, Ordering::SeqCst);
+        debug!("log file {} reached {} bytes, writes moved to file {}", writer.file_id, writer.pos, file_id);
+        let uncompacted = writer.uncompacted;
+        *writer = Writer::new(file_id, &self.log_file_name(file_id), &self.options)?;
+        writer.uncompacted = uncompacted;
+        Ok(())
+    }
+
+    // unlocks the writers once they are done writing and, under Durability::GroupCommit, waits
+    // until what they wrote is on disk. the writes are visible to readers before that, but the
+    // call that made them only returns once they are durable.
+    fn release<'a>(&self, writers: impl IntoIterator<Item = MutexGuard<'a, Writer>>) -> Result<()> {
+        let Some(committer) = &self.committer else {
+            return Ok(());
+        };
+
+        let mut ticket = 0;
+        for writer in writers {
+            ticket = ticket.max(committer.register(&writer)?);
+        }
+        committer.wait(ticket)
+    }
+
+    // sets the key and returns the version of the new entry
+    pub fn write(&self, key: K, val: V, expires_at: Option<u64>) -> Result<u64> {
+        let mut writer = self.lock_writer(&key)?;
+        let version = self.write_locked(&mut writer, key, val, expires_at)?;
+        self.release([writer])?;
+        self.enforce_limits()?;
+
+        Ok(version)
+    }
+
+    // same as write, but also returns the value the key held before, which is read under the
+    // writer lock so no other write can slip in between
+    pub fn write_and_get(&self, key: K, val: V, expires_at: Option<u64>) -> Result<Option<V>> {
+        let mut writer = self.lock_writer(&key)?;
+        let old_val = self.read_current(&key)?;
+        self.write_locked(&mut writer, key, val, expires_at)?;
+        self.release([writer])?;
+        self.enforce_limits()?;
+
+        Ok(old_val)
     }
 
-    pub fn write(&self, key: K, b: &[u8]) -> Result<()> {
-        let mut writer = self.writer.lock().unwrap();
+    // same as write, but only if check accepts the value the key currently holds. the value is
+    // read under the writer lock, so it cannot change before the write. returns whether the
+    // entry was written.
+    pub fn write_if(&self, key: K, val: V, expires_at: Option<u64>, check: impl FnOnce(Option<V>) -> bool) -> Result<bool> {
+        let mut writer = self.lock_writer(&key)?;
+        if !check(self.read_current(&key)?) {
+            return Ok(false);
+        }
+        self.write_locked(&mut writer, key, val, expires_at)?;
+        self.release([writer])?;
+        self.enforce_limits()?;
+
+        Ok(true)
+    }
+
+    // same as write, but only if the key is at the expected version, 0 standing for a missing
+    // or expired key. only the index is consulted, under the writer lock. returns whether the
+    // entry was written.
+    pub fn write_if_version(&self, key: K, val: V, expected_version: u64) -> Result<bool> {
+        let mut writer = self.lock_writer(&key)?;
+        let version = match self.index.get(&key).map(|slot| slot.value().get()) {
+            Some(offset) if !offset.is_expired() => offset.version,
+            _ => 0,
+        };
+        if version != expected_version {
+            return Ok(false);
+        }
+        self.write_locked(&mut writer, key, val, None)?;
+        self.release([writer])?;
+        self.enforce_limits()?;
+
+        Ok(true)
+    }
+
+    // replaces the value the key currently holds with the one f computes from it. the value is
+    // read under the writer lock, so concurrent updates of the same key are applied one after
+    // the other. returns the new value.
+    pub fn update(&self, key: K, f: impl FnOnce(Option<V>) -> V) -> Result<V> {
+        let mut writer = self.lock_writer(&key)?;
+        let new_val = f(self.read_current(&key)?);
+        self.write_locked(&mut writer, key, new_val.clone(), None)?;
+        self.release([writer])?;
+        self.enforce_limits()?;
+
+        Ok(new_val)
+    }
+
+    // reads the live value of the key, if any
+    fn read_current(&self, key: &K) -> Result<Option<V>> {
+        let _files = self.pin_files();
+        match self.index.get(key).map(|slot| slot.value().get()) {
+            Some(offset) if !offset.is_expired() => self.read(offset.file_id, offset.start, offset.end),
+            _ => Ok(None),
+        }
+    }
+
+    // encodes a set of the key with the next version, which it returns as well. must be called
+    // with the writer of the key's shard, and the entry written before the lock is released.
+    fn encode_set(&self, key: &K, val: V, expires_at: Option<u64>) -> Result<(Vec<u8>, u64)> {
+        let version = self.next_version.fetch_add(1, Ordering::SeqCst);
+        let cmd = Entry::init_set(key.clone(), val, expires_at, version);
+
+        Ok((self.codec.encode(&cmd)?, version))
+    }
+
+    // appends a set of the key and points the key at it, must be called with the writer of the
+    // key's shard. returns the version of the entry.
+    fn write_locked(&self, writer: &mut Writer, key: K, val: V, expires_at: Option<u64>) -> Result<u64> {
+        self.roll_if_full(writer)?;
+        let event = self.is_watching().then(|| Event::Set(val.clone()));
+        let (b, version) = self.encode_set(&key, val, expires_at)?;
         let pos = writer.pos;
-        let end_pos = writer.write(b)?;
+        let end_pos = writer.write(&b)?;
         let curr_file_id = writer.file_id;
 
-        if let Some(old_val) = self.index.get(&key) {
-            writer.uncompacted += old_val.value().end - old_val.value().start;
+        let offset = EntryOffset{file_id: curr_file_id, start: pos, end: end_pos, expires_at, version};
+        trace!("wrote {:?} to file {} at {}..{}", key, curr_file_id, pos, end_pos);
+        let watched = event.map(|event| (key.clone(), event));
+        self.index_insert(writer, key, offset)?;
+        self.record(|metrics| metrics.record_set(end_pos - pos));
+        if let Some((key, event)) = watched {
+            self.notify(&key, || Ok(event))?;
+        }
+
+        self.maybe_compact(writer);
+        Ok(version)
+    }
+
+    // points the key at the given offset and keeps the filters, the byte counts and the
+    // insertion order up to date, must be called with the writer of the key's shard
+    fn index_insert(&self, writer: &mut Writer, key: K, offset: EntryOffset) -> Result<()> {
+        self.filter_insert(offset.file_id, &key)?;
+        self.live_bytes.fetch_add(offset.end - offset.start, Ordering::SeqCst);
+        let tracked = self.eviction.as_ref().map(|eviction| (eviction, key.clone()));
+        match index_put(&self.index, key, offset) {
+            Some(old_val) => {
+                writer.uncompacted += old_val.end - old_val.start;
+                self.live_bytes.fetch_sub(old_val.end - old_val.start, Ordering::SeqCst);
+            },
+            None => if let Some((eviction, key)) = tracked {
+                let seq = self.index.get(&key).unwrap().value().seq();
+                let mut insertions = eviction.insertions.lock().unwrap();
+                insertions.push_back((seq, key));
+                // drop the entries of removed keys once they make up most of the queue
+                if insertions.len() > 2 * self.index.len() + 1024 {
+                    insertions.retain(|(seq, key)| self.index.get(key).is_some_and(|slot| slot.value().seq() == *seq));
+                }
+            },
         }
-        self.index.insert(key, EntryOffset{file_id: curr_file_id, start: pos, end: end_pos});
 
-        if writer.uncompacted > COMPACTION_THRESHOLD {
-            let new_file_id = writer.compact::<K, V>(curr_file_id, self.dir.to_path_buf(), &self.readers, Arc::clone(&self.index))?;
-            self.last_compaction_point.store(new_file_id, Ordering::SeqCst);
-            self.close_stale_fds()?;
+        Ok(())
+    }
+
+    // evicts the oldest inserted keys while the store holds more keys or live bytes than its
+    // options allow. called after the writers have been released, since an evicted key may be
+    // of another shard.
+    fn enforce_limits(&self) -> Result<()> {
+        let Some(eviction) = &self.eviction else {
+            return Ok(());
+        };
+
+        let _running = eviction.running.lock().unwrap();
+        while self.options.max_keys.is_some_and(|max_keys| self.index.len() > max_keys)
+            || self.options.max_disk_bytes.is_some_and(|max_bytes| self.live_bytes.load(Ordering::SeqCst) > max_bytes)
+        {
+            let Some((seq, key)) = eviction.insertions.lock().unwrap().pop_front() else {
+                break;
+            };
+            let mut writer = self.lock_writer(&key)?;
+            if self.index.get(&key).is_none_or(|slot| slot.value().seq() != seq) {
+                continue;
+            }
+            debug!("evicting {:?} to stay within the limits", key);
+            self.remove_locked(&mut writer, key)?;
+            self.release([writer])?;
         }
 
         Ok(())
     }
 
+    // appends all encoded entries under the locks of their shards with a single flush per
+    // writer, the keys only become visible once the whole batch has been flushed
+    pub fn write_batch(&self, entries: Vec<(K, V)>) -> Result<()> {
+        self.check_writable()?;
+        let mut sharded = Vec::with_capacity(entries.len());
+        for (key, val) in entries {
+            sharded.push((self.shard(&key)?, key, val));
+        }
+        let mut writers = BTreeMap::new();
+        for (shard, _, _) in &sharded {
+            writers.entry(*shard).or_insert_with(|| self.writers[*shard].lock().unwrap());
+        }
+        // a batch stays in a single file per writer, which is flushed and synced at once
+        for writer in writers.values_mut() {
+            self.roll_if_full(writer)?;
+        }
+
+        let mut offsets = Vec::with_capacity(sharded.len());
+        for (shard, key, val) in sharded {
+            let writer = writers.get_mut(&shard).unwrap();
+            let event = self.is_watching().then(|| Event::Set(val.clone()));
+            let (b, version) = self.encode_set(&key, val, None)?;
+            let start = writer.pos;
+            let end = writer.append(&b)?;
+            offsets.push((shard, key, event, EntryOffset{file_id: writer.file_id, start, end, expires_at: None, version}));
+        }
+        for writer in writers.values_mut() {
+            writer.commit()?;
+        }
+        debug!("wrote a batch of {} entries through {} writers", offsets.len(), writers.len());
+
+        for (shard, key, event, offset) in offsets {
+            self.record(|metrics| metrics.record_set(offset.end - offset.start));
+            let watched = event.map(|event| (key.clone(), event));
+            self.index_insert(writers.get_mut(&shard).unwrap(), key, offset)?;
+            if let Some((key, event)) = watched {
+                self.notify(&key, || Ok(event))?;
+            }
+        }
+
+        for writer in writers.values() {
+            self.maybe_compact(writer);
+        }
+        self.release(writers.into_values())?;
+        self.enforce_limits()
+    }
+
+    // writes the entries of the operations between a begin and a commit marker, with a single
+    // flush, and only then applies them to the index. replay drops the entries of a transaction
+    // whose commit marker is missing, so a crash leaves either all of them or none. None stands
+    // for the removal of the key, which gets a tombstone whether the key exists or not.
+    //
+    // the entries of a transaction have to stay in one file. if its keys belong to several
+    // shards, every one of their writers is moved to a new file first, as for clear, and the
+    // transaction goes into the lowest of them. it then sorts after the earlier entries of all its
+    // keys and before their later ones.
+    pub fn transaction(&self, ops: Vec<(K, Option<V>)>) -> Result<()> {
+        self.check_writable()?;
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let mut shards = BTreeSet::new();
+        for (key, _) in &ops {
+            shards.insert(self.shard(key)?);
+        }
+        let mut writers = shards.into_iter().map(|shard| self.writers[shard].lock().unwrap()).collect::<Vec<_>>();
+        if writers.len() > 1 {
+            let uncompacted = writers.iter().map(|writer| writer.uncompacted).collect::<Vec<_>>();
+            self.roll_writers(&mut writers)?;
+            for (writer, uncompacted) in writers.iter_mut().zip(uncompacted) {
+                writer.uncompacted = uncompacted;
+            }
+        } else {
+            self.roll_if_full(&mut writers[0])?;
+        }
+
+        // the index changes are counted against the writer of the transaction, the counters only
+        // decide when to compact
+        let writer = &mut writers[0];
+        let (begin, commit): (Entry<K, V>, Entry<K, V>) = (Entry::Begin, Entry::Commit);
+        let (begin, commit) = (self.codec.encode(&begin)?, self.codec.encode(&commit)?);
+        // a None version marks a removal
+        let mut entries = Vec::with_capacity(ops.len());
+        for (key, val) in ops {
+            entries.push(match val {
+                Some(val) => {
+                    let event = self.is_watching().then(|| Event::Set(val.clone()));
+                    let (b, version) = self.encode_set(&key, val, None)?;
+                    (key, b, Some(version), event)
+                },
+                None => {
+                    let b = self.codec.encode(&Entry::<K, V>::init_rm(key.clone()))?;
+                    (key, b, None, self.is_watching().then_some(Event::Removed))
+                },
+            });
+        }
+
+        let pos = writer.pos;
+        let appended = (|| {
+            writer.append(&begin)?;
+            let mut offsets = Vec::with_capacity(entries.len());
+            for (key, b, version, event) in entries {
+                let start = writer.pos;
+                let end = writer.append(&b)?;
+                let offset = EntryOffset{file_id: writer.file_id, start, end, expires_at: None, version: version.unwrap_or(0)};
+                offsets.push((key, version.is_some(), event, offset));
+            }
+            let markers_end = writer.append(&commit)?;
+            writer.commit()?;
+            Ok((offsets, markers_end))
+        })();
+        let (offsets, end) = match appended {
+            Ok(appended) => appended,
+            Err(err) => {
+                // whatever made it to the file lacks a commit marker. later entries must not
+                // follow it, since replay would hold them back as part of the transaction.
+                let file_id = self.next_file_id.fetch_add(1, Ordering::SeqCst);
+                let uncompacted = writer.uncompacted;
+                **writer = Writer::new(file_id, &self.log_file_name(file_id), &self.options)?;
+                writer.uncompacted = uncompacted;
+                return Err(err);
+            },
+        };
+        debug!("wrote a transaction of {} entries to file {} at {}..{}", offsets.len(), writer.file_id, pos, end);
+
+        let mut entries_bytes = 0;
+        for (key, set, event, offset) in offsets {
+            let watched = event.map(|event| (key.clone(), event));
+            entries_bytes += offset.end - offset.start;
+            if set {
+                self.record(|metrics| metrics.record_set(offset.end - offset.start));
+                self.index_insert(writer, key, offset)?;
+            } else {
+                self.record(|metrics| metrics.record_remove(offset.end - offset.start));
+                // the tombstone itself is stale from the start
+                writer.uncompacted += offset.end - offset.start;
+                self.index_remove(writer, &key);
+            }
+            if let Some((key, event)) = watched {
+                self.notify(&key, || Ok(event))?;
+            }
+        }
+        // so are the markers
+        writer.uncompacted += end - pos - entries_bytes;
+
+        self.maybe_compact(writer);
+        // only the first writer has written anything
+        writers.truncate(1);
+        self.release(writers)?;
+        self.enforce_limits()
+    }
+
+    // appends a tombstone for the key and drops it from the index, the tombstone itself is
+    // never indexed. expired keys count as missing.
     pub fn remove(&self, key: K) -> Result<()> {
-        if !self.index.contains_key(&key) {
+        if !self.remove_if_exists(key.clone())? {
             return Err(Error::DoesNotExist{key: format!("{:?}", key)});
         }
+        Ok(())
+    }
+
+    // removes the key if it exists and returns whether it did. a missing or expired key gets no
+    // tombstone.
+    pub fn remove_if_exists(&self, key: K) -> Result<bool> {
+        let mut writer = self.lock_writer(&key)?;
+        if self.index.get(&key).is_none_or(|slot| slot.value().get().is_expired()) {
+            return Ok(false);
+        }
+
+        self.remove_locked(&mut writer, key)?;
+        self.release([writer])?;
+        Ok(true)
+    }
+
+    // writes a tombstone for every expired key and drops it from the index, returns how many
+    // keys it removed. the keys are found without any lock and each is checked again under the
+    // writer of its shard, since it may have been set again in the meantime.
+    pub fn sweep_expired(&self) -> Result<usize> {
+        self.check_writable()?;
+        let expired = self.index.iter()
+            .filter(|entry| entry.value().get().is_expired())
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
 
+        let mut swept = 0;
+        for key in expired {
+            let mut writer = self.lock_writer(&key)?;
+            if self.index.get(&key).is_some_and(|slot| slot.value().get().is_expired()) {
+                self.remove_locked(&mut writer, key)?;
+                swept += 1;
+            }
+            self.release([writer])?;
+        }
+        Ok(swept)
+    }
+
+    // same as remove, but returns the removed value, or None without writing anything if there
+    // is none. the value is read under the writer lock, so it is the one the tombstone removes.
+    pub fn remove_and_get(&self, key: K) -> Result<Option<V>> {
+        let mut writer = self.lock_writer(&key)?;
+        let Some(old_val) = self.read_current(&key)? else {
+            return Ok(None);
+        };
+
+        self.remove_locked(&mut writer, key)?;
+        self.release([writer])?;
+        Ok(Some(old_val))
+    }
+
+    // must be called with the writer of the key's shard
+    fn remove_locked(&self, writer: &mut Writer, key: K) -> Result<()> {
+        self.roll_if_full(writer)?;
         let cmd: Entry<K, V> = Entry::init_rm(key.clone());
-        let serialized = serde_json::to_string(&cmd).unwrap();
-        let b = serialized.as_bytes();
+        let serialized = self.codec.encode(&cmd)?;
+        let pos = writer.pos;
+        let end_pos = writer.write(&serialized)?;
+        self.record(|metrics| metrics.record_remove(end_pos - pos));
+        // the tombstone itself is stale from the start
+        writer.uncompacted += end_pos - pos;
+        self.index_remove(writer, &key);
+        self.notify(&key, || Ok(Event::Removed))?;
 
-        if let Some(old_val) = self.index.remove(&key) {
-            self.writer.lock().unwrap().uncompacted += old_val.value().end - old_val.value().start;
+        self.maybe_compact(writer);
+        Ok(())
+    }
+
+    // drops the key from the index and counts its entry as stale, must be called with the writer
+    // of the key's shard
+    fn index_remove(&self, writer: &mut Writer, key: &K) {
+        if let Some(old_val) = self.index.remove(key) {
+            let old_val = old_val.value().get();
+            writer.uncompacted += old_val.end - old_val.start;
+            self.live_bytes.fetch_sub(old_val.end - old_val.start, Ordering::SeqCst);
+        }
+    }
+
+    // writes a clear marker and empties the index. the keys are removed one by one, so a
+    // concurrent reader may still find some of them until the call returns.
+    //
+    // the marker has to sort after every entry written so far and before every entry written
+    // afterwards, in all shards. every writer is therefore moved to a new file first, and the
+    // marker opens the file of the first shard, which sorts lowest among the new ones.
+    pub fn clear(&self) -> Result<()> {
+        self.check_writable()?;
+        let mut writers = self.lock_writers();
+        let uncompacted = writers.iter().map(|writer| writer.uncompacted).collect::<Vec<_>>();
+        self.roll_writers(&mut writers)?;
+        for (writer, uncompacted) in writers.iter_mut().zip(uncompacted) {
+            writer.uncompacted = uncompacted;
         }
-        self.write(key.clone(), b)?;
+
+        // the removed entries are counted against the first shard, the counters only decide
+        // when to compact
+        let writer = &mut writers[0];
+        let cmd: Entry<K, V> = Entry::Clear;
+        let serialized = self.codec.encode(&cmd)?;
+        let pos = writer.pos;
+        writer.uncompacted += writer.write(&serialized)? - pos;
+
+        while let Some(entry) = self.index.pop_front() {
+            let old_val = entry.value().get();
+            writer.uncompacted += old_val.end - old_val.start;
+            self.live_bytes.fetch_sub(old_val.end - old_val.start, Ordering::SeqCst);
+            self.notify(entry.key(), || Ok(Event::Removed))?;
+        }
+        if let Some(eviction) = &self.eviction {
+            eviction.insertions.lock().unwrap().clear();
+        }
+
+        self.maybe_compact(writer);
+        // only the first writer has written anything
+        writers.truncate(1);
+        self.release(writers)
+    }
+
+    // compacts the log files on the calling thread regardless of how many stale bytes have
+    // accumulated, waiting for a running background compaction first
+    pub fn compact(&self) -> Result<()> {
+        self.check_writable()?;
+        self.run_compaction(&AtomicBool::new(false), &mut |_, _| {})
+    }
+
+    // compact, calling progress with the number of entries copied so far and the number to copy
+    // after each one. setting cancel stops the copy before the next entry: the partial output
+    // file is deleted, the old files stay as they are and Error::Cancelled is returned.
+    pub fn compact_with(&self, cancel: &AtomicBool, progress: &mut dyn FnMut(usize, usize)) -> Result<()> {
+        self.check_writable()?;
+        self.run_compaction(cancel, progress)
+    }
+
+    // syncs every write made so far to disk, whatever the durability. writes wait until it is
+    // done. every log file is synced, not just the active ones, since the files a writer or a
+    // compaction left behind may hold unsynced entries as well.
+    pub fn flush(&self) -> Result<()> {
+        let mut writers = self.lock_writers();
+        let _files = self.pin_files();
+        for writer in writers.iter_mut() {
+            writer.writer.flush()?;
+        }
+        let files = find_log_files(&self.dir)?;
+        for path in files.values() {
+            fs::File::open(path)?.sync_all()?;
+        }
+        // new files are only found again after a crash if their directory entries are synced,
+        // those in the subdirectories of a fan-out layout as well as the subdirectories themselves
+        let mut dirs = files.values().filter_map(|path| path.parent()).collect::<BTreeSet<_>>();
+        dirs.insert(&self.dir);
+        for dir in dirs {
+            fs::File::open(dir)?.sync_all()?;
+        }
+        debug!("synced {} log files", files.len());
 
         Ok(())
     }
 
-    pub fn close_stale_fds(&self) -> Result<()> {
-        let last_compaction_point = self.last_compaction_point.load(Ordering::SeqCst);
-        let mut readers = self.readers.borrow_mut();
-        let stale_file_ids = readers.keys()
-            .filter(|&file_id| file_id < &last_compaction_point)
-            .map(|file_id| file_id.clone())
-            .collect::<Vec<_>>();
+    // number of bytes in the log files taken up by stale entries
+    pub fn uncompacted_bytes(&self) -> u64 {
+        self.read_only_uncompacted + self.writers.iter().map(|writer| writer.lock().unwrap().uncompacted).sum::<u64>()
+    }
+
+    // copies the index and pins the files it points into. the copy is taken under all writer
+    // locks, so it reflects a single point in time, and the pin is taken before a compaction can
+    // move any of the copied keys elsewhere.
+    pub fn snapshot(&self) -> (BTreeMap<K, EntryOffset>, SnapshotPin) {
+        let _writers = self.lock_writers();
+        let index = self.index.iter().map(|entry| (entry.key().clone(), entry.value().get())).collect();
+        (index, self.pin_snapshot())
+    }
 
-        for file_id in stale_file_ids {
-            readers.remove(&file_id);
-            fs::remove_file(log_file_name(&self.dir, file_id))?;
+    fn pin_snapshot(&self) -> SnapshotPin {
+        self.snapshots.lock().unwrap().open += 1;
+        SnapshotPin{
+            dir: Arc::clone(&self.dir),
+            layout: self.options.segment_layout,
+            files: Arc::clone(&self.files),
+            snapshots: Arc::clone(&self.snapshots),
+        }
+    }
+
+    // reads every entry of the log files as they are now, in the order they were written. the
+    // writers are flushed and the length of every file noted under all writer locks, so later
+    // writes are left out. a running compaction is waited for, since its output file is only
+    // complete once it is done. the files are pinned like for a snapshot, later compactions
+    // leave them in place until the returned iterator is dropped.
+    pub fn log_order(&self) -> LogOrder<K, V, C> {
+        let _compaction = self.compaction_lock.lock().unwrap();
+        let mut writers = self.lock_writers();
+        let files = writers.iter_mut()
+            .try_for_each(|writer| writer.writer.flush())
+            .map_err(Error::from)
+            .and_then(|()| find_log_files(&self.dir))
+            .and_then(|files| {
+                files.into_iter()
+                    .map(|(file_id, path)| Ok((file_id, fs::metadata(&path)?.len(), path)))
+                    .collect::<Result<Vec<_>>>()
+            });
+        match files {
+            Ok(files) => LogOrder{
+                files: files.into_iter(),
+                entries: Vec::new().into_iter(),
+                failed: None,
+                codec: self.codec.clone(),
+                _pin: Some(self.pin_snapshot()),
+            },
+            Err(err) => LogOrder{
+                files: Vec::new().into_iter(),
+                entries: Vec::new().into_iter(),
+                failed: Some(err),
+                codec: self.codec.clone(),
+                _pin: None,
+            },
+        }
+    }
+
+    // writes the current entry of every live key to the given file, in key order. all writer
+    // locks are held throughout, so the file reflects a single point in time.
+    pub fn export(&self, path: &Path) -> Result<()> {
+        let _writers = self.lock_writers();
+        let _files = self.pin_files();
+        let mut out = BufWriter::new(fs::File::create(path)?);
+        out.write_all(EXPORT_HEADER)?;
+        for entry in self.index.iter() {
+            let offset = entry.value().get();
+            if offset.is_expired() {
+                continue;
+            }
+            let payload = self.with_reader(offset.file_id, |reader| reader.read_payload(offset.start, offset.end))?;
+            out.write_all(&entry::frame(&payload))?;
+        }
+        out.flush()?;
+
+        Ok(())
+    }
+
+    // writes every entry of a file created by export, entries that expired in the meantime are
+    // skipped
+    pub fn import(&self, path: &Path) -> Result<()> {
+        self.check_writable()?;
+        let mut input = BufReader::new(fs::File::open(path)?);
+        let mut header = [0; EXPORT_HEADER.len()];
+        let n = entry::read_full(&mut input, &mut header)?;
+        if &header[..n] != EXPORT_HEADER {
+            return Err(Error::UnhandledError(format!("{} is not an export file", path.display())));
+        }
+
+        while let Some(frame) = entry::read_frame(&mut input)? {
+            let payload = match frame {
+                Frame::Entry(payload) => payload,
+                frame => return Err(Error::UnhandledError(format!("invalid entry in {}: {:?}", path.display(), frame))),
+            };
+            match self.codec.decode::<Entry<K, V>>(&payload)? {
+                Entry::Set{expires_at, ..} if entry::is_expired(expires_at) => {},
+                Entry::Set{key, val, expires_at, ..} => {
+                    self.write(key, val, expires_at)?;
+                },
+                _ => return Err(Error::UnhandledError(format!("unexpected entry in {}", path.display()))),
+            }
         }
 
         Ok(())
     }
+
+    // counts the live keys and measures the log files, files are pinned so that a running
+    // compaction cannot delete one between listing and measuring it
+    pub fn stats(&self) -> Result<StoreStats> {
+        let live_keys = self.index.iter().filter(|entry| !entry.value().get().is_expired()).count() as u64;
+        let (segments, disk_bytes) = self.log_files_size()?;
+
+        Ok(StoreStats{
+            live_keys,
+            segments,
+            uncompacted_bytes: self.uncompacted_bytes(),
+            disk_bytes,
+        })
+    }
+
+    // total size of the log files, live entries and stale ones alike
+    pub fn disk_usage(&self) -> Result<u64> {
+        Ok(self.log_files_size()?.1)
+    }
+
+    // number of log files and their total size. the files are pinned so that a compaction does
+    // not delete one between listing it and reading its size.
+    fn log_files_size(&self) -> Result<(u64, u64)> {
+        let _files = self.pin_files();
+        let file_ids = get_inactive_file_ids(&self.dir)?;
+        let mut bytes = 0;
+        for file_id in &file_ids {
+            bytes += fs::metadata(self.log_file_name(*file_id))?.len();
+        }
+
+        Ok((file_ids.len() as u64, bytes))
+    }
+
+    // starts a background compaction if enough stale bytes have accumulated in the writer's
+    // shard and none is running yet, must be called with the writer locked
+    fn maybe_compact(&self, writer: &Writer) {
+        if writer.uncompacted <= COMPACTION_THRESHOLD || self.compacting.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let store = self.clone();
+        let spawned = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || {
+                if let Err(err) = store.run_compaction(&AtomicBool::new(false), &mut |_, _| {}) {
+                    error!("background compaction failed: {}", err);
+                }
+                store.compacting.store(false, Ordering::SeqCst);
+            });
+        match spawned {
+            Ok(handle) => {
+                // the previous compaction has already cleared the flag, so it is done or about
+                // to be
+                if let Some(prev) = self.background.lock().unwrap().replace(handle) {
+                    let _ = prev.join();
+                }
+            },
+            Err(err) => {
+                error!("failed to spawn background compaction: {}", err);
+                self.compacting.store(false, Ordering::SeqCst);
+            },
+        }
+    }
+
+    // step 2 of run_compaction: copies the given entries to the output file, makes the copies
+    // durable and adds their keys to the output file's filter. returns the copies and the size of
+    // the file.
+    fn copy_live(&self, compaction_file_id: u32, live: Vec<(K, EntryOffset)>, cancel: &AtomicBool, progress: &mut dyn FnMut(usize, usize)) -> Result<(Vec<Copied<K>>, u64)> {
+        let compaction_file = self.log_file_name(compaction_file_id);
+        let mut w = Writer::with_compression(compaction_file_id, &compaction_file, self.options.compression, &self.options)
+            .with_context(|| format!("creating compaction file {}", compaction_file_id))?;
+        let mut copied = Vec::with_capacity(live.len());
+        let total = live.len();
+        for (key, offset) in live {
+            if cancel.load(Ordering::SeqCst) {
+                info!("compaction cancelled after copying {} of {} entries", copied.len(), total);
+                return Err(Error::Cancelled);
+            }
+            let payload = self.with_reader(offset.file_id, |reader| reader.read_payload(offset.start, offset.end))
+                .with_context(|| format!("compacting log file {} at offset {}", offset.file_id, offset.start))?;
+            let start = w.pos;
+            let end = w.append(&payload).with_context(|| format!("writing compaction file {}", compaction_file_id))?;
+            let new_offset = EntryOffset{file_id: compaction_file_id, start, end, ..offset};
+            copied.push((key, offset, new_offset));
+            progress(copied.len(), total);
+        }
+        w.writer.flush().with_context(|| format!("writing compaction file {}", compaction_file_id))?;
+        // the copies replace entries that may already be on disk, so they have to be durable
+        // before the old files are deleted
+        if w.durability != Durability::None {
+            w.sync().with_context(|| format!("syncing compaction file {}", compaction_file_id))?;
+        }
+
+        for (key, _, new_offset) in &copied {
+            self.filter_insert(new_offset.file_id, key)?;
+        }
+
+        Ok((copied, w.pos))
+    }
+
+    // undoes a compaction that stopped before any key pointed into its output file: the file and
+    // its filter are deleted, and the stale bytes the roll cleared are given back to the writers
+    // so that the next compaction is still triggered in time
+    fn abandon_compaction(&self, compaction_file_id: u32, rolled_uncompacted: Vec<u64>) {
+        match fs::remove_file(self.log_file_name(compaction_file_id)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                error!("failed to delete the output file {} of an abandoned compaction: {}", compaction_file_id, err);
+            },
+            _ => {},
+        }
+        if let Some(filters) = &self.filters {
+            filters.write().unwrap().remove(&compaction_file_id);
+        }
+        let mut writers = self.lock_writers();
+        for (writer, uncompacted) in writers.iter_mut().zip(rolled_uncompacted) {
+            writer.uncompacted += uncompacted;
+        }
+        info!("compaction abandoned, file {} deleted", compaction_file_id);
+    }
+
+    // rewrites the live entries of every file older than the active ones into a single new file
+    // and deletes the old files. writes only wait for the writer locks to be taken twice, briefly:
+    //
+    // 1. under all writer locks, writes move to new active files, leaving a free file id just
+    //    below them for the compaction output. every file below that id is immutable from now on.
+    // 2. without the locks, live entries of the immutable files are copied to the output file.
+    //    concurrent writes go to the new active files and update the index as usual.
+    // 3. under all writer locks, every copied key that still points at the location it was
+    //    copied from is pointed at the copy. keys written or removed in the meantime are left
+    //    alone and their copy counts as stale. readers see either location, both stay valid
+    //    until step 4.
+    // 4. the old files are deleted. replaying the directory after a crash at any point yields the
+    //    same state, since the output file sorts between the old files and the new active ones.
+    fn run_compaction(&self, cancel: &AtomicBool, progress: &mut dyn FnMut(usize, usize)) -> Result<()> {
+        let _compaction = self.compaction_lock.lock().unwrap();
+
+        // the stale bytes the roll clears, given back to the writers if the compaction is cancelled
+        let (compaction_file_id, rolled_uncompacted) = {
+            let mut writers = self.lock_writers();
+            let compaction_file_id = self.next_file_id.fetch_add(1, Ordering::SeqCst);
+            let uncompacted = writers.iter().map(|writer| writer.uncompacted).collect::<Vec<_>>();
+            self.roll_writers(&mut writers)?;
+            info!("compaction started: copying files below {} into file {}, writes moved to files from {}", compaction_file_id, compaction_file_id, compaction_file_id + 1);
+            (compaction_file_id, uncompacted)
+        };
+
+        // the entries to copy are collected first so that progress can report the total
+        let mut live = Vec::new();
+        let mut expired = Vec::new();
+        for entry in self.index.iter() {
+            let offset = entry.value().get();
+            if offset.file_id >= compaction_file_id {
+                continue;
+            }
+            if offset.is_expired() {
+                expired.push((entry.key().clone(), offset));
+            } else {
+                live.push((entry.key().clone(), offset));
+            }
+        }
+
+        // until the copies are in the index, nothing points into the output file and the old
+        // files are untouched, so a compaction that stops early on any error can be undone
+        let (copied, compaction_file_size) = match self.copy_live(compaction_file_id, live, cancel, progress) {
+            Ok(copied) => copied,
+            Err(err) => {
+                self.abandon_compaction(compaction_file_id, rolled_uncompacted);
+                return Err(err);
+            },
+        };
+
+        {
+            let mut writers = self.lock_writers();
+            let (copies, expirations) = (copied.len(), expired.len());
+            let mut stale_copies = 0;
+            for (key, old_offset, new_offset) in copied {
+                match self.index.get(&key) {
+                    Some(slot) if slot.value().get().same_location(&old_offset) => {
+                        slot.value().replace(new_offset.clone());
+                        self.live_bytes.fetch_add(new_offset.end - new_offset.start, Ordering::SeqCst);
+                        self.live_bytes.fetch_sub(old_offset.end - old_offset.start, Ordering::SeqCst);
+                    },
+                    // the write that replaced the old entry counted it as stale after the roll,
+                    // but it is deleted with the old files. only the copy is left behind.
+                    _ => {
+                        let writer = &mut writers[self.shard(&key)?];
+                        writer.uncompacted = (writer.uncompacted + new_offset.end - new_offset.start).saturating_sub(old_offset.end - old_offset.start);
+                        stale_copies += 1;
+                    },
+                }
+            }
+            for (key, old_offset) in expired {
+                if self.index.get(&key).is_some_and(|slot| slot.value().get().same_location(&old_offset)) {
+                    self.index.remove(&key);
+                    self.live_bytes.fetch_sub(old_offset.end - old_offset.start, Ordering::SeqCst);
+                } else {
+                    let writer = &mut writers[self.shard(&key)?];
+                    writer.uncompacted = writer.uncompacted.saturating_sub(old_offset.end - old_offset.start);
+                }
+            }
+            debug!("compaction copied {} keys, {} of them overwritten meanwhile, and dropped {} expired keys", copies, stale_copies, expirations);
+        }
+
+        // no key points below the output file anymore
+        if let Some(filters) = &self.filters {
+            filters.write().unwrap().retain(|&file_id, _| file_id >= compaction_file_id);
+        }
+        self.last_compaction_point.store(compaction_file_id, Ordering::SeqCst);
+        self.close_stale_fds();
+        let _files = self.files.write().unwrap();
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let (mut removed_files, mut removed_bytes) = (0, 0);
+        for file_id in get_inactive_file_ids(&self.dir)? {
+            if file_id < compaction_file_id && snapshots.open > 0 {
+                snapshots.retired.insert(file_id);
+            } else if file_id < compaction_file_id {
+                let path = self.log_file_name(file_id);
+                removed_bytes += fs::metadata(&path)?.len();
+                fs::remove_file(path)?;
+                removed_files += 1;
+            }
+        }
+        if !snapshots.retired.is_empty() {
+            info!("open snapshots keep {} old files until they are dropped", snapshots.retired.len());
+        }
+        info!(
+            "compaction finished: {} files of {} bytes replaced by file {} of {} bytes, {} bytes reclaimed",
+            removed_files, removed_bytes, compaction_file_id, compaction_file_size, removed_bytes.saturating_sub(compaction_file_size),
+        );
+        self.record(|metrics| metrics.record_compaction());
+
+        Ok(())
+    }
+
+    // returns a channel receiving every change of the key from now on, in the order of the
+    // changes. the store keeps the sender until the receiver is dropped and the key changes once
+    // more.
+    pub fn watch(&self, key: K) -> Receiver<Event<V>> {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let mut channels = self.watchers.channels.lock().unwrap();
+        let senders = channels.entry(key).or_default();
+        if senders.is_empty() {
+            self.watchers.watched.fetch_add(1, Ordering::SeqCst);
+        }
+        senders.push(sender);
+        receiver
+    }
+
+    fn is_watching(&self) -> bool {
+        self.watchers.watched.load(Ordering::SeqCst) > 0
+    }
+
+    // sends the event to the watchers of the key, if any, only building it if there are. must be
+    // called with the writer of the key's shard, after the index has changed, which keeps the
+    // events of a key in order and lets a watcher read the value it was told about.
+    fn notify(&self, key: &K, event: impl FnOnce() -> Result<Event<V>>) -> Result<()> {
+        if !self.is_watching() {
+            return Ok(());
+        }
+        let mut channels = self.watchers.channels.lock().unwrap();
+        let Some(senders) = channels.get_mut(key) else {
+            return Ok(());
+        };
+        let event = event()?;
+        senders.retain(|sender| sender.send(event.clone()).is_ok());
+        if senders.is_empty() {
+            channels.remove(key);
+            self.watchers.watched.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        Ok(())
+    }
+
+    // passes an event to the counters of the store and to the sink of the options, if any
+    pub fn record(&self, f: impl Fn(&dyn Metrics)) {
+        f(&*self.metrics);
+        if let Some(sink) = &self.options.metrics {
+            f(&**sink);
+        }
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    // drops the readers of files retired by a compaction, the files themselves are deleted by
+    // the compaction
+    pub fn close_stale_fds(&self) {
+        let last_compaction_point = self.last_compaction_point.load(Ordering::SeqCst);
+        let mut readers = self.readers.borrow_mut();
+        let open = readers.len();
+        readers.retain(|file_id| file_id >= last_compaction_point);
+        if readers.len() < open {
+            debug!("closed {} readers of files below {}", open - readers.len(), last_compaction_point);
+        }
+    }
 }
 
-impl<K, V> Clone for Store<K, V>
+impl<K, V, C> Clone for Store<K, V, C>
 where
     K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
     V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    C: Codec,
 {
     fn clone(&self) -> Self {
         Self {
             dir: self.dir.clone(),
-            readers: RefCell::new(HashMap::new()),
-            writer: self.writer.clone(),
+            codec: self.codec.clone(),
+            options: self.options.clone(),
+            readers: RefCell::new(ReaderCache::new(self.options.max_open_readers)),
+            writers: Arc::clone(&self.writers),
+            next_file_id: Arc::clone(&self.next_file_id),
             index: self.index.clone(),
             last_compaction_point: Arc::clone(&self.last_compaction_point),
+            files: Arc::clone(&self.files),
+            compaction_lock: Arc::clone(&self.compaction_lock),
+            compacting: Arc::clone(&self.compacting),
+            background: Arc::clone(&self.background),
+            snapshots: Arc::clone(&self.snapshots),
+            committer: self.committer.clone(),
+            filters: self.filters.clone(),
+            live_bytes: Arc::clone(&self.live_bytes),
+            eviction: self.eviction.clone(),
+            read_only_uncompacted: self.read_only_uncompacted,
+            metrics: Arc::clone(&self.metrics),
+            _lock: self._lock.clone(),
+            watchers: Arc::clone(&self.watchers),
+            next_version: Arc::clone(&self.next_version),
             _phantom: PhantomData,
         }
     }
 }
 
-pub fn init_writer(file: &Path) -> Result<BufWriter<fs::File>> {
-    Ok(BufWriter::new(
+// opens the file for appending, buffered and with retries as the options say
+pub fn init_writer(file: &Path, options: &StoreOptions) -> Result<BufWriter<RetryingWriter<fs::File>>> {
+    // a fan-out layout puts the file in a subdirectory that may not exist yet
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(BufWriter::with_capacity(options.buffer_size, RetryingWriter::new(
         fs::OpenOptions::new()
         .create(true)
-        .write(true)
         .append(true)
-        .open(&file)?,
-    ))
+        .open(file)?,
+        options.write_retry,
+    )))
 }
 
 impl Writer {
-    pub fn new(file_id: u32, file: &Path) -> Result<Writer> {
-        let writer = init_writer(file)?;
-        
+    // takes the durability and the retries of writes from the options
+    pub fn new(file_id: u32, file: &Path, options: &StoreOptions) -> Result<Writer> {
+        Writer::with_compression(file_id, file, None, options)
+    }
+
+    // same as new, but compresses every entry it writes. the compression is recorded in the
+    // header, so it only applies to a file the writer creates.
+    pub fn with_compression(file_id: u32, file: &Path, compression: Option<Compression>, options: &StoreOptions) -> Result<Writer> {
+        let durability = options.durability;
+        let mut writer = init_writer(file, options)?;
+        let mut pos = writer.get_ref().get_ref().metadata()?.len();
+        if pos == 0 {
+            let header = if compression.is_some() { COMPRESSED_LOG_HEADER } else { LOG_HEADER };
+            writer.write_all(header)?;
+            writer.flush()?;
+            pos = header.len() as u64;
+            // a synced entry is only found again after a crash if the file it is in is too
+            if durability.syncs_writes() {
+                writer.get_ref().get_ref().sync_all()?;
+                if let Some(dir) = file.parent() {
+                    fs::File::open(dir)?.sync_all()?;
+                }
+            }
+        }
+
         Ok(Writer{
             file_id,
-            pos: 0,
+            pos,
             uncompacted: 0,
+            compression,
+            durability,
             writer,
         })
     }
 
-    // writes the given bytes to the file and returns the new cursor position
+    // frames the given encoded entry, writes it to the file and returns the new cursor position
     pub fn write(&mut self, b: &[u8]) -> Result<u64> {
-        self.writer.write(b)?;
-        self.writer.flush()?;
-        self.pos += b.len() as u64;
+        self.append(b)?;
+        self.commit()?;
 
         Ok(self.pos)
     }
 
-    // check existing keys in index against the corresponding file
-    // copy the log entry to a new file
-    // remove the inactive files from the dir as well as store hashmap
-    pub fn compact<K2, V2>(&mut self, file_id: u32, dir: PathBuf, readers: &RefCell<HashMap<u32, Reader>>, index: Arc<SkipMap<K2, EntryOffset>>) -> Result<u32>
-    where
-        K2: Clone + Serialize + DeserializeOwned + Ord + Send + 'static + Debug,
-        V2: Clone + Serialize + DeserializeOwned + Send + 'static,
-    {
-        // compaction output file
-        let compaction_file_id = file_id + 1;
-        let new_filename = log_file_name(&dir, compaction_file_id);
-        let w = Writer::new(compaction_file_id, &new_filename)?;
-        let mut readers_mut = readers.borrow_mut();
-        readers_mut.insert(compaction_file_id, Reader::new(&new_filename)?);
+    // hands the buffered entries to the os, which they need to be in before the index may point
+    // at them, and syncs them to disk if the durability asks for it. under group commit the
+    // store syncs them later, after releasing the writer.
+    fn commit(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if self.durability == Durability::Fsync {
+            self.sync()?;
+        }
 
-        let mut pos = 0;
-        let mut writer = w.writer;
-        for entry in index.iter() {
-            let offset: &EntryOffset = entry.value();
-            let reader = readers_mut.get_mut(&offset.file_id).unwrap_or_else(|| panic!("no reader for file_id: {}", offset.file_id));
-            let len = reader.read_into(offset.start, offset.end, &mut writer)?;
+        Ok(())
+    }
 
-            index.insert(entry.key().clone(), EntryOffset{file_id: compaction_file_id, start: pos, end: pos + len});
-            pos += len;
-        }
-        writer.flush()?;
+    // waits until everything handed to the os so far is on disk
+    fn sync(&self) -> Result<()> {
+        self.writer.get_ref().get_ref().sync_all()?;
 
-        let new_filename = log_file_name(&dir, compaction_file_id + 1);
-        self.writer = init_writer(&new_filename)?;
-        self.pos = 0;
-        self.uncompacted = 0;
-        readers_mut.insert(compaction_file_id + 1, Reader::new(&new_filename)?);
+        Ok(())
+    }
+
+    // same as write, but leaves the entry in the buffer until the next flush
+    fn append(&mut self, b: &[u8]) -> Result<u64> {
+        let framed = match self.compression {
+            Some(Compression::Zstd(level)) => entry::frame(&zstd::stream::encode_all(b, level)?),
+            None => entry::frame(b),
+        };
+        self.writer.write_all(&framed)?;
+        self.pos += framed.len() as u64;
 
-        Ok(compaction_file_id)
+        Ok(self.pos)
     }
 }
 
 impl Reader {
     pub fn new(file: &Path) -> Result<Reader> {
-        let f = fs::File::open(&file)?;
-        let reader = BufReader::new(f.try_clone()?);
+        Reader::with_capacity(file, DEFAULT_BUFFER_SIZE)
+    }
+
+    // reads through a buffer of the given capacity
+    pub fn with_capacity(file: &Path, capacity: usize) -> Result<Reader> {
+        let f = fs::File::open(file)?;
+        let mut reader = BufReader::with_capacity(capacity, f.try_clone()?);
+
+        // an empty or partially written header still counts as a framed file without entries
+        let mut header = [0; LOG_HEADER.len()];
+        let n = entry::read_full(&mut reader, &mut header)?;
+        let compressed = &header == COMPRESSED_LOG_HEADER;
+        let framed = compressed || LOG_HEADER.starts_with(&header[..n]);
 
         Ok(Reader{
             reader,
+            framed,
+            compressed,
         })
     }
 
@@ -262,84 +1772,330 @@ impl Reader {
 
     // reads from the given offset and returns a value if Set command is present at the
     // offset, otherwise returns None
-    pub fn read<K, V>(&mut self, start: u64, end: u64) -> Result<Option<V>>
+    pub fn read<K, V, C>(&mut self, codec: &C, start: u64, end: u64) -> Result<Option<V>>
     where
         K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
         V: Clone + Serialize + DeserializeOwned + Send + 'static,
+        C: Codec,
     {
-        let reader = self.read_limited(start, end)?;
+        let payload = self.read_payload(start, end)?;
 
-        if let Entry::Set{val, ..} = serde_json::from_reader::<_, Entry<K, V>>(reader)? {
+        if let Entry::Set{val, ..} = codec.decode::<Entry<K, V>>(&payload)? {
             Ok(Some(val))
         } else {
             Ok(None)
         }
     }
 
-    // reads from the given offset and copies to the given writer instance.
-    pub fn read_into(&mut self, start: u64, end: u64, writer: &mut BufWriter<fs::File>) -> Result<u64> {
-        let mut reader = self.read_limited(start, end)?;
-        Ok(copy(&mut reader, writer)?)
+    // reads the encoded entry stored at the given offset, verifying its checksum if the file is
+    // framed
+    pub fn read_payload(&mut self, start: u64, end: u64) -> Result<Vec<u8>> {
+        let mut b = Vec::with_capacity((end - start) as usize);
+        self.read_limited(start, end)?.read_to_end(&mut b)?;
+        if !self.framed {
+            return Ok(b);
+        }
+
+        match entry::read_frame(&mut b.as_slice())? {
+            Some(Frame::Entry(payload)) => decompress(payload, self.compressed),
+            frame => Err(Error::UnhandledError(format!("invalid entry at offset {}: {:?}", start, frame))),
+        }
     }
 
-    // loads index from the corresponding log file and computes and returns the size of uncompacted bytes
-    pub fn load_index<K, V>(&mut self, file_id: u32, index: Arc<SkipMap<K, EntryOffset>>) -> Result<u64>
+    // loads index from the corresponding log file and computes and returns the size of uncompacted bytes,
+    // along with the offset the last complete entry ends at and the highest version of its sets.
+    // the entries of a transaction are held back until its commit marker, those of a transaction
+    // left unfinished by a crash are skipped.
+    pub fn load_index<K, V, C>(&mut self, codec: &C, file_id: u32, index: Arc<SkipMap<K, IndexSlot>>) -> Result<(u64, u64, u64)>
     where
         K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
         V: Clone + Serialize + DeserializeOwned + Send + 'static,
+        C: Codec,
     {
-        let reader = &mut self.reader;
-        let mut cmd_start = reader.seek(SeekFrom::Start(0))?;
-        let mut stream = Deserializer::from_reader(reader).into_iter::<Entry<K, V>>();
         let mut uncompacted = 0;
-
-        while let Some(cmd) = stream.next() {
-            let cmd_end = stream.byte_offset() as u64;
-            match cmd? {
-                Entry::Set {key, ..} => {
-                    if let Some(old_val) = index.get(&key) {
-                        uncompacted += old_val.value().end - old_val.value().start;
+        let mut pending = None;
+        let mut max_version = 0;
+        let end = self.for_each_entry::<K, V, C>(codec, file_id, |cmd, cmd_start, cmd_end| {
+            if let Entry::Set{version, ..} = cmd {
+                max_version = max_version.max(version);
+            }
+            match cmd {
+                Entry::Begin => {
+                    if let Some((start, entries)) = pending.replace((cmd_start, Vec::new())) {
+                        uncompacted += skip_transaction(file_id, start, &entries);
                     }
-                    index.insert(key, EntryOffset{file_id, start: cmd_start, end: cmd_end});
+                    uncompacted += cmd_end - cmd_start;
                 },
-                Entry::Rm {key} => {
-                    if let Some(old_val) = index.remove(&key) {
-                        uncompacted += old_val.value().end - old_val.value().start;
+                Entry::Commit => {
+                    for (cmd, start, end) in pending.take().map(|(_, entries)| entries).unwrap_or_default() {
+                        uncompacted += index_entry(&index, file_id, cmd, start, end);
                     }
                     uncompacted += cmd_end - cmd_start;
-                }
+                },
+                cmd => match &mut pending {
+                    Some((_, entries)) => entries.push((cmd, cmd_start, cmd_end)),
+                    None => uncompacted += index_entry(&index, file_id, cmd, cmd_start, cmd_end),
+                },
+            }
+            Ok(())
+        })?;
+        if let Some((start, entries)) = pending {
+            uncompacted += skip_transaction(file_id, start, &entries);
+        }
+
+        Ok((uncompacted, end, max_version))
+    }
+
+    // calls f with every entry of the file and the byte range it takes up, in the order they
+    // were written, and returns the offset the last one ends at. a torn tail ends the file, any
+    // other damage fails with CorruptLog.
+    pub fn for_each_entry<K, V, C>(&mut self, codec: &C, file_id: u32, f: impl FnMut(Entry<K, V>, u64, u64) -> Result<()>) -> Result<u64>
+    where
+        K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+        V: Clone + Serialize + DeserializeOwned + Send + 'static,
+        C: Codec,
+    {
+        self.for_each_entry_until(codec, file_id, u64::MAX, f)
+    }
+
+    // like for_each_entry, but stops at the given offset. a file a writer still appends to is
+    // only read up to a length it had between two entries, what comes after may be half written.
+    pub fn for_each_entry_until<K, V, C>(&mut self, codec: &C, file_id: u32, until: u64, mut f: impl FnMut(Entry<K, V>, u64, u64) -> Result<()>) -> Result<u64>
+    where
+        K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+        V: Clone + Serialize + DeserializeOwned + Send + 'static,
+        C: Codec,
+    {
+        let compressed = self.compressed;
+        let reader = &mut self.reader;
+
+        if !self.framed {
+            let mut cmd_start = reader.seek(SeekFrom::Start(0))?;
+            while cmd_start < until {
+                let (cmd, len) = match codec.decode_next::<Entry<K, V>, _>(&mut *reader) {
+                    Ok(Some(next)) => next,
+                    Ok(None) => break,
+                    Err(err) => return Err(corrupt_log(file_id, cmd_start, err)),
+                };
+                let cmd_end = cmd_start + len;
+                f(cmd, cmd_start, cmd_end)?;
+                cmd_start = cmd_end;
+            }
+
+            return Ok(cmd_start);
+        }
+
+        let mut cmd_start = reader.seek(SeekFrom::Start(LOG_HEADER.len() as u64))?;
+        while cmd_start < until {
+            let payload = match entry::read_frame(reader)? {
+                Some(Frame::Entry(payload)) => payload,
+                Some(Frame::Corrupt) if !reader.fill_buf()?.is_empty() => {
+                    // a torn write only damages the tail, anything after a bad entry means the
+                    // file itself is damaged
+                    return Err(corrupt_log(file_id, cmd_start, Frame::Corrupt));
+                },
+                Some(frame) => {
+                    // a torn write can only leave garbage at the tail, so stop replaying here
+                    // instead of failing the whole open
+                    warn!("ignoring {:?} entry at offset {} of log file {}", frame, cmd_start, file_id);
+                    break;
+                },
+                None => break,
             };
+            let cmd_end = cmd_start + FRAME_HEADER_LEN + payload.len() as u64;
+            let cmd = decompress(payload, compressed)
+                .and_then(|payload| codec.decode::<Entry<K, V>>(&payload))
+                .map_err(|err| corrupt_log(file_id, cmd_start, err))?;
+            f(cmd, cmd_start, cmd_end)?;
             cmd_start = cmd_end;
         }
 
-        Ok(uncompacted)
+        Ok(cmd_start)
+    }
+}
+
+// applies a replayed entry to the index and returns the number of bytes it made stale
+fn index_entry<K, V>(index: &SkipMap<K, IndexSlot>, file_id: u32, cmd: Entry<K, V>, start: u64, end: u64) -> u64
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    let mut uncompacted = 0;
+    match cmd {
+        Entry::Set {key, expires_at, version, ..} => {
+            // an entry that expired while the store was closed is as good as removed
+            let old_val = if entry::is_expired(expires_at) {
+                uncompacted += end - start;
+                index.remove(&key).map(|slot| slot.value().get())
+            } else {
+                index_put(index, key, EntryOffset{file_id, start, end, expires_at, version})
+            };
+            if let Some(old_val) = old_val {
+                uncompacted += old_val.end - old_val.start;
+            }
+        },
+        Entry::Rm {key} => {
+            if let Some(old_val) = index.remove(&key) {
+                let old_val = old_val.value().get();
+                uncompacted += old_val.end - old_val.start;
+            }
+            uncompacted += end - start;
+        },
+        Entry::Clear => {
+            while let Some(entry) = index.pop_front() {
+                let old_val = entry.value().get();
+                uncompacted += old_val.end - old_val.start;
+            }
+            uncompacted += end - start;
+        },
+        // the markers are handled by load_index, they only take up space
+        Entry::Begin | Entry::Commit => uncompacted += end - start,
+    };
+
+    uncompacted
+}
+
+// drops the entries of a transaction that has no commit marker and returns their size
+fn skip_transaction<K, V>(file_id: u32, start: u64, entries: &[(Entry<K, V>, u64, u64)]) -> u64
+where
+    K: Clone + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Send + 'static,
+{
+    warn!("skipping {} entries of a transaction left unfinished at offset {} of log file {}", entries.len(), start, file_id);
+    entries.iter().map(|(_, start, end)| end - start).sum()
+}
+
+// points the key at the given offset and returns the offset it replaced. a key is only ever
+// modified by one thread at a time, either under the writer of its shard or while loading the
+// store.
+fn index_put<K>(index: &SkipMap<K, IndexSlot>, key: K, offset: EntryOffset) -> Option<EntryOffset>
+where
+    K: Ord + Send + 'static,
+{
+    if let Some(slot) = index.get(&key) {
+        return Some(slot.value().replace(offset));
+    }
+    index.insert(key, IndexSlot::new(offset));
+
+    None
+}
+
+// reports an entry that cannot be replayed, along with the reason
+fn corrupt_log(file_id: u32, offset: u64, cause: impl Debug) -> Error {
+    error!("log file {} is corrupt at offset {}: {:?}", file_id, offset, cause);
+    Error::CorruptLog{file_id, offset}
+}
+
+// undoes the compression of a framed payload read from a compressed file
+fn decompress(payload: Vec<u8>, compressed: bool) -> Result<Vec<u8>> {
+    if !compressed {
+        return Ok(payload);
+    }
+
+    Ok(zstd::stream::decode_all(payload.as_slice())?)
+}
+
+// calls f with every entry of every log file in the directory, in replay order, without
+// opening a store. f sees every entry before the first that cannot be read.
+pub fn dump<K, V, C>(dir: &Path, codec: &C, mut f: impl FnMut(LogRecord<K, V>) -> Result<()>) -> Result<()>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    C: Codec,
+{
+    for (file_id, path) in find_log_files(dir)? {
+        let mut reader = Reader::new(&path)?;
+        reader.for_each_entry::<K, V, C>(codec, file_id, |entry, start, end| f(LogRecord{file_id, start, end, entry}))?;
     }
+
+    Ok(())
 }
 
+// locks the directory for as long as the returned file stays open. a writable store takes the
+// lock exclusively, a read-only one shares it with other read-only ones. a read-only store does
+// not create the file, so a directory no writable store has opened yet is not locked.
+fn lock_dir(dir: &Path, read_only: bool) -> Result<Option<fs::File>> {
+    let path = dir.join(LOCK_FILE);
+    let file = if read_only {
+        match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+    } else {
+        fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path)?
+    };
+
+    let locked = if read_only { file.try_lock_shared() } else { file.try_lock() };
+    match locked {
+        Ok(()) => Ok(Some(file)),
+        Err(fs::TryLockError::WouldBlock) => Err(Error::Locked(dir.display().to_string())),
+        Err(fs::TryLockError::Error(err)) => Err(err.into()),
+    }
+}
 
 // goes through the log directory and returns all old/inactive file ids in a sorted order.
 fn get_inactive_file_ids(dir: &Path) -> Result<Vec<u32>> {
-   let filenames = fs::read_dir(dir)?
-       .filter_map(|res| res.ok())
-       .map(|entry| entry.path())
-       .filter_map(|path| {
-           if path.is_file() && path.extension().map_or(false, |ext| ext == "log") {
-               Some(path)
-           } else {
-               None
-           }
-       })
-       .collect::<Vec<_>>();
-
-    let mut file_ids: Vec<u32> = Vec::new();
-    for filepath in filenames {
-        let filename = filepath.file_name().unwrap();
-        let filename_str = filename.to_str().unwrap();
-        let file_id = filename_str.split(".log").collect::<Vec<_>>()[0];
-        file_ids.push(file_id.parse::<u32>().unwrap());
-    }
-
-    file_ids.sort();
-
-    Ok(file_ids)
+    Ok(find_log_files(dir)?.into_keys().collect())
+}
+
+// every log file by id, wherever a layout put it: right in the directory or in one of its
+// numbered subdirectories
+fn find_log_files(dir: &Path) -> Result<BTreeMap<u32, PathBuf>> {
+    let mut files = BTreeMap::new();
+    for path in fs::read_dir(dir)?.filter_map(|res| res.ok()).map(|entry| entry.path()) {
+        if path.is_dir() && path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.parse::<u32>().is_ok()) {
+            for path in fs::read_dir(&path)?.filter_map(|res| res.ok()).map(|entry| entry.path()) {
+                insert_log_file(&mut files, path);
+            }
+        } else {
+            insert_log_file(&mut files, path);
+        }
+    }
+
+    Ok(files)
+}
+
+fn insert_log_file(files: &mut BTreeMap<u32, PathBuf>, path: PathBuf) {
+    if !path.is_file() || path.extension().is_none_or(|ext| ext != "log") {
+        return;
+    }
+    if let Some(file_id) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u32>().ok()) {
+        files.insert(file_id, path);
+    }
+}
+
+// moves the log files another layout put elsewhere to where this one expects them. a crash
+// halfway leaves every file in one place or the other, where the next open finds it. a
+// read-only store cannot move them and fails instead.
+fn place_log_files(dir: &Path, layout: SegmentLayout, read_only: bool) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    let mut moved = 0;
+    for (file_id, path) in find_log_files(dir)? {
+        let target = layout.file_name(dir, file_id);
+        if path == target {
+            continue;
+        }
+        if read_only {
+            return Err(Error::UnhandledError(format!(
+                "log file {} is not where the {:?} layout puts it, open the store writable once to move it", path.display(), layout,
+            )));
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&path, &target)?;
+        moved += 1;
+        // drops the subdirectory of the previous layout once it is empty
+        if let Some(parent) = path.parent().filter(|parent| *parent != dir) {
+            let _ = fs::remove_dir(parent);
+        }
+    }
+    if moved > 0 {
+        info!("moved {} log files into the {:?} layout", moved, layout);
+    }
+
+    Ok(())
 }
@@ -1,5 +1,10 @@
+use crate::error::Result;
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::fmt::Debug;
+use std::io::{self, Read};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Entry<K, V>
@@ -7,8 +12,35 @@ where
     K: Clone + Ord + Send + Sync + 'static + Debug,
     V: Clone + Send + 'static,
 {
-    Set {key: K, val: V},
+    // expires_at is in unix millis, entries written before it existed never expire. version is
+    // taken from a counter of the store and written_at is in unix millis, both are 0 in entries
+    // written before they existed.
+    Set {
+        key: K,
+        val: V,
+        #[serde(default)] expires_at: Option<u64>,
+        #[serde(default)] version: u64,
+        #[serde(default)] written_at: u64,
+    },
     Rm {key: K},
+    // removes every key written before it
+    Clear,
+    // open and close a transaction, the entries between them only apply once the commit follows
+    Begin,
+    Commit,
+}
+
+// an entry as found in a log file, with the byte range it takes up there
+#[derive(Debug)]
+pub struct LogRecord<K, V>
+where
+    K: Clone + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Send + 'static,
+{
+    pub file_id: u32,
+    pub start: u64,
+    pub end: u64,
+    pub entry: Entry<K, V>,
 }
 
 #[derive(Clone, Debug)]
@@ -16,6 +48,66 @@ pub struct EntryOffset {
     pub file_id: u32,
     pub start: u64,
     pub end: u64,
+    // copied from the entry so that expiry can be checked without reading the log
+    pub expires_at: Option<u64>,
+    // copied from the entry so that versions can be compared without reading the log
+    pub version: u64,
+}
+
+impl EntryOffset {
+    pub fn is_expired(&self) -> bool {
+        is_expired(self.expires_at)
+    }
+
+    // whether both offsets point at the same entry in the log
+    pub fn same_location(&self, other: &EntryOffset) -> bool {
+        self.file_id == other.file_id && self.start == other.start
+    }
+}
+
+// the index value of a key. it is updated in place because replacing a skip list entry unlinks
+// the old node before linking the new one, which would make the key briefly invisible to
+// concurrent readers.
+pub struct IndexSlot {
+    offset: RwLock<EntryOffset>,
+    // taken from a process-wide counter when the slot is created, so slots created later have
+    // higher numbers and a removed and reinserted key gets a new one
+    seq: u64,
+}
+
+static NEXT_SLOT_SEQ: AtomicU64 = AtomicU64::new(0);
+
+impl IndexSlot {
+    pub fn new(offset: EntryOffset) -> IndexSlot {
+        IndexSlot{
+            offset: RwLock::new(offset),
+            seq: NEXT_SLOT_SEQ.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn get(&self) -> EntryOffset {
+        self.offset.read().unwrap().clone()
+    }
+
+    // points the slot at the given offset and returns the one it replaced
+    pub fn replace(&self, offset: EntryOffset) -> EntryOffset {
+        std::mem::replace(&mut *self.offset.write().unwrap(), offset)
+    }
+}
+
+// current unix time in millis
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+pub fn is_expired(expires_at: Option<u64>) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= now_millis())
 }
 
 impl<K, V> Entry<K, V>
@@ -23,10 +115,14 @@ where
     K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
     V: Clone + Serialize + DeserializeOwned + Send + 'static,
 {
-    pub fn init_set(key: K, val: V) -> Entry<K, V> {
+    // a set written now
+    pub fn init_set(key: K, val: V, expires_at: Option<u64>, version: u64) -> Entry<K, V> {
         Entry::Set{
             key,
             val,
+            expires_at,
+            version,
+            written_at: now_millis(),
         }
     }
 
@@ -36,3 +132,74 @@ where
         }
     }
 }
+
+// every log file starts with this header, files without it were written before entries were
+// framed and hold back-to-back encoded entries instead
+pub const LOG_HEADER: &[u8; 8] = b"KVSLOG01";
+
+// header of framed log files whose entry payloads are compressed with zstd
+pub const COMPRESSED_LOG_HEADER: &[u8; 8] = b"KVSLOGZ1";
+
+// header of files written by KvStore::export, followed by one framed Set entry per live key
+pub const EXPORT_HEADER: &[u8; 8] = b"KVSEXP01";
+
+// size of the length and checksum prefix in front of every framed entry
+pub const FRAME_HEADER_LEN: u64 = 8;
+
+#[derive(Debug)]
+pub enum Frame {
+    // a complete entry whose payload matches its checksum
+    Entry(Vec<u8>),
+    // an entry cut short by the end of the file, typically left behind by a torn write
+    Truncated,
+    // a complete entry whose payload does not match its checksum
+    Corrupt,
+}
+
+// prefixes the encoded entry with its length and CRC32 so that torn or corrupted writes can be
+// detected on replay
+pub fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut b = Vec::with_capacity(FRAME_HEADER_LEN as usize + payload.len());
+    b.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    b.extend_from_slice(&crc32fast::hash(payload).to_be_bytes());
+    b.extend_from_slice(payload);
+    b
+}
+
+// reads the next framed entry from the reader, returns None at a clean end of the stream
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Frame>> {
+    let mut header = [0; FRAME_HEADER_LEN as usize];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        n if n < header.len() => return Ok(Some(Frame::Truncated)),
+        _ => {},
+    }
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let checksum = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len as usize {
+        return Ok(Some(Frame::Truncated));
+    }
+    if crc32fast::hash(&payload) != checksum {
+        return Ok(Some(Frame::Corrupt));
+    }
+
+    Ok(Some(Frame::Entry(payload)))
+}
+
+// fills as much of the buffer as the reader allows and returns the number of bytes read
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(n)
+}
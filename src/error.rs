@@ -1,13 +1,19 @@
-use failure::Fail;
+// `failure`'s derive expands its impls inside an anonymous const
+#![allow(non_local_definitions)]
+
+use failure::{Backtrace, Fail};
 use std::{io, string::FromUtf8Error};
 
+// the errors converted from other crates' errors carry a backtrace of where the conversion
+// happened. it is only captured if RUST_BACKTRACE or RUST_FAILURE_BACKTRACE is set, and shows up
+// in the Debug output.
 #[derive(Debug, Fail)]
 pub enum Error {
     #[fail(display = "{}", _0)]
-    Io(#[cause] io::Error),
+    Io(#[cause] io::Error, Backtrace),
 
     #[fail(display = "{}", _0)]
-    Serde(#[cause] serde_json::Error),
+    Serde(#[cause] serde_json::Error, Backtrace),
 
     #[fail(display = "key: {} does not exist", key)]
     DoesNotExist {
@@ -18,33 +24,148 @@ pub enum Error {
     UnhandledError(String),
 
     #[fail(display = "sled error: {}", _0)]
-    Sled(#[cause] sled::Error),
+    Sled(#[cause] sled::Error, Backtrace),
 
     #[fail(display = "UTF-8 error: {}", _0)]
-    Utf8(#[cause] FromUtf8Error),
+    Utf8(#[cause] FromUtf8Error, Backtrace),
+
+    #[fail(display = "bincode error: {}", _0)]
+    Bincode(#[cause] bincode::Error, Backtrace),
+
+    // a request ran into the client's timeout or the server's request timeout. a write that
+    // timed out may still be applied afterwards.
+    #[fail(display = "operation timed out")]
+    Timeout,
+
+    #[fail(display = "authentication failed: {}", _0)]
+    AuthFailed(String),
+
+    #[fail(display = "incompatible peer: {}", _0)]
+    Incompatible(String),
+
+    #[fail(display = "protocol version mismatch: the client speaks version {}, the server {}", client, server)]
+    ProtocolVersion {
+        client: u32,
+        server: u32,
+    },
+
+    #[cfg(feature = "tls")]
+    #[fail(display = "tls error: {}", _0)]
+    Tls(#[cause] rustls::Error, Backtrace),
+
+    #[fail(display = "the store is opened read-only")]
+    ReadOnly,
+
+    #[fail(display = "{} is locked by another open store", _0)]
+    Locked(String),
+
+    #[fail(display = "compaction was cancelled")]
+    Cancelled,
+
+    #[fail(display = "log file {} is corrupt at offset {}", file_id, offset)]
+    CorruptLog {
+        file_id: u32,
+        offset: u64,
+    },
+
+    // failure only follows causes that are std errors, the wrapped error is reached with root
+    #[fail(display = "{}: {}", context, cause)]
+    Context {
+        context: String,
+        cause: Box<Error>,
+    },
+}
+
+impl Error {
+    // a catch-all error with the given message
+    pub fn unhandled(msg: impl Into<String>) -> Error {
+        Error::UnhandledError(msg.into())
+    }
+
+    // wraps the error with a note on what was being done when it happened, shown before it
+    pub fn with_context(self, context: impl Into<String>) -> Error {
+        Error::Context{context: context.into(), cause: Box::new(self)}
+    }
+
+    // where the error under every context was converted, if a backtrace was captured
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        Fail::backtrace(self.root()).filter(|backtrace| !backtrace.is_empty())
+    }
+
+    // the error under every context it was wrapped with
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Context{cause, ..} => cause.root(),
+            err => err,
+        }
+    }
+}
+
+// annotates the error of a result on its way up, e.g.
+// `reader.read_payload(start, end).context("reading log file 7")?`
+pub trait ResultExt<T> {
+    fn context(self, context: impl Into<String>) -> Result<T>;
+
+    // builds the context only if there is an error
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for std::result::Result<T, E> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|err| err.into().with_context(context))
+    }
+
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T> {
+        self.map_err(|err| err.into().with_context(context()))
+    }
+}
+
+impl From<String> for Error {
+    fn from(msg: String) -> Error {
+        Error::UnhandledError(msg)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(msg: &str) -> Error {
+        Error::UnhandledError(msg.to_owned())
+    }
 }
 
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
-        Error::Io(err)
+        Error::Io(err, Backtrace::new())
     }
 }
 
 impl From<serde_json::Error> for Error {
     fn from(err: serde_json::Error) -> Error {
-        Error::Serde(err)
+        Error::Serde(err, Backtrace::new())
     }
 }
 
 impl From<sled::Error> for Error {
     fn from(err: sled::Error) -> Error {
-        Error::Sled(err)
+        Error::Sled(err, Backtrace::new())
     }
 }
 
 impl From<FromUtf8Error> for Error {
     fn from(err: FromUtf8Error) -> Error {
-        Error::Utf8(err)
+        Error::Utf8(err, Backtrace::new())
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Error {
+        Error::Bincode(err, Backtrace::new())
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<rustls::Error> for Error {
+    fn from(err: rustls::Error) -> Error {
+        Error::Tls(err, Backtrace::new())
     }
 }
 
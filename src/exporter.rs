@@ -0,0 +1,126 @@
+use crate::{Endpoint, KvsEngine, MetricsSnapshot, Result, ServerHandle, StoreStats};
+use std::fmt::Debug;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::marker::PhantomData;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+use serde::{Serialize, de::DeserializeOwned};
+use log::{error, warn};
+
+// scrapes are answered one at a time, so a stalled scraper only holds the exporter this long
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+// a scrape is a request line and a few headers, anything beyond is not read
+const MAX_SCRAPE_REQUEST_BYTES: u64 = 8 * 1024;
+
+// serves the stats and counters of an engine over http, in the prometheus text format, at
+// /metrics. meant to run next to a KvsServer over a clone of its engine.
+pub struct MetricsExporter<K, V, E>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    E: KvsEngine<K, V>,
+{
+    engine: E,
+    handle: ServerHandle,
+    _phantom: PhantomData<(K, V)>,
+}
+
+impl<K, V, E> MetricsExporter<K, V, E>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    E: KvsEngine<K, V>,
+{
+    pub fn new(engine: E) -> Self {
+        MetricsExporter{engine, handle: ServerHandle::default(), _phantom: PhantomData}
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    pub fn run(self, addr: SocketAddr) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    // answers scrapes on the calling thread until the exporter is stopped through its handle
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        *self.handle.endpoint.lock().unwrap() = Some(Endpoint::Tcp(listener.local_addr()?));
+        if self.handle.is_stopped() {
+            return Ok(());
+        }
+
+        for stream in listener.incoming() {
+            if self.handle.is_stopped() {
+                break;
+            }
+            match stream {
+                Ok(stream) => if let Err(err) = self.answer(stream) {
+                    warn!("failed to answer a metrics scrape: {}", err);
+                },
+                Err(err) => error!("failed to accept metrics connection: {}", err),
+            }
+        }
+        Ok(())
+    }
+
+    // reads a single request and answers it, closing the connection afterwards
+    fn answer(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+        stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?.take(MAX_SCRAPE_REQUEST_BYTES));
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // the headers are of no interest, but a client may wait for them to be read
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+            header.clear();
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let path = parts.nth(1).map(|target| target.split('?').next().unwrap_or(target));
+        let (status, body) = match (request_line.starts_with("GET "), path) {
+            (true, Some("/metrics")) => match self.engine.stats() {
+                Ok(stats) => ("200 OK", render(&stats, &self.engine.metrics())),
+                Err(err) => ("500 Internal Server Error", format!("failed to collect the stats: {}\n", err)),
+            },
+            (true, _) => ("404 Not Found", "metrics are served at /metrics\n".to_owned()),
+            (false, _) => ("405 Method Not Allowed", "only GET is supported\n".to_owned()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status, body.len(), body,
+        )?;
+        stream.flush()?;
+
+        Ok(())
+    }
+}
+
+// formats the stats and counters in the prometheus text exposition format
+fn render(stats: &StoreStats, metrics: &MetricsSnapshot) -> String {
+    let mut out = String::new();
+    family(&mut out, "kvs_operations_total", "counter", "Operations served since the store was opened.", &[
+        ("{op=\"get\"}", metrics.gets),
+        ("{op=\"set\"}", metrics.sets),
+        ("{op=\"remove\"}", metrics.removes),
+    ]);
+    family(&mut out, "kvs_get_hits_total", "counter", "Gets that found their key.", &[("", metrics.hits)]);
+    family(&mut out, "kvs_get_misses_total", "counter", "Gets that did not find their key.", &[("", metrics.misses)]);
+    family(&mut out, "kvs_written_bytes_total", "counter", "Bytes appended to the log files by sets and removes.", &[("", metrics.bytes_written)]);
+    family(&mut out, "kvs_compactions_total", "counter", "Compactions that ran to completion.", &[("", metrics.compactions)]);
+    family(&mut out, "kvs_live_keys", "gauge", "Keys currently readable.", &[("", stats.live_keys)]);
+    family(&mut out, "kvs_uncompacted_bytes", "gauge", "Bytes of stale entries the next compaction would reclaim.", &[("", stats.uncompacted_bytes)]);
+    family(&mut out, "kvs_segments", "gauge", "Log files in the data directory.", &[("", stats.segments)]);
+    family(&mut out, "kvs_disk_bytes", "gauge", "Total size of the log files.", &[("", stats.disk_bytes)]);
+    out
+}
+
+// a metric family, every sample given as its labels, braces included, and its value
+fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, u64)]) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+    for (labels, value) in samples {
+        out.push_str(&format!("{}{} {}\n", name, labels, value));
+    }
+}
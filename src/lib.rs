@@ -1,13 +1,44 @@
-pub use error::{Error, Result};
-pub use client::KvsClient;
-pub use server::KvsServer;
-pub use engines::{KvsEngine, KvStore};
-pub use threadpool::ThreadPool;
+pub use error::{Error, Result, ResultExt};
+pub use client::{KvsClient, Pipeline, Watch};
+pub use client_pool::{KvsClientPool, PooledClient};
+pub use endpoint::Endpoint;
+#[cfg(feature = "tokio")]
+pub use async_client::AsyncKvsClient;
+#[cfg(feature = "tokio")]
+pub use async_server::AsyncKvsServer;
+pub use server::{KvsServer, ServerHandle, REQUEST_LOG_TARGET};
+pub use exporter::MetricsExporter;
+pub use resource::{PROTOCOL_VERSION, WATCH_HEARTBEAT};
+pub use engines::{Event, KvsEngine, KvStore, Metadata, Op, Snapshot, StoreStats};
+pub use entry::{Entry, LogRecord};
+pub use codec::{Codec, BincodeCodec, JsonCodec, WireCodec, WireFormat};
+pub use threadpool::{Job, ThreadPool};
+pub use options::{Compression, Durability, SegmentLayout, StoreOptions};
+pub use retry::{RetryingWriter, WriteRetry};
+pub use bloom::BloomFilter;
+pub use metrics::{AtomicMetrics, Metrics, MetricsSnapshot};
+// the tls types KvsClient and KvsServer take, at the version they were built against
+#[cfg(feature = "tls")]
+pub use rustls;
 
 mod error;
+mod codec;
 mod entry;
 mod resource;
 mod client;
+mod client_pool;
+mod endpoint;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tokio")]
+mod async_client;
+#[cfg(feature = "tokio")]
+mod async_server;
 mod server;
+mod exporter;
 mod engines;
 mod threadpool;
+mod options;
+mod retry;
+mod bloom;
+mod metrics;
@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// receives the events of a store as they happen, e.g. to forward them to a monitoring system.
+// called on the paths of every read and write, so implementations should be cheap and must not
+// block.
+pub trait Metrics: Send + Sync + Debug {
+    // a get of a key, hit telling whether the key was found
+    fn record_get(&self, hit: bool);
+    // a set of a key that appended the given number of bytes to the log
+    fn record_set(&self, bytes: u64);
+    // a removed key, with the size of its tombstone
+    fn record_remove(&self, bytes: u64);
+    // a compaction that ran to completion
+    fn record_compaction(&self);
+}
+
+// counts the events in memory, every store keeps one of these
+#[derive(Debug, Default)]
+pub struct AtomicMetrics {
+    gets: AtomicU64,
+    hits: AtomicU64,
+    sets: AtomicU64,
+    removes: AtomicU64,
+    bytes_written: AtomicU64,
+    compactions: AtomicU64,
+}
+
+// the counters of an AtomicMetrics at one point, every counter only grows while a store is
+// open and starts from zero when it is opened
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    pub gets: u64,
+    // gets that found their key, and those that did not
+    pub hits: u64,
+    pub misses: u64,
+    pub sets: u64,
+    pub removes: u64,
+    // bytes appended to the log files by sets and removes, compaction not included
+    pub bytes_written: u64,
+    pub compactions: u64,
+}
+
+impl AtomicMetrics {
+    // the counters are read one by one, so a snapshot taken under load may count an event in
+    // one counter and not yet in another
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let gets = self.gets.load(Ordering::Relaxed);
+        let hits = self.hits.load(Ordering::Relaxed);
+        MetricsSnapshot{
+            gets,
+            hits,
+            misses: gets.saturating_sub(hits),
+            sets: self.sets.load(Ordering::Relaxed),
+            removes: self.removes.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Metrics for AtomicMetrics {
+    fn record_get(&self, hit: bool) {
+        // hits first, so that a snapshot never sees more hits than gets
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        self.gets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_set(&self, bytes: u64) {
+        self.sets.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn record_remove(&self, bytes: u64) {
+        self.removes.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn record_compaction(&self) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::metrics::Metrics;
+use crate::retry::WriteRetry;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+// the capacity std gives BufReader and BufWriter
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+// tunables of a KvStore that are independent of its key, value and codec types
+#[derive(Clone, Debug)]
+pub struct StoreOptions {
+    // compresses the entries of files written by compaction, files written before the option
+    // was set or without it stay readable either way
+    pub compression: Option<Compression>,
+    // number of writers, each appending to its own active file, keys are spread over them by
+    // hash. writes to keys of different shards do not wait for each other. may be changed
+    // between opens, 0 counts as 1.
+    pub shards: usize,
+    // how far every write is pushed towards the disk before it returns
+    pub durability: Durability,
+    // retries the writes to the log files that fail with an error that may go away on its own,
+    // such as a full disk or a busy device, a few times by default. None fails them right away.
+    pub write_retry: Option<WriteRetry>,
+    // keeps a bloom filter of the keys of every log file, which lets contains_key and get turn
+    // away most missing keys before looking at the index. every write then hashes its key once
+    // more, and lookups only get faster with an index that is slow to search.
+    pub bloom_filters: bool,
+    // once the store holds more keys than this, the keys inserted first are removed until it
+    // does not anymore. overwriting a key keeps its place, removing and setting it again does not.
+    pub max_keys: Option<usize>,
+    // same as max_keys, for the total size of the live entries in the log files. the files
+    // themselves take more until compaction drops the stale entries and the tombstones.
+    pub max_disk_bytes: Option<u64>,
+    // number of log files every handle keeps open for reading, the least recently read one is
+    // closed to open another. the active files stay open regardless. unbounded by default, which
+    // may run out of file descriptors on stores of many files read through many handles.
+    pub max_open_readers: Option<usize>,
+    // moves a writer to a new log file once its active one has grown to this many bytes, which
+    // keeps the files written between compactions bounded. the entry that crosses the limit
+    // still goes into the old file, and so does a whole batch. unbounded by default, a file
+    // then grows until the next compaction.
+    pub segment_max_bytes: Option<u64>,
+    // where the log files go in the directory. may be changed between opens, a writable open
+    // moves the files it finds elsewhere into place.
+    pub segment_layout: SegmentLayout,
+    // capacity of the buffers every log file is read and written through, 8 KiB by default.
+    // larger buffers save syscalls on stores of large values, at that much memory per open file.
+    pub buffer_size: usize,
+    // serves reads only, every write fails with Error::ReadOnly. opening neither creates the
+    // directory nor an active log file, so inspecting a store leaves it as it was. limits are
+    // not enforced, since evicting is writing.
+    pub read_only: bool,
+    // receives every operation the store counts, on top of the counters KvStore::metrics
+    // returns. shared by all handles of the store, and by every store opened with these options.
+    pub metrics: Option<Arc<dyn Metrics>>,
+    // runs a thread that writes tombstones for the expired keys this often, so that they leave
+    // the index and compaction reclaims their entries without waiting for the keys to be written
+    // again. off by default, expired keys then stay in the index until the next compaction.
+    pub ttl_sweep_interval: Option<Duration>,
+    // compacts right away if opening finds more stale bytes than a write would let pile up before
+    // compacting, e.g. after a crash left many overwritten entries behind. the open returns once
+    // the compaction is done. off by default, and never done by a read-only store.
+    pub compact_on_open: bool,
+}
+
+impl Default for StoreOptions {
+    fn default() -> StoreOptions {
+        StoreOptions{
+            compression: None,
+            shards: 1,
+            durability: Durability::Flush,
+            write_retry: Some(WriteRetry::default()),
+            bloom_filters: false,
+            max_keys: None,
+            max_disk_bytes: None,
+            max_open_readers: None,
+            segment_max_bytes: None,
+            segment_layout: SegmentLayout::Flat,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            read_only: false,
+            metrics: None,
+            ttl_sweep_interval: None,
+            compact_on_open: false,
+        }
+    }
+}
+
+// how the log files are laid out in the store directory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SegmentLayout {
+    // every file right in the directory, as {file_id}.log
+    #[default]
+    Flat,
+    // files spread over this many subdirectories, as {file_id % n}/{file_id}.log, which keeps
+    // directories small enough to list quickly on stores of many thousands of files. 0 counts
+    // as 1.
+    FanOut(u32),
+}
+
+impl SegmentLayout {
+    // path of the log file with the given id in the store directory
+    pub fn file_name(&self, dir: &Path, file_id: u32) -> PathBuf {
+        match *self {
+            SegmentLayout::Flat => dir.join(format!("{}.log", file_id)),
+            SegmentLayout::FanOut(n) => dir.join((file_id % n.max(1)).to_string()).join(format!("{}.log", file_id)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Compression {
+    // zstd at the given level, 0 meaning zstd's default
+    Zstd(i32),
+}
+
+// how far a write has made it before it is acknowledged. every level hands each entry to the os
+// before it becomes visible, since readers go through their own file handles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    // entries are handed to the os, nothing is ever synced. a crash of the process loses
+    // nothing, a crash of the machine may lose recent writes and the output of a compaction
+    // together with the files it replaced.
+    None,
+    // same as None, but the output of a compaction is synced before the files it replaces are
+    // deleted, so a crash of the machine only loses recent writes
+    Flush,
+    // every write, batch and new log file is synced before it returns, so no acknowledged write
+    // is lost. each write waits for the disk, which costs from tens of microseconds on fast ssds
+    // to several milliseconds on spinning disks.
+    Fsync,
+    // same guarantee as Fsync, but concurrent writes are synced together. a write waits for up
+    // to window, or until max_writes writes are pending, then a single sync covers all of them.
+    // raises throughput under many concurrent writers, at the cost of up to window of latency
+    // for every write.
+    GroupCommit{window: Duration, max_writes: usize},
+}
+
+impl Durability {
+    // whether every acknowledged write is on disk
+    pub fn syncs_writes(&self) -> bool {
+        matches!(self, Durability::Fsync | Durability::GroupCommit{..})
+    }
+}
@@ -1,5 +1,10 @@
-use serde::{Serialize, Deserialize};
+use crate::{Error, Event, Result, StoreStats};
+use crate::codec::{WireCodec, WireFormat};
+use crate::entry::read_full;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
 use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request<K, V> 
@@ -10,6 +15,40 @@ where
     Get {key: K},
     Set {key: K, val: V},
     Rm {key: K},
+    GetMany {keys: Vec<K>},
+    // answered with Exists, which tells whether the key is live without sending its value
+    Exists {key: K},
+    Compact,
+    Stats,
+    // answered with Ack once the server watches the key, then with an Event for every change of
+    // it, for as long as the connection stays open. the connection serves nothing else
+    // afterwards. an Ack is sent again after every WATCH_HEARTBEAT without a change, which is
+    // how the server notices a client that has gone away.
+    Watch {key: K},
+    // switches the connection to another of the server's databases, the requests that follow
+    // are served by it. every connection starts on database 0. answered with Ack, or with an
+    // error if the server has no such database.
+    Select {db: u32},
+    // answered with Ack without touching the engine, to check the server is alive
+    Ping,
+    // proves the client knows the server's secret, must come first on a connection to a server
+    // that has one. answered with Ack, or with AuthFailed before the server hangs up.
+    Auth {token: String},
+    // sent by clients first on every connection, with the protocol version they speak, the
+    // types they encode keys and values as and the wire codec they want. answered with Ack, with
+    // ProtocolVersion if the server speaks another version, or with Incompatible if it stores
+    // other types or does not accept the codec. a hello without a version comes from a client
+    // older than versioning and counts as version 0. the hello and its answer are in the codec
+    // the connection used so far, json for the first one, everything after an Ack in the codec
+    // asked for.
+    Hello {
+        #[serde(default)]
+        version: u32,
+        key_type: String,
+        value_type: String,
+        #[serde(default)]
+        codec: WireFormat,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -17,6 +56,198 @@ pub enum Response<V>
 where
     V: Clone + Send + 'static,
 {
-    Ok(Option<V>),
-    Err(String),
+    // the value of a get, None if the key does not exist
+    Value(Option<V>),
+    // a request that returns nothing has succeeded
+    Ack,
+    // one value per requested key, in request order
+    Many(Vec<Option<V>>),
+    Exists(bool),
+    Stats(StoreStats),
+    // a change of the key of a Watch
+    Event(Event<V>),
+    Err(RemoteError),
+}
+
+// an error as reported by the server, keeping apart the cases a client may want to handle
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RemoteError {
+    DoesNotExist {key: String},
+    AuthFailed(String),
+    Incompatible(String),
+    ProtocolVersion {client: u32, server: u32},
+    // the engine did not answer within the server's request timeout, or every helper that
+    // serves engine calls was busy. a write that timed out may still be applied later.
+    Timeout,
+    Other(String),
+}
+
+impl From<Error> for RemoteError {
+    fn from(err: Error) -> RemoteError {
+        match err {
+            Error::DoesNotExist{key} => RemoteError::DoesNotExist{key},
+            Error::AuthFailed(msg) => RemoteError::AuthFailed(msg),
+            Error::Incompatible(msg) => RemoteError::Incompatible(msg),
+            Error::ProtocolVersion{client, server} => RemoteError::ProtocolVersion{client, server},
+            Error::Timeout => RemoteError::Timeout,
+            err => RemoteError::Other(err.to_string()),
+        }
+    }
+}
+
+impl From<RemoteError> for Error {
+    fn from(err: RemoteError) -> Error {
+        match err {
+            RemoteError::DoesNotExist{key} => Error::DoesNotExist{key},
+            RemoteError::AuthFailed(msg) => Error::AuthFailed(msg),
+            RemoteError::Incompatible(msg) => Error::Incompatible(msg),
+            RemoteError::ProtocolVersion{client, server} => Error::ProtocolVersion{client, server},
+            RemoteError::Timeout => Error::Timeout,
+            RemoteError::Other(msg) => Error::UnhandledError(msg),
+        }
+    }
+}
+
+// longest time a watching connection goes without a message
+pub const WATCH_HEARTBEAT: Duration = Duration::from_secs(5);
+
+// version of the requests and responses on the wire, raised with every change to them that a
+// peer speaking the previous version would misread. the Hello request keeps its shape across
+// versions, so that a mismatch is always reported as such.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+// the Hello a peer storing K keys and V values and asking for the codec sends. type names are
+// not guaranteed to be stable across compiler versions, a mismatch then fails connections that
+// would have worked rather than the other way around.
+pub fn hello<K, V>(codec: WireFormat) -> Request<K, V>
+where
+    K: Clone + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Send + 'static,
+{
+    Request::Hello{
+        version: PROTOCOL_VERSION,
+        key_type: std::any::type_name::<K>().to_owned(),
+        value_type: std::any::type_name::<V>().to_owned(),
+        codec,
+    }
+}
+
+// size of the big-endian length that precedes every message on the wire
+const MESSAGE_HEADER_LEN: usize = 4;
+
+// writes the message in the codec prefixed with its length and flushes the writer
+pub fn write_message<T: Serialize, W: Write>(codec: &impl WireCodec, writer: &mut W, message: &T) -> Result<()> {
+    write_message_unflushed(codec, writer, message)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+// same as write_message without the flush, so that several messages can go out together
+pub fn write_message_unflushed<T: Serialize, W: Write>(codec: &impl WireCodec, writer: &mut W, message: &T) -> Result<()> {
+    let payload = codec.encode_message(message)?;
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too large"))?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(&payload)?;
+
+    Ok(())
+}
+
+// reads the next length-prefixed message, returns None if the peer closed the connection
+// between two messages
+pub fn read_message<T: DeserializeOwned, R: Read>(codec: &impl WireCodec, reader: &mut R) -> Result<Option<T>> {
+    match read_message_len(reader)? {
+        Some(len) => read_message_payload(codec, reader, len).map(Some),
+        None => Ok(None),
+    }
+}
+
+// reads the length prefix of the next message, so that the caller can check it before reading
+// the message itself with read_message_payload
+pub fn read_message_len<R: Read>(reader: &mut R) -> Result<Option<u32>> {
+    let mut header = [0; MESSAGE_HEADER_LEN];
+    match read_full(reader, &mut header)? {
+        0 => Ok(None),
+        n if n < MESSAGE_HEADER_LEN => Err(truncated_message()),
+        _ => Ok(Some(u32::from_be_bytes(header))),
+    }
+}
+
+pub fn read_message_payload<T: DeserializeOwned, R: Read>(codec: &impl WireCodec, reader: &mut R, len: u32) -> Result<T> {
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len as usize {
+        return Err(truncated_message());
+    }
+
+    codec.decode_message(&payload)
+}
+
+// same as write_message, on an async writer. the message is serialized before the returned
+// future is first polled, so the future does not borrow it.
+#[cfg(feature = "tokio")]
+pub fn write_message_async<'a, T, W>(codec: &impl WireCodec, writer: &'a mut W, message: &T) -> impl std::future::Future<Output = Result<()>> + 'a
+where
+    T: Serialize,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::AsyncWriteExt;
+
+    let payload = codec.encode_message(message);
+    async move {
+        let payload = payload?;
+        let len = u32::try_from(payload.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too large"))?;
+        writer.write_all(&len.to_be_bytes()).await?;
+        writer.write_all(&payload).await?;
+        writer.flush().await?;
+
+        Ok(())
+    }
+}
+
+// same as read_message_len, on an async reader
+#[cfg(feature = "tokio")]
+pub async fn read_message_len_async<R>(reader: &mut R) -> Result<Option<u32>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+
+    let mut header = [0; MESSAGE_HEADER_LEN];
+    let mut n = 0;
+    while n < MESSAGE_HEADER_LEN {
+        match reader.read(&mut header[n..]).await? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    match n {
+        0 => Ok(None),
+        n if n < MESSAGE_HEADER_LEN => Err(truncated_message()),
+        _ => Ok(Some(u32::from_be_bytes(header))),
+    }
+}
+
+// same as read_message_payload, on an async reader
+#[cfg(feature = "tokio")]
+pub async fn read_message_payload_async<T, R>(codec: &impl WireCodec, reader: &mut R, len: u32) -> Result<T>
+where
+    T: DeserializeOwned,
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload).await?;
+    if payload.len() < len as usize {
+        return Err(truncated_message());
+    }
+
+    codec.decode_message(&payload)
+}
+
+fn truncated_message() -> crate::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in the middle of a message").into()
 }
@@ -0,0 +1,86 @@
+use log::warn;
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+
+// how often and how patiently a write to a log file is retried after an error that may go away
+// on its own
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteRetry {
+    pub max_retries: u32,
+    // waited before the first retry, twice as long before each following one
+    pub backoff: Duration,
+}
+
+impl WriteRetry {
+    fn delay(&self, attempt: u32) -> Duration {
+        self.backoff.saturating_mul(1 << attempt.min(16))
+    }
+}
+
+impl Default for WriteRetry {
+    fn default() -> WriteRetry {
+        WriteRetry{max_retries: 3, backoff: Duration::from_millis(10)}
+    }
+}
+
+// retries the writes and flushes of the wrapped writer that fail with a transient error, up to
+// the policy's number of times. meant to sit under a BufWriter: a failed write wrote nothing,
+// so retrying it never writes a byte twice, and the BufWriter keeps track of what did go
+// through. any other error, or the last one, is returned as is.
+pub struct RetryingWriter<W: Write> {
+    inner: W,
+    retry: Option<WriteRetry>,
+}
+
+impl<W: Write> RetryingWriter<W> {
+    // without a policy, every error is returned right away
+    pub fn new(inner: W, retry: Option<WriteRetry>) -> RetryingWriter<W> {
+        RetryingWriter{inner, retry}
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    fn retried<T>(&mut self, mut op: impl FnMut(&mut W) -> io::Result<T>) -> io::Result<T> {
+        let mut attempt = 0;
+        loop {
+            match op(&mut self.inner) {
+                Err(err) if is_transient(&err) => match self.retry {
+                    Some(retry) if attempt < retry.max_retries => {
+                        let delay = retry.delay(attempt);
+                        warn!("write to a log file failed, retrying in {:?}: {}", delay, err);
+                        thread::sleep(delay);
+                        attempt += 1;
+                    },
+                    _ => return Err(err),
+                },
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for RetryingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.retried(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.retried(|inner| inner.flush())
+    }
+}
+
+// errors a write may not run into again a moment later. interrupted writes are already retried
+// by write_all and BufWriter. syncs are never retried, a failed sync may have dropped the
+// pages it was meant to write.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::StorageFull
+    )
+}
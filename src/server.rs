@@ -1,11 +1,27 @@
-use crate::{Result, KvsEngine, ThreadPool};
-use crate::resource::{Request, Response};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::io::{Write, BufReader};
-use serde_json::Deserializer;
+use crate::{Endpoint, Error, Result, KvsEngine, ThreadPool};
+use crate::codec::WireFormat;
+use crate::endpoint::Stream;
+use crate::resource::{read_message_len, read_message_payload, write_message, RemoteError, Request, Response, PROTOCOL_VERSION, WATCH_HEARTBEAT};
+use std::net::TcpListener;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::fs;
 use serde::{Serialize, de::DeserializeOwned};
 use std::fmt::Debug;
+use log::{error, info, log_enabled, Level};
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use std::panic::{self, AssertUnwindSafe};
+use std::any::type_name;
+use std::collections::{BTreeMap, HashMap};
 
 pub struct KvsServer<K, V, E: KvsEngine<K, V>>
 where
@@ -13,11 +29,103 @@ where
     V: Clone + Serialize + DeserializeOwned + Send + 'static,
     E: KvsEngine<K, V>,
 {
-    engine: E,
+    // the engines of the databases a connection can select, by number. database 0 always exists.
+    databases: BTreeMap<u32, E>,
     pool: ThreadPool,
+    handle: ServerHandle,
+    connection: ConnectionOptions,
+    // serve the engine calls of every connection once a request timeout is set
+    helpers: Option<Arc<RequestHelpers<K, V, E>>>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
     _phantom: PhantomData<(K, V)>,
 }
 
+// what every connection is held to, cloned into each of them
+#[derive(Clone)]
+struct ConnectionOptions {
+    max_request_bytes: u32,
+    auth_token: Option<Arc<str>>,
+    // the codecs a client may switch the connection to in its hello
+    codecs: Arc<[WireFormat]>,
+    // the flag of the server's handle, checked by connections that would otherwise never end
+    shutdown: Arc<AtomicBool>,
+}
+
+// every request served is logged at info under this target, with the time the engine took
+pub const REQUEST_LOG_TARGET: &str = "kvs::requests";
+
+// requests larger than this are rejected unless configured otherwise
+pub(crate) const DEFAULT_MAX_REQUEST_BYTES: u32 = 64 * 1024 * 1024;
+
+// how often a watching connection checks whether the server is stopping
+const WATCH_POLL: Duration = Duration::from_millis(100);
+
+// allows stopping a running server from another thread
+#[derive(Clone, Default)]
+pub struct ServerHandle {
+    shutdown: Arc<AtomicBool>,
+    pub(crate) endpoint: Arc<Mutex<Option<Endpoint>>>,
+    connections: Arc<Mutex<Connections>>,
+}
+
+// a handle of every accepted stream whose connection has not ended yet, by a number of its own
+#[derive(Default)]
+struct Connections {
+    next_id: u64,
+    streams: HashMap<u64, Stream>,
+}
+
+// forgets the stream of a connection once it ends
+struct Tracked {
+    connections: Arc<Mutex<Connections>>,
+    id: u64,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.connections.lock().unwrap().streams.remove(&self.id);
+    }
+}
+
+impl ServerHandle {
+    // makes the accept loop exit and the open connections stop reading. a connection finishes
+    // the request it is serving, if any, and ends at its next read. the server waits for them
+    // before returning from run.
+    pub fn stop(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // wake up the pending accept, if the server is not listening yet it sees the flag as
+        // soon as it binds
+        if let Some(endpoint) = &*self.endpoint.lock().unwrap() {
+            let _ = Stream::connect(endpoint, None);
+        }
+        // idle connections are blocked reading their next request, without this they would
+        // hold up the server until their clients disconnect
+        for stream in self.connections.lock().unwrap().streams.values() {
+            let _ = stream.shutdown_read();
+        }
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    // keeps a handle of the stream until the returned guard is dropped, for stop to shut down.
+    // the flag is checked under the lock stop takes after setting it, so a stream accepted
+    // while stopping is either shut down by stop or right here.
+    fn track(&self, stream: &Stream) -> io::Result<Tracked> {
+        let stream = stream.try_clone()?;
+        let mut connections = self.connections.lock().unwrap();
+        if self.is_stopped() {
+            stream.shutdown_read()?;
+        }
+        let id = connections.next_id;
+        connections.next_id += 1;
+        connections.streams.insert(id, stream);
+        Ok(Tracked{connections: Arc::clone(&self.connections), id})
+    }
+}
+
 impl<K, V, E> KvsServer<K, V, E>
 where
     K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
@@ -25,67 +133,508 @@ where
     E: KvsEngine<K, V>,
 {
     pub fn new(engine: E, pool: ThreadPool) -> Self {
+        let handle = ServerHandle::default();
         KvsServer {
-            engine,
+            databases: BTreeMap::from([(0, engine)]),
             pool,
+            connection: ConnectionOptions{
+                max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+                auth_token: None,
+                codecs: Arc::from(WireFormat::ALL),
+                shutdown: Arc::clone(&handle.shutdown),
+            },
+            handle,
+            helpers: None,
+            #[cfg(feature = "tls")]
+            tls: None,
             _phantom: PhantomData,
         }
     }
 
-    pub fn run(&self, addr: SocketAddr) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            let stream = stream.unwrap();
-            let engine = self.engine.clone();
+    // serves the engine as database db, which connections switch to with Request::Select.
+    // database 0 is the engine the server was built with, giving it again replaces it.
+    pub fn with_database(mut self, db: u32, engine: E) -> Self {
+        self.databases.insert(db, engine);
+        self
+    }
+
+    // caps the size of a single request, a client sending a larger one gets an error response
+    // and is disconnected before the request is read
+    pub fn with_max_request_bytes(mut self, max_request_bytes: u32) -> Self {
+        self.connection.max_request_bytes = max_request_bytes;
+        self
+    }
+
+    // makes every connection start with Request::Auth carrying this token, which
+    // KvsClient::authenticate sends. a connection whose first request is anything else, or a
+    // wrong token, gets an error response and is closed. the token travels as is, so it only
+    // stays secret over tls or a trusted network.
+    pub fn with_auth_token(mut self, token: &str) -> Self {
+        self.connection.auth_token = Some(Arc::from(token));
+        self
+    }
+
+    // limits the wire codecs clients may ask for in their hello, a client asking for another
+    // one fails to connect with Error::Incompatible. every codec is accepted by default, and the
+    // hello itself is always read as json.
+    pub fn with_wire_codecs(mut self, codecs: &[WireFormat]) -> Self {
+        self.connection.codecs = Arc::from(codecs);
+        self
+    }
+
+    // gives the engine this long to serve every request that calls it. the calls then run on as
+    // many helper threads as the pool has workers. a request that takes longer is answered with
+    // Error::Timeout and its connection is closed, which frees the worker for other connections.
+    // the call cannot be interrupted and keeps its helper until it is done, so a write that
+    // timed out may still be applied afterwards. waiting for a free helper counts against the
+    // timeout, a request that finds none in time is answered with Error::Timeout as well but its
+    // connection stays open.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.helpers = Some(Arc::new(RequestHelpers::spawn(self.pool.size(), timeout)));
+        self
+    }
+
+    // encrypts every accepted connection with tls, presenting the given certificate chain, leaf
+    // first. clients then have to connect with KvsClient::connect_tls.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(
+        mut self,
+        cert_chain: Vec<rustls::pki_types::CertificateDer<'static>>,
+        key: rustls::pki_types::PrivateKeyDer<'static>,
+    ) -> Result<Self> {
+        self.tls = Some(crate::tls::server_config(cert_chain, key)?);
+        Ok(self)
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    // listens on tcp when given a SocketAddr, or on any Endpoint. a unix socket file is removed
+    // again once the server stops.
+    pub fn run(self, endpoint: impl Into<Endpoint>) -> Result<()> {
+        match endpoint.into() {
+            Endpoint::Tcp(addr) => self.serve(TcpListener::bind(addr)?),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                remove_stale_socket(&path)?;
+                let res = self.serve_unix(UnixListener::bind(&path)?);
+                let _ = fs::remove_file(&path);
+                res
+            },
+        }
+    }
+
+    // accepts connections on the given listener until the server is stopped through its handle
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        let endpoint = Endpoint::Tcp(listener.local_addr()?);
+        self.serve_incoming(endpoint, listener.incoming().map(|stream| stream.map(Stream::Tcp)))
+    }
+
+    // same as serve, for a unix socket listener bound to a path
+    #[cfg(unix)]
+    pub fn serve_unix(self, listener: UnixListener) -> Result<()> {
+        let path = listener.local_addr()?.as_pathname().map(|path| path.to_path_buf()).ok_or_else(|| {
+            Error::UnhandledError("the unix socket listener is not bound to a path".to_owned())
+        })?;
+        self.serve_incoming(Endpoint::Unix(path), listener.incoming().map(|stream| stream.map(Stream::Unix)))
+    }
+
+    // serves a single connection given as its two halves on the calling thread, until the reader
+    // is exhausted. lets the server run over transports it does not know about, like tls streams
+    // or in-memory pipes.
+    pub fn serve_connection<R: Read, W: Write>(&self, reader: R, writer: W) -> Result<()> {
+        handle_client::<K, V, E, R, W>(self.databases.clone(), reader, writer, &self.connection, self.helpers.as_deref())
+    }
+
+    fn serve_incoming(self, endpoint: Endpoint, incoming: impl Iterator<Item = io::Result<Stream>>) -> Result<()> {
+        *self.handle.endpoint.lock().unwrap() = Some(endpoint);
+        if self.handle.is_stopped() {
+            return Ok(());
+        }
+
+        for stream in incoming {
+            if self.handle.is_stopped() {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("failed to accept connection: {}", err);
+                    continue;
+                },
+            };
+            let tracked = match self.handle.track(&stream) {
+                Ok(tracked) => tracked,
+                Err(err) => {
+                    error!("failed to set up connection from {}: {}", stream.peer(), err);
+                    continue;
+                },
+            };
+            let databases = self.databases.clone();
+            let connection = self.connection.clone();
+            let helpers = self.helpers.clone();
+            #[cfg(feature = "tls")]
+            let tls = self.tls.clone();
             self.pool.execute(move || {
-               handle_client::<K, V, E>(engine, stream).unwrap();
+                let peer = stream.peer();
+                let res = serve_stream::<K, V, E>(
+                    databases,
+                    stream,
+                    &connection,
+                    helpers.as_deref(),
+                    #[cfg(feature = "tls")]
+                    tls,
+                );
+                if let Err(err) = res {
+                    error!("error while serving {}: {}", peer, err);
+                }
+                drop(tracked);
             });
         }
         Ok(())
     }
 }
 
-fn handle_client<K, V, E>(engine: E, stream: TcpStream) -> Result<()>
+// serves an accepted connection on the calling thread, setting up tls first if configured. the
+// handshake happens here rather than in the accept loop, so a slow client only holds a worker.
+fn serve_stream<K, V, E>(
+    databases: BTreeMap<u32, E>,
+    stream: Stream,
+    connection: &ConnectionOptions,
+    helpers: Option<&RequestHelpers<K, V, E>>,
+    #[cfg(feature = "tls")] tls: Option<Arc<rustls::ServerConfig>>,
+) -> Result<()>
 where
     K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
     V: Clone + Serialize + DeserializeOwned + Send + 'static,
     E: KvsEngine<K, V>,
 {
-    let reader = BufReader::new(stream.try_clone()?);
-    let mut writer = stream;
-    let request_reader = Deserializer::from_reader(reader).into_iter::<Request<K, V>>();
-
-    for req in request_reader {
-        let req = req?;
-        match req {
-            Request::Get{key} => {
-                let resp: Response<V> = match engine.get(key) {
-                    Ok(val) => Response::<V>::Ok(val),
-                    Err(err) => Response::<V>::Err(err.to_string()),
-                };
-                let b = serde_json::to_string(&resp).unwrap();
-                writer.write_all(b.as_bytes())?;
-                writer.flush()?;
+    #[cfg(feature = "tls")]
+    let stream = match tls {
+        Some(config) => crate::tls::accept(&config, stream)?,
+        None => stream,
+    };
+    let reader = stream.try_clone()?;
+    handle_client::<K, V, E, _, _>(databases, reader, stream, connection, helpers)
+}
+
+// serves requests read from one half of a connection, answering on the other, until the reader
+// is exhausted. the halves may be anything from two handles of a socket to in-memory buffers.
+// each connection has its own clones of the engines, so that they keep their open files.
+fn handle_client<K, V, E, R, W>(
+    mut databases: BTreeMap<u32, E>,
+    reader: R,
+    writer: W,
+    connection: &ConnectionOptions,
+    helpers: Option<&RequestHelpers<K, V, E>>,
+) -> Result<()>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    E: KvsEngine<K, V>,
+    R: Read,
+    W: Write,
+{
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    let max_request_bytes = connection.max_request_bytes;
+    let mut authenticated = connection.auth_token.is_none();
+    let mut db = 0;
+    let mut codec = WireFormat::Json;
+    while let Some(len) = read_message_len(&mut reader)? {
+        if len > max_request_bytes {
+            let msg = format!("request of {} bytes exceeds the limit of {} bytes", len, max_request_bytes);
+            write_message(&codec, &mut writer, &Response::<V>::Err(RemoteError::Other(msg.clone())))?;
+            return Err(Error::UnhandledError(msg));
+        }
+
+        let req = read_message_payload::<Request<K, V>, _>(&codec, &mut reader, len)?;
+        // the handshake comes before authentication, it reveals nothing but the types
+        if !authenticated && !matches!(req, Request::Hello{..}) {
+            let msg = match (&req, &connection.auth_token) {
+                (Request::Auth{token}, Some(expected)) if tokens_match(token, expected) => None,
+                (Request::Auth{..}, _) => Some("invalid token"),
+                _ => Some("the server requires an auth token first"),
+            };
+            if let Some(msg) = msg {
+                write_message(&codec, &mut writer, &Response::<V>::Err(RemoteError::AuthFailed(msg.to_owned())))?;
+                return Err(Error::AuthFailed(msg.to_owned()));
+            }
+            authenticated = true;
+        }
+        let requested = match &req {
+            Request::Hello{codec, ..} => Some(*codec),
+            _ => None,
+        };
+        let resp = match req {
+            Request::Watch{key} => return serve_watch::<K, V, E, _>(&databases[&db], key, codec, &mut writer, &connection.shutdown),
+            Request::Select{db: selected} => {
+                let resp = select::<K, V, E>(&databases, selected);
+                if matches!(resp, Response::Ack) {
+                    db = selected;
+                }
+                resp
             },
-            Request::Set{key, val} => {
-                let resp: Response<V> = match engine.set(key, val) {
-                    Ok(()) => Response::<V>::Ok(None),
-                    Err(err) => Response::<V>::Err(err.to_string()),
-                };
-                let b = serde_json::to_string(&resp).unwrap();
-                writer.write_all(b.as_bytes())?;
-                writer.flush()?;
+            Request::Hello{codec: requested, ..} if !connection.codecs.contains(&requested) => {
+                Response::<V>::Err(codec_refused(requested))
             },
-            Request::Rm{key} => {
-                let resp: Response<V> = match engine.remove(key.clone()) {
-                    Ok(_) => Response::<V>::Ok(None),
-                    Err(err) => Response::<V>::Err(err.to_string()),
-                };
-                let b = serde_json::to_string(&resp).unwrap();
-                writer.write_all(b.as_bytes())?;
-                writer.flush()?;
+            // the requests the connection answers without calling the engine
+            req @ (Request::Ping | Request::Auth{..} | Request::Hello{..}) => handle_request(&databases[&db], req),
+            req => match helpers {
+                Some(helpers) => {
+                    let engine = databases.remove(&db).unwrap();
+                    match helpers.call(engine, req) {
+                        HelperCall::Served(engine, resp) => {
+                            databases.insert(db, engine);
+                            resp
+                        },
+                        HelperCall::Busy(engine) => {
+                            databases.insert(db, engine);
+                            Response::<V>::Err(RemoteError::Timeout)
+                        },
+                        // the engine stays with the helper until the call is done
+                        HelperCall::TimedOut => {
+                            write_message(&codec, &mut writer, &Response::<V>::Err(RemoteError::Timeout))?;
+                            return Err(Error::Timeout);
+                        },
+                        HelperCall::Panicked => {
+                            return Err(Error::UnhandledError("the engine panicked serving a request".to_owned()));
+                        },
+                    }
+                },
+                None => handle_request(&databases[&db], req),
             },
+        };
+        write_message(&codec, &mut writer, &resp)?;
+        if let (Some(requested), Response::Ack) = (requested, &resp) {
+            codec = requested;
         }
     }
     Ok(())
 }
+
+// the threads that serve engine calls under a request timeout, shared by every connection of a
+// server. a call is only handed to a helper that is waiting for one, so a slow engine ties up
+// at most this many threads however many requests time out. a helper exits once the server and
+// its connections are gone and it has finished its call.
+struct RequestHelpers<K, V, E>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    E: KvsEngine<K, V>,
+{
+    timeout: Duration,
+    // a rendezvous channel, a call is only sent once a helper receives it
+    calls: Sender<Call<K, V, E>>,
+}
+
+// a request handed to a helper with the engine to serve it and where to send both back. the
+// engine comes back with the response, so that the connection keeps its open files.
+type Call<K, V, E> = (E, Request<K, V>, Sender<(E, Response<V>)>);
+
+enum HelperCall<V: Clone + Send + 'static, E> {
+    Served(E, Response<V>),
+    // no helper was free before the timeout ran out, the request was not served
+    Busy(E),
+    TimedOut,
+    Panicked,
+}
+
+impl<K, V, E> RequestHelpers<K, V, E>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    E: KvsEngine<K, V>,
+{
+    fn spawn(count: usize, timeout: Duration) -> RequestHelpers<K, V, E> {
+        let (calls, receiver) = crossbeam_channel::bounded::<Call<K, V, E>>(0);
+        for _ in 0..count.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new().name("kvs-request".to_owned()).spawn(move || {
+                for (engine, req, reply) in receiver {
+                    // a panicking call drops the reply sender, which the connection sees
+                    if let Ok(resp) = panic::catch_unwind(AssertUnwindSafe(|| handle_request(&engine, req))) {
+                        let _ = reply.send((engine, resp));
+                    }
+                }
+            }).unwrap();
+        }
+        RequestHelpers{timeout, calls}
+    }
+
+    // waiting for a helper to take the call counts against the timeout, so a request is only
+    // given up on once the whole timeout has passed
+    fn call(&self, engine: E, req: Request<K, V>) -> HelperCall<V, E> {
+        let deadline = Instant::now() + self.timeout;
+        let (reply, receiver) = crossbeam_channel::bounded(1);
+        if let Err(err) = self.calls.send_timeout((engine, req, reply), self.timeout) {
+            let (engine, _, _) = err.into_inner();
+            return HelperCall::Busy(engine);
+        }
+        match receiver.recv_deadline(deadline) {
+            Ok((engine, resp)) => HelperCall::Served(engine, resp),
+            Err(RecvTimeoutError::Timeout) => HelperCall::TimedOut,
+            Err(RecvTimeoutError::Disconnected) => HelperCall::Panicked,
+        }
+    }
+}
+
+fn codec_refused(codec: WireFormat) -> RemoteError {
+    RemoteError::Incompatible(format!("the server does not accept the {} wire codec", codec))
+}
+
+// streams the changes of the key until writing to the client fails or the server stops, see
+// Request::Watch
+fn serve_watch<K, V, E, W>(engine: &E, key: K, codec: WireFormat, writer: &mut W, shutdown: &AtomicBool) -> Result<()>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    E: KvsEngine<K, V>,
+    W: Write,
+{
+    info!(target: REQUEST_LOG_TARGET, "watch {:?}", key);
+    let events = engine.watch(key);
+    write_message(&codec, writer, &Response::<V>::Ack)?;
+    let mut last_sent = Instant::now();
+    loop {
+        let resp = match events.recv_timeout(WATCH_POLL) {
+            Ok(event) => Response::<V>::Event(event),
+            Err(RecvTimeoutError::Timeout) if shutdown.load(Ordering::SeqCst) => return Ok(()),
+            Err(RecvTimeoutError::Timeout) if last_sent.elapsed() >= WATCH_HEARTBEAT => Response::<V>::Ack,
+            Err(RecvTimeoutError::Timeout) => continue,
+            // the engine keeps the sender as long as the receiver lives
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        write_message(&codec, writer, &resp)?;
+        last_sent = Instant::now();
+    }
+}
+
+// answers a Request::Select, which the connection loop acts upon
+pub(crate) fn select<K, V, E>(databases: &BTreeMap<u32, E>, db: u32) -> Response<V>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    E: KvsEngine<K, V>,
+{
+    let (resp, outcome) = match databases.contains_key(&db) {
+        true => (Response::<V>::Ack, "ok"),
+        false => (Response::<V>::Err(RemoteError::Other(format!("no database {}", db))), "err"),
+    };
+    info!(target: REQUEST_LOG_TARGET, "select {} {}", db, outcome);
+    resp
+}
+
+// compares every byte whatever the first difference, so the time taken does not tell how much
+// of a guessed token was right
+fn tokens_match(token: &str, expected: &str) -> bool {
+    token.len() == expected.len()
+        && token.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// a socket file left behind by a server that did not stop cleanly would make binding fail. it
+// is only removed if nothing answers on it, anything else at the path is left alone.
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() && UnixStream::connect(path).is_err() => {
+            fs::remove_file(path)?;
+            Ok(())
+        },
+        _ => Ok(()),
+    }
+}
+
+// serves a single request against the engine and logs it, errors are turned into responses
+pub(crate) fn handle_request<K, V, E>(engine: &E, req: Request<K, V>) -> Response<V>
+where
+    K: Clone + Serialize + DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
+    E: KvsEngine<K, V>,
+{
+    let description = log_enabled!(target: REQUEST_LOG_TARGET, Level::Info).then(|| describe(&req));
+    let started = Instant::now();
+    let resp: Response<V> = match req {
+        Request::Get{key} => match engine.get(key) {
+            Ok(val) => Response::<V>::Value(val),
+            Err(err) => Response::<V>::Err(err.into()),
+        },
+        Request::Set{key, val} => match engine.set(key, val) {
+            Ok(_) => Response::<V>::Ack,
+            Err(err) => Response::<V>::Err(err.into()),
+        },
+        Request::Rm{key} => match engine.remove(key) {
+            Ok(_) => Response::<V>::Ack,
+            Err(err) => Response::<V>::Err(err.into()),
+        },
+        Request::Exists{key} => match engine.contains_key(key) {
+            Ok(exists) => Response::<V>::Exists(exists),
+            Err(err) => Response::<V>::Err(err.into()),
+        },
+        Request::Compact => match engine.compact() {
+            Ok(()) => Response::<V>::Ack,
+            Err(err) => Response::<V>::Err(err.into()),
+        },
+        Request::Stats => match engine.stats() {
+            Ok(stats) => Response::<V>::Stats(stats),
+            Err(err) => Response::<V>::Err(err.into()),
+        },
+        // a watch turns the connection into a stream, which the connection loop serves itself
+        Request::Watch{..} => Response::<V>::Err(RemoteError::Other("watch is only served on a connection".to_owned())),
+        // so does a select, which changes the engine that serves the connection
+        Request::Select{..} => Response::<V>::Err(RemoteError::Other("select is only served on a connection".to_owned())),
+        Request::Ping => Response::<V>::Ack,
+        // the token, if the server has one, was checked before the request got here
+        Request::Auth{..} => Response::<V>::Ack,
+        // the codec, once accepted, is switched to by the connection loop
+        Request::Hello{version, key_type, value_type, ..} => {
+            let (server_key_type, server_value_type) = (type_name::<K>(), type_name::<V>());
+            if version != PROTOCOL_VERSION {
+                Response::<V>::Err(RemoteError::ProtocolVersion{client: version, server: PROTOCOL_VERSION})
+            } else if key_type == server_key_type && value_type == server_value_type {
+                Response::<V>::Ack
+            } else {
+                Response::<V>::Err(RemoteError::Incompatible(format!(
+                    "the server stores {} keys and {} values, not {} keys and {} values",
+                    server_key_type, server_value_type, key_type, value_type,
+                )))
+            }
+        },
+        Request::GetMany{keys} => {
+            let vals: Result<Vec<Option<V>>> = keys.into_iter().map(|key| engine.get(key)).collect();
+            match vals {
+                Ok(vals) => Response::<V>::Many(vals),
+                Err(err) => Response::<V>::Err(err.into()),
+            }
+        },
+    };
+    if let Some(description) = description {
+        let outcome = if matches!(resp, Response::Err(_)) { "err" } else { "ok" };
+        info!(target: REQUEST_LOG_TARGET, "{} {} in {:?}", description, outcome, started.elapsed());
+    }
+    resp
+}
+
+// names the request and its keys for the request log
+fn describe<K, V>(req: &Request<K, V>) -> String
+where
+    K: Clone + Ord + Send + Sync + 'static + Debug,
+    V: Clone + Send + 'static,
+{
+    match req {
+        Request::Get{key} => format!("get {:?}", key),
+        Request::Set{key, ..} => format!("set {:?}", key),
+        Request::Rm{key} => format!("rm {:?}", key),
+        Request::GetMany{keys} => format!("get_many {:?}", keys),
+        Request::Exists{key} => format!("exists {:?}", key),
+        Request::Compact => "compact".to_owned(),
+        Request::Stats => "stats".to_owned(),
+        Request::Watch{key} => format!("watch {:?}", key),
+        Request::Select{db} => format!("select {}", db),
+        Request::Ping => "ping".to_owned(),
+        Request::Auth{..} => "auth".to_owned(),
+        Request::Hello{..} => "hello".to_owned(),
+    }
+}
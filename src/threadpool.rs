@@ -1,47 +1,147 @@
+use std::any::Any;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use std::sync::{mpsc, Arc, Mutex};
+use std::panic::{self, AssertUnwindSafe};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use log::error;
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+enum Message {
+    Job(Job),
+    // asks whichever worker receives it to exit, used when shrinking the pool
+    Terminate,
+}
+
+// jobs queued or running, with a condvar signalled whenever the count drops to zero. the lock
+// only orders the signal against a waiter checking the count, the count itself is atomic.
+#[derive(Default)]
+struct Pending {
+    jobs: AtomicUsize,
+    lock: Mutex<()>,
+    idle: Condvar,
+}
+
+impl Pending {
+    fn add(&self) {
+        self.jobs.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn done(&self) {
+        if self.jobs.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _lock = self.lock.lock().unwrap();
+            self.idle.notify_all();
+        }
+    }
+}
 
 pub struct Worker {
+    id: usize,
     thread: thread::JoinHandle<()>,
 }
 
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    sender: Option<Sender<Message>>,
+    // every worker holds a clone, so workers take jobs off the queue without a shared lock
+    receiver: Receiver<Message>,
+    // workers that exited on a Terminate message report their id here
+    exit_sender: Sender<usize>,
+    exit_receiver: Receiver<usize>,
+    next_id: usize,
+    pending: Arc<Pending>,
 }
 
 impl Worker {
-    fn new(receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::Builder::new().spawn(move || loop {
-            match receiver.lock().unwrap().recv() {
-                Ok(job) => {
-                    job();
+    fn new(id: usize, receiver: Receiver<Message>, exit_sender: Sender<usize>, pending: Arc<Pending>) -> Worker {
+        let thread = thread::Builder::new().name(format!("kvs-worker-{}", id)).spawn(move || loop {
+            match receiver.recv() {
+                Ok(Message::Job(job)) => {
+                    // a panicking job must not take the worker down with it
+                    if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                        error!("job panicked: {}", panic_message(&*panic));
+                    }
+                    pending.done();
+                },
+                Ok(Message::Terminate) => {
+                    let _ = exit_sender.send(id);
+                    break;
                 },
                 Err(_) => {
+                    break;
                 },
             }
         }).unwrap();
         Worker{
+            id,
             thread,
         }
     }
 }
 
+// extracts the message passed to panic!, if any
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic payload"
+    }
+}
+
 impl ThreadPool {
     pub fn new(size: usize) -> ThreadPool {
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        ThreadPool::spawn(size, crossbeam_channel::unbounded())
+    }
 
-        let mut workers = Vec::with_capacity(size);
+    // same as new, but queues at most queue_cap jobs that no worker has picked up yet. execute
+    // then blocks until a slot frees up, and try_execute hands the job back instead.
+    pub fn with_capacity(size: usize, queue_cap: usize) -> ThreadPool {
+        ThreadPool::spawn(size, crossbeam_channel::bounded(queue_cap))
+    }
 
-        for _ in 0..size {
-            workers.push(Worker::new(Arc::clone(&receiver)));
-        }
-        ThreadPool{
-            workers,
+    fn spawn(size: usize, (sender, receiver): (Sender<Message>, Receiver<Message>)) -> ThreadPool {
+        let (exit_sender, exit_receiver) = crossbeam_channel::unbounded();
+
+        let mut pool = ThreadPool{
+            workers: Vec::with_capacity(size),
             sender: Some(sender),
+            receiver,
+            exit_sender,
+            exit_receiver,
+            next_id: 0,
+            pending: Arc::default(),
+        };
+        pool.resize(size);
+        pool
+    }
+
+    // number of worker threads in the pool
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    // grows the pool by spawning new workers, or shrinks it by asking surplus workers to exit.
+    // when shrinking, workers exit after finishing the jobs queued ahead of the request, and this
+    // call blocks until they have.
+    pub fn resize(&mut self, new_size: usize) {
+        while self.workers.len() < new_size {
+            let worker = Worker::new(self.next_id, self.receiver.clone(), self.exit_sender.clone(), Arc::clone(&self.pending));
+            self.workers.push(worker);
+            self.next_id += 1;
+        }
+
+        let surplus = self.workers.len().saturating_sub(new_size);
+        for _ in 0..surplus {
+            self.send(Message::Terminate);
+        }
+        for _ in 0..surplus {
+            let id = self.exit_receiver.recv().unwrap();
+            if let Some(pos) = self.workers.iter().position(|worker| worker.id == id) {
+                self.workers.swap_remove(pos).thread.join().unwrap();
+            }
         }
     }
 
@@ -49,10 +149,45 @@ impl ThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        self.pending.add();
+        self.send(Message::Job(Box::new(f)));
+    }
+
+    // queues the job unless the queue is full, in which case the job is returned untouched
+    pub fn try_execute<F>(&self, f: F) -> Result<(), Job>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let sender = self.sender.as_ref().unwrap();
+        self.pending.add();
+        match sender.try_send(Message::Job(Box::new(f))) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(Message::Job(job))) => {
+                self.pending.done();
+                Err(job)
+            },
+            Err(err) => panic!("job queue closed: {}", err),
+        }
+    }
+
+    // number of jobs queued or running
+    pub fn pending(&self) -> usize {
+        self.pending.jobs.load(Ordering::SeqCst)
+    }
+
+    // blocks until every job queued so far, and any queued meanwhile, has finished, panicked
+    // jobs included. unlike dropping the pool, it keeps the workers around for more jobs.
+    pub fn join_pending(&self) {
+        let mut lock = self.pending.lock.lock().unwrap();
+        while self.pending() > 0 {
+            lock = self.pending.idle.wait(lock).unwrap();
+        }
+    }
+
+    fn send(&self, message: Message) {
         self.sender
             .as_ref()
-            .map(|sender| sender.send(job))
+            .map(|sender| sender.send(message))
             .transpose()
             .unwrap();
     }
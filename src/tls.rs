@@ -0,0 +1,83 @@
+use crate::Result;
+use crate::endpoint::{Duplex, Stream};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+// how a client encrypts its connections, kept so that reconnecting encrypts the same way
+#[derive(Clone)]
+pub(crate) struct ClientTls {
+    server_name: ServerName<'static>,
+    config: Arc<ClientConfig>,
+}
+
+impl ClientTls {
+    // trusts servers whose certificate for server_name chains up to one of the roots
+    pub(crate) fn new(server_name: &str, roots: RootCertStore) -> Result<ClientTls> {
+        let server_name = ServerName::try_from(server_name.to_owned())
+            .map_err(|err| crate::Error::UnhandledError(format!("invalid server name {}: {}", server_name, err)))?;
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(ClientTls{server_name, config: Arc::new(config)})
+    }
+
+    // runs the handshake over the connected stream, so a server that cannot prove its identity
+    // fails the connect rather than the first request
+    pub(crate) fn connect(&self, mut stream: Stream) -> Result<Stream> {
+        let mut conn = ClientConnection::new(Arc::clone(&self.config), self.server_name.clone())?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+        Ok(shared(StreamOwned::new(conn, stream)))
+    }
+}
+
+// builds the settings of a server presenting the given certificate chain, leaf first
+pub(crate) fn server_config(cert_chain: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Result<Arc<ServerConfig>> {
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)?;
+
+    Ok(Arc::new(config))
+}
+
+// runs the server side of the handshake over an accepted stream
+pub(crate) fn accept(config: &Arc<ServerConfig>, mut stream: Stream) -> Result<Stream> {
+    let mut conn = ServerConnection::new(Arc::clone(config))?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut stream)?;
+    }
+    Ok(shared(StreamOwned::new(conn, stream)))
+}
+
+// the provider is picked explicitly, the process-wide default is ambiguous as soon as another
+// crate enables a second one
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn shared<S: Duplex + 'static>(stream: S) -> Stream {
+    Stream::Tls(Arc::new(Mutex::new(Box::new(stream))))
+}
+
+// a session is shut down with a close_notify alert, which the peer reads as the end of the stream.
+// the socket itself stays open, the peer may still be sending.
+impl Duplex for StreamOwned<ClientConnection, Stream> {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.conn.send_close_notify();
+        self.flush()
+    }
+}
+
+impl Duplex for StreamOwned<ServerConnection, Stream> {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.conn.send_close_notify();
+        self.flush()
+    }
+}
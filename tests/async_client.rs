@@ -0,0 +1,31 @@
+use kvs::{AsyncKvsClient, Error, KvStore, KvsServer, Result, ThreadPool};
+use std::net::TcpListener;
+use std::thread;
+use tempfile::TempDir;
+
+// The async client should talk to the sync server like the sync client does
+#[tokio::test]
+async fn async_client_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned()).await?, None);
+    client.remove("key1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    match client.remove("key1".to_owned()).await {
+        Err(Error::DoesNotExist{key}) => assert_eq!(key, "\"key1\""),
+        res => panic!("expected a does not exist error, got {:?}", res),
+    }
+
+    drop(client);
+    handle.stop();
+    Ok(())
+}
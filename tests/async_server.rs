@@ -0,0 +1,72 @@
+use kvs::{AsyncKvsClient, AsyncKvsServer, KvStore, KvsClient, KvsEngine, Result};
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+
+// Many concurrent connections should all be served, and cancelling the token should stop the
+// server once the connections are closed, even one left idle
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn async_server_concurrent_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let server = AsyncKvsServer::<String, String, _>::new(engine);
+    let shutdown = server.shutdown_token();
+    let server = tokio::spawn(server.serve(listener));
+
+    let mut clients = Vec::new();
+    for i in 0..100 {
+        clients.push(tokio::spawn(async move {
+            let mut client = AsyncKvsClient::connect(addr).await?;
+            for j in 0..10 {
+                let key = format!("key{}_{}", i, j);
+                client.set(key.clone(), format!("value{}", j)).await?;
+                assert_eq!(client.get(key).await?, Some(format!("value{}", j)));
+            }
+            Ok::<_, kvs::Error>(())
+        }));
+    }
+    for client in clients {
+        client.await.expect("client task panicked")?;
+    }
+
+    let _idle = AsyncKvsClient::connect(addr).await?;
+    shutdown.cancel();
+    server.await.expect("server task panicked")?;
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    for i in 0..100 {
+        for j in 0..10 {
+            assert_eq!(store.get(format!("key{}_{}", i, j))?, Some(format!("value{}", j)));
+        }
+    }
+    Ok(())
+}
+
+// A connection should be able to switch databases, each keeping its own keys
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn async_server_select_database() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let first = KvStore::<String, String>::open(&temp_dir.path().join("db0"))?;
+    let second = KvStore::<String, String>::open(&temp_dir.path().join("db1"))?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let server = AsyncKvsServer::<String, String, _>::new(first).with_database(1, second);
+    let shutdown = server.shutdown_token();
+    let server = tokio::spawn(server.serve(listener));
+
+    tokio::task::spawn_blocking(move || {
+        let mut client: KvsClient = KvsClient::connect(addr)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        client.select(1)?;
+        assert_eq!(client.get("key1".to_owned())?, None);
+        assert!(client.select(2).is_err());
+        client.select(0)?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        Ok::<_, kvs::Error>(())
+    }).await.expect("client task panicked")?;
+
+    shutdown.cancel();
+    server.await.expect("server task panicked")?;
+    Ok(())
+}
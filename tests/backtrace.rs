@@ -0,0 +1,32 @@
+// a binary of its own: whether backtraces are captured is decided once per process, by the
+// first error converted
+use kvs::{Error, KvStore, KvsEngine, Result, ResultExt};
+use std::env;
+use std::io;
+use tempfile::TempDir;
+
+fn convert() -> Result<()> {
+    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"))?;
+    Ok(())
+}
+
+// With backtraces enabled, an error converted from an io error should carry a backtrace of where
+// the conversion happened, shown in its Debug output, also under a context
+#[test]
+fn error_backtrace() -> Result<()> {
+    env::set_var("RUST_FAILURE_BACKTRACE", "1");
+
+    let err = convert().context("reading log file 7").unwrap_err();
+    let backtrace = format!("{:?}", err.backtrace().expect("no backtrace was captured"));
+    assert!(backtrace.contains("convert"), "{}", backtrace);
+    let debug = format!("{:?}", err);
+    assert!(debug.contains("UnexpectedEof") && debug.contains("convert"), "{}", debug);
+
+    // errors the store creates itself carry none
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    let err = store.remove("key1".to_owned()).unwrap_err();
+    assert!(matches!(err, Error::DoesNotExist{..}));
+    assert!(err.backtrace().is_none());
+    Ok(())
+}
@@ -0,0 +1,40 @@
+use kvs::BloomFilter;
+
+// Every inserted item should be found
+#[test]
+fn no_false_negatives() {
+    let mut filter = BloomFilter::new(10_000, 0.01);
+    for id in 0..10_000 {
+        filter.insert(format!("key{}", id).as_bytes());
+    }
+
+    assert_eq!(filter.len(), 10_000);
+    for id in 0..10_000 {
+        assert!(filter.contains(format!("key{}", id).as_bytes()));
+    }
+}
+
+// A filter holding as many items as it was sized for should report absent items at roughly the
+// rate it was sized for
+#[test]
+fn false_positive_rate() {
+    let mut filter = BloomFilter::new(10_000, 0.01);
+    for id in 0..10_000 {
+        filter.insert(format!("key{}", id).as_bytes());
+    }
+
+    let false_positives = (0..100_000)
+        .filter(|id| filter.contains(format!("missing{}", id).as_bytes()))
+        .count();
+    let rate = false_positives as f64 / 100_000.0;
+    assert!(rate < 0.02, "false positive rate {} too high", rate);
+}
+
+// An empty filter should not report anything
+#[test]
+fn empty_filter() {
+    let filter = BloomFilter::new(100, 0.01);
+
+    assert!(filter.is_empty());
+    assert!(!filter.contains(b"key"));
+}
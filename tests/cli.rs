@@ -1,6 +1,9 @@
 use assert_cmd::prelude::*;
+use kvs::{JsonCodec, KvStore, KvsEngine, KvsServer, ThreadPool};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -20,28 +23,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +55,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +94,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +126,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +137,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +148,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +160,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
 //    assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -171,24 +175,297 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+// `kvs-server --log-level` should set the level of everything the server logs
+#[test]
+fn server_cli_log_level() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    for (level, logs_info) in [("warn", false), ("info", true), ("debug", true)] {
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = cmd
+            .args(["--log-level", level, "--addr", "127.0.0.1:4012"])
+            .current_dir(&temp_dir)
+            .stderr(File::create(&stderr_path).unwrap())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+
+        let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+        assert_eq!(content.contains("Listening on 127.0.0.1:4012"), logs_info, "at level {}: {}", level, content);
+    }
+
+    for level in ["loud", "WARNING", ""] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--log-level", level])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+}
+
+#[test]
+fn server_cli_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--threads", "3", "--addr", "127.0.0.1:4005"])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("Worker threads: 3"));
+}
+
+#[test]
+fn server_cli_invalid_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    for threads in ["0", "-1", "many"] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--threads", threads])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+}
+
+#[test]
+fn server_cli_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let work_dir = temp_dir.path().join("work");
+    fs::create_dir(&work_dir).unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--addr", "127.0.0.1:4008", "--dir"])
+        .arg(&data_dir)
+        .current_dir(&work_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert!(data_dir.join("engine").exists());
+    assert!(data_dir.join("1.log").exists());
+    assert_eq!(fs::read_dir(&work_dir).unwrap().count(), 0);
+}
+
+// `kvs-server --databases` should keep every database but the first in its own directory, and
+// `kvs-client --db` should select one of them
+#[test]
+fn cli_databases() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4013";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--databases", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--db", "1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--db", "1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--db", "2"])
+        .assert()
+        .failure()
+        .stderr(contains("no database 2"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert!(temp_dir.path().join("db1").join("1.log").exists());
+}
+
+// `kvs-server --metrics-addr` should serve the metrics of its store over http
+#[test]
+fn server_cli_metrics_addr() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--addr", "127.0.0.1:4010", "--metrics-addr", "127.0.0.1:4011"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let mut stream = TcpStream::connect("127.0.0.1:4011").unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("kvs_operations_total{op=\"set\"} 1\n"));
+    assert!(response.contains("kvs_live_keys 1\n"));
+}
+
+#[test]
+fn server_cli_truncated_engine_file() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("engine"), "k").unwrap();
+    for args in [&["--addr", "127.0.0.1:4009"][..], &["--engine", "kvs", "--addr", "127.0.0.1:4009"][..]] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("engine file"));
+    }
+
+    assert_eq!(fs::read_to_string(temp_dir.path().join("engine")).unwrap(), "k");
+    assert!(!temp_dir.path().join("1.log").exists());
+}
+
+#[test]
+fn cli_stats() {
+    let addr = "127.0.0.1:4007";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    for key in ["key1", "key2", "key3"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, "value", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Live keys: 3\n"))
+        .stdout(contains("Segments: 1\n"))
+        .stdout(contains("Uncompacted bytes: 0\n"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--json", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"live_keys\":3"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_unix_socket() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let socket = temp_dir.path().join("kvs.sock");
+    let socket = socket.to_str().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--socket", socket])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--socket", socket])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--socket", socket])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--socket", socket, "--addr", "127.0.0.1:4000"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -196,7 +473,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -204,7 +481,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -212,7 +489,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -220,7 +497,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -228,7 +505,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -236,7 +513,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -244,7 +521,15 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["compact", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -257,26 +542,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -289,3 +575,117 @@ fn cli_access_server(engine: &str, addr: &str) {
 fn cli_access_server_kvs_engine() {
     cli_access_server("kvs", "127.0.0.1:4004");
 }
+
+// `kvs-client --value-type i64` should parse integers before sending them to a server storing
+// them, and print them back
+#[test]
+fn cli_typed_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::<String, i64>::open(temp_dir.path()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = KvsServer::<String, i64, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "-42", "--value-type", "i64", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--value-type", "i64", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout("-42\n");
+
+    // not an integer, rejected before connecting
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "forty-two", "--value-type", "i64", "--addr", &addr])
+        .assert()
+        .failure()
+        .stderr(contains("invalid i64"));
+
+    // the types default to strings, which the server refuses
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .assert()
+        .failure();
+
+    handle.stop();
+}
+
+// `kvs-client dump` should print the entries of a data directory without a server
+#[test]
+fn cli_dump() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::<String, String>::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value 1".to_owned()).unwrap();
+    store.remove("key1".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["dump", "--dir", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("1.log 8.."))
+        .stdout(contains(r#"set "key1" "value 1""#))
+        .stdout(contains(r#"rm "key1""#));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["dump", "--dir", temp_dir.path().join("missing").to_str().unwrap()])
+        .assert()
+        .failure();
+}
+
+// `kvs-client dump` should decode the log files with the key and value types it is given
+#[test]
+fn cli_dump_typed() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::<String, i64>::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), -42).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["dump", "--dir", temp_dir.path().to_str().unwrap(), "--value-type", "i64"])
+        .assert()
+        .success()
+        .stdout(contains(r#"set "key1" "-42""#));
+
+    // the entries do not decode as strings
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["dump", "--dir", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .failure();
+}
+
+// `kvs-client dump` should decode log files written with the codec it is given
+#[test]
+fn cli_dump_json() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::<String, String, JsonCodec>::open_with_codec(temp_dir.path(), JsonCodec).unwrap();
+    store.set("key1".to_owned(), "value 1".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["dump", "--dir", temp_dir.path().to_str().unwrap(), "--codec", "json"])
+        .assert()
+        .success()
+        .stdout(contains(r#"set "key1" "value 1""#));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["dump", "--dir", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .failure();
+}
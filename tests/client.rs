@@ -0,0 +1,311 @@
+use assert_cmd::prelude::*;
+use kvs::{Error, JsonCodec, KvStore, KvsClient, KvsClientPool, KvsServer, Result, ThreadPool};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// A server that never answers should make the client time out instead of blocking forever
+#[test]
+fn client_times_out_on_unresponsive_server() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        // accept the connection, answer the handshake and hold it open without ever replying
+        // to anything else
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+        stream.read_exact(&mut vec![0; u32::from_be_bytes(len) as usize]).unwrap();
+        let ok = br#""Ack""#;
+        stream.write_all(&(ok.len() as u32).to_be_bytes()).unwrap();
+        stream.write_all(ok).unwrap();
+        thread::sleep(Duration::from_secs(10));
+    });
+
+    let mut client: KvsClient = KvsClient::connect_with_timeout(addr, Duration::from_millis(200))?;
+    let start = Instant::now();
+    let err = client.get("key1".to_owned()).expect_err("get did not time out");
+    assert!(matches!(err, Error::Timeout), "unexpected error: {}", err);
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    Ok(())
+}
+
+// A client built with retries should survive the server restarting between two calls
+#[test]
+fn client_reconnects_after_server_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4006";
+    let start_server = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+
+    let mut server = start_server();
+    let res = KvsClient::connect_with_retry(addr.parse::<SocketAddr>().unwrap(), 8, Duration::from_millis(50))
+        .and_then(|mut client| {
+            client.set("key1".to_owned(), "value1".to_owned())?;
+            Ok(client)
+        });
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    let mut client = res?;
+
+    let mut server = start_server();
+
+    let res = client.get("key1".to_owned());
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    assert_eq!(res?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Many threads sharing a small pool should all get their requests through
+#[test]
+fn client_pool_shared_by_threads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(4));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let pool = Arc::new(KvsClientPool::connect(addr, 4)?);
+    assert_eq!(pool.size(), 4);
+    let threads: Vec<_> = (0..32)
+        .map(|i| {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || -> Result<()> {
+                for j in 0..20 {
+                    let key = format!("key{}_{}", i, j);
+                    pool.get().set(key.clone(), format!("value{}", j))?;
+                    assert_eq!(pool.get().get(key)?, Some(format!("value{}", j)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().expect("client thread panicked")?;
+    }
+
+    let mut client = pool.get();
+    assert_eq!(client.get("key31_19".to_owned())?, Some("value19".to_owned()));
+    drop(client);
+    drop(pool);
+    handle.stop();
+    Ok(())
+}
+
+// Pipelined requests should all be answered, in the order they were queued
+#[test]
+fn client_pipeline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut pipeline = client.pipeline();
+    for i in 0..100 {
+        pipeline.set(format!("key{}", i), format!("value{}", i));
+    }
+    pipeline.get("key42".to_owned()).remove("missing".to_owned()).get("key99".to_owned());
+    assert_eq!(pipeline.len(), 103);
+
+    let mut results = pipeline.flush()?.into_iter();
+    for _ in 0..100 {
+        assert_eq!(results.next().unwrap()?, None);
+    }
+    assert_eq!(results.next().unwrap()?, Some("value42".to_owned()));
+    assert!(matches!(results.next().unwrap(), Err(Error::DoesNotExist{..})));
+    assert_eq!(results.next().unwrap()?, Some("value99".to_owned()));
+    assert!(results.next().is_none());
+    assert!(pipeline.is_empty());
+
+    // the connection keeps serving single requests afterwards
+    assert_eq!(client.get("key0".to_owned())?, Some("value0".to_owned()));
+
+    drop(client);
+    handle.stop();
+    Ok(())
+}
+
+// Existence probes should tell present keys from absent and removed ones
+#[test]
+fn client_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".repeat(1000))?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.remove("key2".to_owned())?;
+
+    assert!(client.exists("key1".to_owned())?);
+    assert!(!client.exists("key2".to_owned())?);
+    assert!(!client.exists("key3".to_owned())?);
+
+    drop(client);
+    handle.stop();
+    Ok(())
+}
+
+// A client should refuse a server storing other types when connecting, rather than send it
+// requests it would misread
+#[test]
+fn client_rejects_incompatible_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, i64>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::<String, i64, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    match KvsClient::<String, String>::connect(addr) {
+        Err(Error::Incompatible(msg)) => assert!(msg.contains("i64"), "unexpected message: {}", msg),
+        Err(err) => panic!("expected an incompatible peer error, got {:?}", err),
+        Ok(_) => panic!("expected an incompatible peer error, got a client"),
+    }
+
+    handle.stop();
+    Ok(())
+}
+
+// A client typed like the server should round-trip its values
+#[test]
+fn client_typed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // bincode cannot decode a json value, which only knows its type once read
+    let engine = KvStore::<i64, serde_json::Value, _>::open_with_codec(temp_dir.path(), JsonCodec)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::<i64, serde_json::Value, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::<i64, serde_json::Value>::connect(addr)?;
+    let value = serde_json::json!({"name": "kvs", "tags": [1, 2]});
+    client.set(-7, value.clone())?;
+    assert_eq!(client.get(-7)?, Some(value.clone()));
+    assert_eq!(client.get(7)?, None);
+    let results = client.pipeline().set(8, serde_json::json!(null)).get(-7).flush()?;
+    assert_eq!(results.into_iter().collect::<Result<Vec<_>>>()?, vec![None, Some(value)]);
+
+    handle.stop();
+    Ok(())
+}
+
+// After close, the server should have served every request sent before, and let go of the
+// connection: a server with a single worker only serves the next one once the first is closed
+#[test]
+fn client_close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    let mut pipeline = client.pipeline();
+    for i in 0..100 {
+        pipeline.set(format!("key{}", i), format!("value{}", i));
+    }
+    pipeline.flush()?;
+    client.set("last".to_owned(), "value".to_owned())?;
+    client.close()?;
+
+    let mut client: KvsClient = KvsClient::connect_with_timeout(addr, Duration::from_secs(5))?;
+    for i in 0..100 {
+        assert_eq!(client.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(client.get("last".to_owned())?, Some("value".to_owned()));
+    client.close()?;
+
+    handle.stop();
+    Ok(())
+}
+
+// A remove resent after the connection broke between the server applying it and the client
+// reading the response should still succeed, the key is gone either way
+#[test]
+fn client_resent_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let server_addr = listener.local_addr()?;
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(2));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    // passes messages on to the server, but drops the first connection once the server has
+    // answered a remove, before the client sees the response
+    let proxy = TcpListener::bind("127.0.0.1:0")?;
+    let addr = proxy.local_addr()?;
+    thread::spawn(move || {
+        for (i, client) in proxy.incoming().enumerate() {
+            let mut client = client.unwrap();
+            let mut server = TcpStream::connect(server_addr).unwrap();
+            thread::spawn(move || -> io::Result<()> {
+                loop {
+                    let req = forward_message(&mut client, &mut server)?;
+                    let resp = read_frame(&mut server)?;
+                    if i == 0 && req.starts_with(br#"{"Rm""#) {
+                        return Ok(());
+                    }
+                    client.write_all(&resp)?;
+                }
+            });
+        }
+    });
+
+    let mut client: KvsClient = KvsClient::connect(server_addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut retrying: KvsClient = KvsClient::connect_with_retry(addr, 3, Duration::from_millis(50))?;
+    retrying.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    // a remove that was not resent still fails for a missing key
+    assert!(matches!(retrying.remove("key1".to_owned()), Err(Error::DoesNotExist{..})));
+
+    handle.stop();
+    Ok(())
+}
+
+// reads a whole message, length included
+fn read_frame(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut frame = vec![0; 4];
+    stream.read_exact(&mut frame)?;
+    let len = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]) as usize;
+    frame.resize(4 + len, 0);
+    stream.read_exact(&mut frame[4..])?;
+    Ok(frame)
+}
+
+// passes a message on and returns its payload
+fn forward_message(from: &mut TcpStream, to: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let frame = read_frame(from)?;
+    to.write_all(&frame)?;
+    Ok(frame[4..].to_vec())
+}
@@ -0,0 +1,78 @@
+use kvs::{BincodeCodec, JsonCodec, KvStore, KvsEngine, Result};
+use std::fs;
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+fn dir_size(dir: &TempDir) -> u64 {
+    WalkDir::new(dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum()
+}
+
+// Log files written before the codec was configurable are plain JSON and should still load
+#[test]
+fn json_codec_loads_legacy_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","val":"value1"}}{"Set":{"key":"key2","val":"value2"}}{"Set":{"key":"key1","val":"value3"}}{"Rm":{"key":"key2"}}"#,
+    )?;
+
+    let store = KvStore::<String, String, JsonCodec>::open_with_codec(temp_dir.path(), JsonCodec)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // new writes keep using JSON and survive a reopen
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    drop(store);
+    let store = KvStore::<String, String, JsonCodec>::open_with_codec(temp_dir.path(), JsonCodec)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Offsets computed while replaying bincode logs must point at the right entries
+#[test]
+fn bincode_codec_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<u32, String>::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(i, format!("value{}", i))?;
+    }
+    for i in (0..100).step_by(3) {
+        store.remove(i)?;
+    }
+    store.set(50, "overwritten".to_owned())?;
+
+    drop(store);
+    let store = KvStore::<u32, String, BincodeCodec>::open_with_codec(temp_dir.path(), BincodeCodec)?;
+    for i in 0..100 {
+        let expected = match i {
+            50 => Some("overwritten".to_owned()),
+            i if i % 3 == 0 => None,
+            i => Some(format!("value{}", i)),
+        };
+        assert_eq!(store.get(i)?, expected);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn bincode_logs_are_smaller_than_json() -> Result<()> {
+    let json_dir = TempDir::new().expect("unable to create temporary working directory");
+    let bincode_dir = TempDir::new().expect("unable to create temporary working directory");
+    let json_store = KvStore::<u64, u64, JsonCodec>::open_with_codec(json_dir.path(), JsonCodec)?;
+    let bincode_store = KvStore::<u64, u64>::open(bincode_dir.path())?;
+
+    for i in 0..1000 {
+        json_store.set(i, i * i)?;
+        bincode_store.set(i, i * i)?;
+    }
+
+    assert!(dir_size(&bincode_dir) < dir_size(&json_dir));
+
+    Ok(())
+}
@@ -0,0 +1,35 @@
+use kvs::{Error, Result, ResultExt};
+use std::io;
+
+fn read_file(file_id: u32) -> Result<()> {
+    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"))
+        .with_context(|| format!("failed during compaction of file {}", file_id))
+}
+
+// A contextual error should show every note added on the way up before the original error, and
+// keep the original error reachable
+#[test]
+fn error_context() {
+    let err = read_file(7).context("compaction aborted").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "compaction aborted: failed during compaction of file 7: failed to fill whole buffer"
+    );
+    match err.root() {
+        Error::Io(err, _) => assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof),
+        err => panic!("expected an io error, got {:?}", err),
+    }
+
+    let err = Error::DoesNotExist{key: "key1".to_owned()}.with_context("removing key1");
+    assert_eq!(err.to_string(), "removing key1: key: key1 does not exist");
+    assert!(matches!(err.root(), Error::DoesNotExist{key} if key == "key1"));
+}
+
+// Messages should convert into the catch-all error
+#[test]
+fn unhandled_error() {
+    assert!(matches!(Error::from("bad export file"), Error::UnhandledError(msg) if msg == "bad export file"));
+    assert!(matches!(Error::from(format!("bad file {}", 7)), Error::UnhandledError(msg) if msg == "bad file 7"));
+    assert_eq!(Error::unhandled("bad export file").to_string(), "bad export file");
+    assert!(matches!(Error::unhandled("bad").root(), Error::UnhandledError(_)));
+}
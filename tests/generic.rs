@@ -109,3 +109,182 @@ fn test_mixed_types() -> Result<()> {
     Ok(())
 }
 
+
+// Test range scans over the ordered index
+#[test]
+fn test_scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<u32, u32>::open(temp_dir.path())?;
+
+    for i in 1..100 {
+        store.set(i, i * 10)?;
+    }
+
+    let expected = (10..20).map(|i| (i, i * 10)).collect::<Vec<_>>();
+    assert_eq!(store.scan(10..20)?, expected);
+
+    // inclusive and unbounded ranges
+    assert_eq!(store.scan(95..=99)?.len(), 5);
+    assert_eq!(store.scan(..)?.len(), 99);
+    assert!(store.scan(200..300)?.is_empty());
+
+    // removed keys are not part of the scan
+    store.remove(15)?;
+    let keys = store.scan(10..20)?.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+    assert_eq!(keys, vec![10, 11, 12, 13, 14, 16, 17, 18, 19]);
+
+    Ok(())
+}
+
+// Test that set_and_get hands back the value it replaced
+#[test]
+fn test_set_and_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, u64>::open(temp_dir.path())?;
+
+    assert_eq!(store.set_and_get("counter".to_string(), 1)?, None);
+    assert_eq!(store.set_and_get("counter".to_string(), 2)?, Some(1));
+    assert_eq!(store.set_and_get("counter".to_string(), 3)?, Some(2));
+    assert_eq!(store.get("counter".to_string())?, Some(3));
+
+    // a removed key has no previous value
+    store.remove("counter".to_string())?;
+    assert_eq!(store.set_and_get("counter".to_string(), 4)?, None);
+
+    // previous values survive a reopen
+    drop(store);
+    let store = KvStore::<String, u64>::open(temp_dir.path())?;
+    assert_eq!(store.set_and_get("counter".to_string(), 5)?, Some(4));
+
+    Ok(())
+}
+
+// Test that compare_and_swap only writes when the current value matches
+#[test]
+fn test_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, u64>::open(temp_dir.path())?;
+
+    // None expects the key to be missing
+    assert!(store.compare_and_swap("lock".to_string(), None, 1)?);
+    assert!(!store.compare_and_swap("lock".to_string(), None, 2)?);
+    assert_eq!(store.get("lock".to_string())?, Some(1));
+
+    assert!(!store.compare_and_swap("lock".to_string(), Some(5), 2)?);
+    assert_eq!(store.get("lock".to_string())?, Some(1));
+    assert!(store.compare_and_swap("lock".to_string(), Some(1), 2)?);
+    assert_eq!(store.get("lock".to_string())?, Some(2));
+
+    store.remove("lock".to_string())?;
+    assert!(!store.compare_and_swap("lock".to_string(), Some(2), 3)?);
+    assert!(store.compare_and_swap("lock".to_string(), None, 3)?);
+
+    Ok(())
+}
+
+// Test that concurrent compare_and_swap increments never lose an update
+#[test]
+fn test_compare_and_swap_concurrent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, u64>::open(temp_dir.path())?;
+    store.set("counter".to_string(), 0)?;
+
+    let handles = (0..2)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..500 {
+                    loop {
+                        let curr = store.get("counter".to_string())?;
+                        let next = curr.unwrap() + 1;
+                        if store.compare_and_swap("counter".to_string(), curr, next)? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(store.get("counter".to_string())?, Some(1000));
+    Ok(())
+}
+
+// Test that concurrent merges never lose an increment
+#[test]
+fn test_merge_concurrent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, u64>::open(temp_dir.path())?;
+
+    let handles = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..250 {
+                    store.merge("counter".to_string(), |curr| curr.unwrap_or(0) + 1)?;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(store.get("counter".to_string())?, Some(2000));
+    assert_eq!(store.merge("counter".to_string(), |curr| curr.unwrap() * 2)?, 4000);
+    Ok(())
+}
+
+// Test that iter yields every live pair in key order
+#[test]
+fn test_iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<u32, String>::open(temp_dir.path())?;
+    assert_eq!(store.iter().count(), 0);
+
+    for key in [5, 3, 9, 1, 7] {
+        store.set(key, format!("value{}", key))?;
+    }
+    store.set(3, "updated".to_string())?;
+    store.remove(9)?;
+
+    let pairs = store.iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs, vec![
+        (1, "value1".to_string()),
+        (3, "updated".to_string()),
+        (5, "value5".to_string()),
+        (7, "value7".to_string()),
+    ]);
+
+    Ok(())
+}
+
+// Test that scan_rev yields the pairs of its range in descending key order
+#[test]
+fn test_scan_rev() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<u32, u32>::open(temp_dir.path())?;
+    assert_eq!(store.scan_rev(..).count(), 0);
+
+    for key in 1..50 {
+        store.set(key, key * 10)?;
+    }
+    store.remove(15)?;
+
+    let keys = store.scan_rev(..).map(|pair| pair.map(|(key, _)| key)).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, (1..50).filter(|key| *key != 15).rev().collect::<Vec<_>>());
+
+    let pairs = store.scan_rev(10..20).collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs, [19, 18, 17, 16, 14, 13, 12, 11, 10].map(|key| (key, key * 10)));
+
+    // the latest n keys, reading only those
+    let latest = store.scan_rev(..).take(3).collect::<Result<Vec<_>>>()?;
+    assert_eq!(latest, vec![(49, 490), (48, 480), (47, 470)]);
+
+    Ok(())
+}
@@ -1,6 +1,7 @@
-use std::{sync::{Arc, Barrier}, thread};
+use std::{fs, io::Write, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
+use std::sync::{Arc, Barrier, atomic::{AtomicBool, Ordering}};
 
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{BincodeCodec, Compression, Durability, Entry, Error, Event, KvStore, KvsEngine, MetricsSnapshot, Op, Result, SegmentLayout, StoreOptions};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -81,6 +82,77 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// remove_and_get should hand back the removed value, and None without writing anything once
+// the key is gone
+#[test]
+fn remove_and_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store.remove_and_get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    let len = fs::metadata(temp_dir.path().join("1.log"))?.len();
+    assert_eq!(store.remove_and_get("key1".to_owned())?, None);
+    assert_eq!(store.remove_and_get("key2".to_owned())?, None);
+    assert_eq!(fs::metadata(temp_dir.path().join("1.log"))?.len(), len);
+
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// remove_if_exists should report whether the key was there, without writing a tombstone for a
+// missing key
+#[test]
+fn remove_if_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl("key2".to_owned(), "value2".to_owned(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+
+    assert!(store.remove_if_exists("key1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    let len = fs::metadata(temp_dir.path().join("1.log"))?.len();
+    assert!(!store.remove_if_exists("key1".to_owned())?);
+    assert!(!store.remove_if_exists("key2".to_owned())?);
+    assert!(!store.remove_if_exists("key3".to_owned())?);
+    assert_eq!(fs::metadata(temp_dir.path().join("1.log"))?.len(), len);
+
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    assert!(store.contains_key("key1".to_owned())?);
+    assert!(!store.contains_key("key2".to_owned())?);
+    assert!(!store.contains_key("key3".to_owned())?);
+    assert!(store.remove("key2".to_owned()).is_err());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert!(store.contains_key("key1".to_owned())?);
+    assert!(!store.contains_key("key2".to_owned())?);
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -127,6 +199,159 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Compaction should keep working when triggered several times without reopening the store.
+#[test]
+fn repeated_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..200 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.set("key1000".to_owned(), "value".to_owned())?;
+
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("199".to_owned()));
+    }
+    assert_eq!(store.get("key1000".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// A torn write at the tail of a log file should not prevent reopening the store.
+#[test]
+fn reopen_with_truncated_entry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    fs::OpenOptions::new().write(true).open(&log)?.set_len(len - 3)?;
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    for i in 0..4 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    // the store keeps working on top of the damaged file
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Reopening should cut off a torn write at the tail of a log file and keep everything before it
+#[test]
+fn reopen_repairs_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    // the start of an entry of 1000 bytes, of which only a few made it to disk
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    let mut torn = 1000u32.to_be_bytes().to_vec();
+    torn.extend_from_slice(&[0xab; 12]);
+    let mut file = fs::OpenOptions::new().append(true).open(&log)?;
+    file.write_all(&torn)?;
+    drop(file);
+
+    // a read-only open replays around the damage without touching the file
+    let store = KvStore::<String, String>::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    drop(store);
+    assert_eq!(fs::metadata(&log)?.len(), len + torn.len() as u64);
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log)?.len(), len);
+    for i in 0..5 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    drop(store);
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log)?.len(), len);
+    for i in 0..6 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// An entry whose payload does not match its checksum should not be replayed.
+#[test]
+fn reopen_with_corrupt_entry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    let last = content.len() - 1;
+    content[last] ^= 0xff;
+    fs::write(&log, content)?;
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Damage anywhere but at the tail should fail the open and point at the bad entry
+#[test]
+fn reopen_with_corrupt_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // the header is followed by frames of a 4 byte length, a 4 byte checksum and the payload
+    let log = temp_dir.path().join("1.log");
+    let content = fs::read(&log)?;
+    let first_len = u32::from_be_bytes(content[8..12].try_into().unwrap()) as u64;
+    let second = 8 + 8 + first_len;
+
+    let mut damaged = content.clone();
+    damaged[second as usize + 8] ^= 0xff;
+    fs::write(&log, &damaged)?;
+    match KvStore::<String, String>::open(temp_dir.path()) {
+        Err(Error::CorruptLog{file_id, offset}) => assert_eq!((file_id, offset), (1, second)),
+        res => panic!("expected a corrupt log error, got {:?}", res.err()),
+    }
+
+    // an entry with a valid checksum that does not decode is just as corrupt
+    let payload = b"not an entry";
+    let mut damaged = content.clone();
+    damaged.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    damaged.extend_from_slice(&crc32fast::hash(payload).to_be_bytes());
+    damaged.extend_from_slice(payload);
+    fs::write(&log, &damaged)?;
+    match KvStore::<String, String>::open(temp_dir.path()) {
+        Err(Error::CorruptLog{file_id, offset}) => assert_eq!((file_id, offset), (1, content.len() as u64)),
+        res => panic!("expected a corrupt log error, got {:?}", res.err()),
+    }
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -209,3 +434,1660 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Gets racing removes and sets of the same key should see either value or no value, never fail
+#[test]
+fn concurrent_get_and_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    let done = Arc::new(AtomicBool::new(false));
+
+    let mut writers = Vec::new();
+    for _ in 0..4 {
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        writers.push(thread::spawn(move || -> Result<()> {
+            while !done.load(Ordering::SeqCst) {
+                store.set("key".to_owned(), "value".to_owned())?;
+                match store.remove("key".to_owned()) {
+                    Ok(_) | Err(Error::DoesNotExist{..}) => {},
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(())
+        }));
+    }
+
+    let mut readers = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        readers.push(thread::spawn(move || -> Result<()> {
+            for _ in 0..100_000 {
+                let val = store.get("key".to_owned())?;
+                assert!(val.is_none() || val.as_deref() == Some("value"));
+            }
+            Ok(())
+        }));
+    }
+
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    done.store(true, Ordering::SeqCst);
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+
+    Ok(())
+}
+
+// A batch takes the writer lock and flushes once for all of its entries. Loading 10k pairs this
+// way is several times faster than 10k individual sets, which flush after every entry.
+#[test]
+fn set_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let entries = (0..10000)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect::<Vec<_>>();
+    store.set_batch(entries)?;
+    for i in 0..10000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // later pairs in a batch win over earlier ones
+    store.set_batch(vec![
+        ("key0".to_owned(), "first".to_owned()),
+        ("key0".to_owned(), "second".to_owned()),
+    ])?;
+    assert_eq!(store.get("key0".to_owned())?, Some("second".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("second".to_owned()));
+    for i in 1..10000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// With a sweep interval, expired keys should leave the index without ever being read, and the
+// sweeper should not keep the store open once it is dropped
+#[test]
+fn ttl_sweeper() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions{ttl_sweep_interval: Some(Duration::from_millis(50)), ..StoreOptions::default()};
+    let store = KvStore::<String, String>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+    for i in 0..10 {
+        store.set_with_ttl(format!("key{}", i), "value".to_owned(), Duration::from_millis(100))?;
+    }
+    store.set("forever".to_owned(), "value".to_owned())?;
+    assert_eq!(store.len(), 11);
+
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.stats()?.live_keys, 1);
+    assert!(store.uncompacted_bytes() > 0);
+
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+
+    // without the interval, nothing is swept until asked to
+    store.set_with_ttl("key".to_owned(), "value".to_owned(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.sweep_expired()?, 1);
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.scan(..)?, vec![("forever".to_owned(), "value".to_owned())]);
+
+    Ok(())
+}
+
+// A key set with a ttl should be visible until it expires, and stay gone after a reopen
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("short".to_owned(), "value1".to_owned(), Duration::from_millis(300))?;
+    store.set_with_ttl("long".to_owned(), "value2".to_owned(), Duration::from_secs(3600))?;
+    store.set("forever".to_owned(), "value3".to_owned())?;
+
+    assert_eq!(store.get("short".to_owned())?, Some("value1".to_owned()));
+    assert!(store.contains_key("short".to_owned())?);
+
+    // Open from disk again before the key expires
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert!(!store.contains_key("short".to_owned())?);
+    assert!(store.remove("short".to_owned()).is_err());
+    assert_eq!(
+        store.scan(..)?,
+        vec![("forever".to_owned(), "value3".to_owned()), ("long".to_owned(), "value2".to_owned())]
+    );
+
+    // Open from disk again after the key expired
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("forever".to_owned())?, Some("value3".to_owned()));
+
+    // an expired key can be set again
+    assert_eq!(store.set_and_get("short".to_owned(), "value4".to_owned())?, None);
+    assert_eq!(store.get("short".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// A manual compaction should reclaim every stale byte without losing any key
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    assert!(store.uncompacted_bytes() > 0);
+
+    store.compact()?;
+    assert_eq!(store.uncompacted_bytes(), 0);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.uncompacted_bytes(), 0);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Disk usage should count every log file, growing with writes whether they are live or not and
+// shrinking once a compaction drops the dead entries
+#[test]
+fn disk_usage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let empty = store.disk_usage()?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let written = store.disk_usage()?;
+    assert!(written > empty);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    for key_id in 0..50 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    let rewritten = store.disk_usage()?;
+    assert!(rewritten > written);
+    assert_eq!(rewritten, store.stats()?.disk_bytes);
+
+    store.compact()?;
+    let compacted = store.disk_usage()?;
+    assert!(compacted < written);
+
+    Ok(())
+}
+
+// Removes should count both the removed entry and their own tombstone as stale, as replay does,
+// so that remove-heavy traffic alone starts a compaction
+#[test]
+fn remove_counts_tombstones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+        store.remove(format!("key{}", key_id))?;
+    }
+    let uncompacted = store.uncompacted_bytes();
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.uncompacted_bytes(), uncompacted);
+
+    // keys removed while a compaction copies them leave only their copy and tombstone behind
+    store.compact_with(Arc::new(AtomicBool::new(false)), |_, _| ())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.compact_with(Arc::new(AtomicBool::new(false)), |copied, _| {
+        store.remove(format!("key{}", copied - 1)).unwrap();
+    })?;
+    let uncompacted = store.uncompacted_bytes();
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.uncompacted_bytes(), uncompacted);
+
+    // the sets alone stay below the 1 MiB threshold, the tombstones take them past it
+    let key = "k".repeat(1000);
+    for key_id in 0..600 {
+        store.set(format!("{}{}", key, key_id), String::new())?;
+        store.remove(format!("{}{}", key, key_id))?;
+    }
+    let deadline = SystemTime::now() + Duration::from_secs(10);
+    while store.metrics().compactions == 0 {
+        assert!(SystemTime::now() < deadline, "remove traffic never started a compaction");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Ok(())
+}
+
+// Cancelling a compaction midway should delete its partial output and leave the store reading
+// from the old files, and a later compaction should still reclaim everything
+#[test]
+fn cancel_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_files = || -> Vec<String> {
+        let mut names = fs::read_dir(temp_dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".log"))
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    let uncompacted = store.uncompacted_bytes();
+    let before = log_files();
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let mut reported = Vec::new();
+    let res = store.compact_with(Arc::clone(&cancel), |copied, total| {
+        reported.push((copied, total));
+        if copied == 50 {
+            cancel.store(true, Ordering::SeqCst);
+        }
+    });
+    assert!(matches!(res, Err(Error::Cancelled)));
+    assert_eq!(reported, (1..=50).map(|copied| (copied, 99)).collect::<Vec<_>>());
+    assert_eq!(store.uncompacted_bytes(), uncompacted);
+    // the old files are all there, next to the new active file but without any output file
+    let after = log_files();
+    assert_eq!(after.len(), before.len() + 1);
+    assert!(before.iter().all(|name| after.contains(name)));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+    store.set("key1".to_owned(), "10".to_owned())?;
+
+    cancel.store(false, Ordering::SeqCst);
+    let mut last = (0, 0);
+    store.compact_with(cancel, |copied, total| last = (copied, total))?;
+    assert_eq!(last, (99, 99));
+    assert_eq!(store.uncompacted_bytes(), 0);
+
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("10".to_owned()));
+    for key_id in 2..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A compaction failing midway, here on reading a log file that was cut short under the store,
+// should delete its partial output and give back the stale bytes it was going to reclaim
+#[test]
+fn failed_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_files = || -> Vec<String> {
+        let mut names = fs::read_dir(temp_dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".log"))
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+    // the next open writes to a new file and reads 1.log as it was
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    let uncompacted = store.uncompacted_bytes();
+    assert!(uncompacted > 0);
+    let file = fs::OpenOptions::new().write(true).open(temp_dir.path().join("1.log"))?;
+    file.set_len(file.metadata()?.len() - 100)?;
+    let before = log_files();
+
+    let mut copied = 0;
+    let err = store.compact_with(Arc::new(AtomicBool::new(false)), |n, _| copied = n).unwrap_err();
+    assert!(copied > 0);
+    assert!(err.to_string().contains("compacting log file 1"), "{}", err);
+    assert_eq!(store.uncompacted_bytes(), uncompacted);
+    // only the new active file was added, the partial output file is gone
+    let after = log_files();
+    assert_eq!(after.len(), before.len() + 1);
+    assert!(before.iter().all(|name| after.contains(name)));
+    assert_eq!(store.get("key0".to_owned())?, Some("9".to_owned()));
+
+    Ok(())
+}
+
+// Writers and readers should keep succeeding while compactions run in the background
+#[test]
+fn concurrent_writes_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for iter in 0..200 {
+                for key_id in 0..250 {
+                    let key = format!("key{}_{}", thread_id, key_id);
+                    store.set(key.clone(), format!("{}", iter))?;
+                    assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+                }
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    for thread_id in 0..4 {
+        for key_id in 0..250 {
+            assert_eq!(store.get(format!("key{}_{}", thread_id, key_id))?, Some("199".to_owned()));
+        }
+    }
+
+    // dropping the store waits for a running compaction, the first log file must be gone by then
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    // Open from disk again and check persistent data
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    for thread_id in 0..4 {
+        for key_id in 0..250 {
+            assert_eq!(store.get(format!("key{}_{}", thread_id, key_id))?, Some("199".to_owned()));
+        }
+    }
+
+    Ok(())
+}
+
+// Handles that have not opened a log file yet should still be able to read it while another
+// thread keeps compacting it away
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let compactor = {
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        thread::spawn(move || -> Result<()> {
+            for iter in 0..50 {
+                store.set("other".to_owned(), format!("{}", iter))?;
+                store.compact()?;
+            }
+            done.store(true, Ordering::SeqCst);
+            Ok(())
+        })
+    };
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        handles.push(thread::spawn(move || -> Result<()> {
+            let mut i = thread_id;
+            while !done.load(Ordering::SeqCst) {
+                // a fresh clone starts without any open file
+                let store = store.clone();
+                let key_id = i % 100;
+                assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+                i += 1;
+            }
+            Ok(())
+        }));
+    }
+
+    compactor.join().unwrap()?;
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    Ok(())
+}
+
+// A handle that keeps reading a key should never see an error while compactions move the key
+// between files and delete the files it was read from before, also when the handle closes its
+// readers to stay within max_open_readers
+#[test]
+fn get_during_compaction_keeps_reading() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions{max_open_readers: Some(1), ..StoreOptions::default()};
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+    store.set("key".to_owned(), "0".to_owned())?;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        thread::spawn(move || -> Result<()> {
+            for iter in 1..=50 {
+                store.set("key".to_owned(), format!("{}", iter))?;
+                store.compact()?;
+            }
+            done.store(true, Ordering::SeqCst);
+            Ok(())
+        })
+    };
+
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        handles.push(thread::spawn(move || -> Result<()> {
+            let mut last = 0;
+            while !done.load(Ordering::SeqCst) {
+                let val = store.get("key".to_owned())?.expect("key is never removed");
+                let val = val.parse::<u32>().unwrap();
+                assert!(val >= last);
+                last = val;
+            }
+            Ok(())
+        }));
+    }
+
+    writer.join().unwrap()?;
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("key".to_owned())?, Some("50".to_owned()));
+
+    Ok(())
+}
+
+// Clearing the store should remove every key, also after a reopen
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    store.clear()?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    assert!(store.scan(..)?.is_empty());
+
+    // keys written after the clear are kept
+    store.set("key1".to_owned(), "new".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.scan(..)?, vec![("key1".to_owned(), "new".to_owned())]);
+
+    // compaction drops everything written before the clear
+    store.compact()?;
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.scan(..)?, vec![("key1".to_owned(), "new".to_owned())]);
+    assert_eq!(store.uncompacted_bytes(), 0);
+
+    Ok(())
+}
+
+// A prefix scan should return exactly the keys starting with the prefix, in order
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:2".to_owned(), "bob".to_owned())?;
+    store.set("order:1".to_owned(), "book".to_owned())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("user".to_owned(), "none".to_owned())?;
+    store.set("user;1".to_owned(), "none".to_owned())?;
+
+    assert_eq!(
+        store.scan_prefix("user:")?,
+        vec![("user:1".to_owned(), "alice".to_owned()), ("user:2".to_owned(), "bob".to_owned())]
+    );
+    assert_eq!(store.scan_prefix("order:")?, vec![("order:1".to_owned(), "book".to_owned())]);
+    assert!(store.scan_prefix("item:")?.is_empty());
+    assert_eq!(store.scan_prefix("")?.len(), 5);
+
+    store.remove("user:1".to_owned())?;
+    assert_eq!(store.scan_prefix("user:")?, vec![("user:2".to_owned(), "bob".to_owned())]);
+
+    Ok(())
+}
+
+#[test]
+fn compressed_compaction() -> Result<()> {
+    let dir_size = |dir: &TempDir| -> u64 {
+        WalkDir::new(dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum()
+    };
+    let fill = |store: &KvStore<String, String>| -> Result<()> {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("value{} ", key_id).repeat(50))?;
+        }
+        store.compact()
+    };
+
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let plain = KvStore::open(plain_dir.path())?;
+    fill(&plain)?;
+
+    let compressed_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions{compression: Some(Compression::Zstd(0)), ..StoreOptions::default()};
+    let compressed = KvStore::open_with_options(compressed_dir.path(), BincodeCodec, options)?;
+    fill(&compressed)?;
+
+    assert!(dir_size(&compressed_dir) < dir_size(&plain_dir));
+    for key_id in 0..200 {
+        assert_eq!(compressed.get(format!("key{}", key_id))?, Some(format!("value{} ", key_id).repeat(50)));
+    }
+
+    // compressed files stay readable without the option
+    drop(compressed);
+    let store = KvStore::<String, String>::open(compressed_dir.path())?;
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{} ", key_id).repeat(50)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 8);
+    assert_eq!(stats.segments, 1);
+    assert!(stats.uncompacted_bytes > 0);
+    assert_eq!(stats.uncompacted_bytes, store.uncompacted_bytes());
+    assert!(stats.disk_bytes > stats.uncompacted_bytes);
+
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 8);
+    assert_eq!(stats.segments, 2);
+    assert_eq!(stats.uncompacted_bytes, 0);
+
+    Ok(())
+}
+
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = KvStore::<String, String>::open(&temp_dir.path().join("source"))?;
+    for key_id in 0..100 {
+        source.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    source.set("key0".to_owned(), "updated".to_owned())?;
+    source.remove("key1".to_owned())?;
+    source.set_with_ttl("temporary".to_owned(), "value".to_owned(), Duration::from_secs(3600))?;
+
+    let export = temp_dir.path().join("export");
+    source.export(&export)?;
+
+    let target = KvStore::<String, String>::open(&temp_dir.path().join("target"))?;
+    target.import(&export)?;
+    assert_eq!(target.scan(..)?, source.scan(..)?);
+    assert_eq!(target.get("key0".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(target.get("key1".to_owned())?, None);
+
+    // a file that is not an export is rejected
+    assert!(target.import(&temp_dir.path().join("source").join("1.log")).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let snapshot = store.snapshot()?;
+    store.set("key0".to_owned(), "updated".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    store.compact()?;
+
+    assert_eq!(snapshot.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key10".to_owned())?, None);
+    assert_eq!(snapshot.scan(..)?.len(), 10);
+    assert_eq!(store.get("key0".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // the files compaction retired stay until the snapshot is gone
+    let log_files = || fs::read_dir(temp_dir.path()).unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "log"))
+        .count();
+    assert_eq!(log_files(), 3);
+    drop(snapshot);
+    assert_eq!(log_files(), 2);
+
+    Ok(())
+}
+
+// An iterator should keep reading correct values across a compaction
+#[test]
+fn iter_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+    }
+
+    let mut iter = store.iter();
+    let mut pairs = iter.by_ref().take(10).collect::<Result<Vec<_>>>()?;
+    store.compact()?;
+    pairs.extend(iter.collect::<Result<Vec<_>>>()?);
+
+    let expected = (0..100)
+        .map(|key_id| (format!("key{:03}", key_id), format!("value{}", key_id)))
+        .collect::<Vec<_>>();
+    assert_eq!(pairs, expected);
+
+    Ok(())
+}
+
+// Writers of different shards should not lose or reorder each other's writes, through
+// compactions, a clear and reopening with a different number of shards
+#[test]
+fn sharded_writers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions{shards: 4, ..StoreOptions::default()};
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+    store.set("cleared".to_owned(), "value".to_owned())?;
+    store.clear()?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for iter in 0..100 {
+                for key_id in 0..100 {
+                    store.set(format!("key{}_{}", thread_id, key_id), format!("{}", iter))?;
+                }
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    store.compact()?;
+    store.remove("key0_0".to_owned())?;
+
+    let check = |store: &KvStore<String, String>| -> Result<()> {
+        assert_eq!(store.get("cleared".to_owned())?, None);
+        assert_eq!(store.get("key0_0".to_owned())?, None);
+        for thread_id in 0..4 {
+            for key_id in 0..100 {
+                if (thread_id, key_id) != (0, 0) {
+                    assert_eq!(store.get(format!("key{}_{}", thread_id, key_id))?, Some("99".to_owned()));
+                }
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// Every durability level should keep the writes it acknowledged across a reopen
+#[test]
+fn durability_levels() -> Result<()> {
+    let group_commit = Durability::GroupCommit{window: Duration::from_millis(1), max_writes: 8};
+    for durability in [Durability::None, Durability::Flush, Durability::Fsync, group_commit] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = StoreOptions{durability, ..StoreOptions::default()};
+        let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set_batch(vec![("key2".to_owned(), "value2".to_owned()), ("key3".to_owned(), "value3".to_owned())])?;
+        store.remove("key3".to_owned())?;
+        store.compact()?;
+        store.set("key1".to_owned(), "value4".to_owned())?;
+        drop(store);
+
+        let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+    }
+
+    Ok(())
+}
+
+// Concurrent writers should all be acknowledged under group commit, and their writes should be
+// on disk once they are
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions{
+        durability: Durability::GroupCommit{window: Duration::from_millis(1), max_writes: 8},
+        ..StoreOptions::default()
+    };
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..16 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for key_id in 0..20 {
+                store.set(format!("key{}_{}", thread_id, key_id), format!("value{}", key_id))?;
+            }
+            store.remove(format!("key{}_0", thread_id))?;
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    drop(store);
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    for thread_id in 0..16 {
+        assert_eq!(store.get(format!("key{}_0", thread_id))?, None);
+        for key_id in 1..20 {
+            assert_eq!(store.get(format!("key{}_{}", thread_id, key_id))?, Some(format!("value{}", key_id)));
+        }
+    }
+
+    Ok(())
+}
+
+// Lookups should give the same answers with bloom filters, through compaction and reopening
+#[test]
+fn bloom_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions{bloom_filters: true, ..StoreOptions::default()};
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options.clone())?;
+    for key_id in 0..3000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set_batch(vec![("batch1".to_owned(), "value1".to_owned()), ("batch2".to_owned(), "value2".to_owned())])?;
+    store.remove("key0".to_owned())?;
+
+    let check = |store: &KvStore<String, String>| -> Result<()> {
+        assert!(!store.contains_key("key0".to_owned())?);
+        assert_eq!(store.get("missing".to_owned())?, None);
+        assert_eq!(store.get("batch2".to_owned())?, Some("value2".to_owned()));
+        for key_id in 1..3000 {
+            assert!(store.contains_key(format!("key{}", key_id))?);
+        }
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), BincodeCodec, options)?)?;
+
+    Ok(())
+}
+
+// The keys inserted first should be evicted once the store holds more than max_keys
+#[test]
+fn max_keys_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions{max_keys: Some(3), ..StoreOptions::default()};
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options.clone())?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let check = |store: &KvStore<String, String>| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key1".to_owned())?, None);
+        for key_id in 2..5 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    // overwriting keeps a key's place, the oldest key is still the first to go
+    store.set("key2".to_owned(), "updated".to_owned())?;
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.stats()?.live_keys, 3);
+
+    drop(store);
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+    store.set("key6".to_owned(), "value6".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.scan(..)?.len(), 3);
+
+    Ok(())
+}
+
+// The keys inserted first should be evicted once the live entries exceed max_disk_bytes
+#[test]
+fn max_disk_bytes_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions{max_disk_bytes: Some(10 * 1024), ..StoreOptions::default()};
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "x".repeat(1000))?;
+    }
+
+    let live = store.scan(..)?;
+    assert!(live.len() < 10);
+    assert!(live.iter().all(|(key, _)| key.as_str() >= "key90"));
+    assert_eq!(store.get("key99".to_owned())?, Some("x".repeat(1000)));
+
+    Ok(())
+}
+
+// Writers should move to new log files once theirs reach segment_max_bytes, and every key
+// should keep resolving to its latest value across the files, also after a reopen
+#[test]
+fn segment_rollover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions{segment_max_bytes: Some(1024), shards: 2, ..StoreOptions::default()};
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options.clone())?;
+    for round in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}-{}", key_id, round))?;
+        }
+    }
+    store.set_batch((0..50).map(|key_id| (format!("batch{}", key_id), "value".to_owned())).collect())?;
+    for key_id in 0..100 {
+        store.remove_if_exists(format!("key{}", key_id * 2))?;
+    }
+
+    let check = |store: &KvStore<String, String, BincodeCodec>| -> Result<()> {
+        for key_id in 0..100 {
+            let expected = (key_id % 2 == 1).then(|| format!("value{}-2", key_id));
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        assert_eq!(store.scan("batch".to_owned().."batch~".to_owned())?.len(), 50);
+        Ok(())
+    };
+    check(&store)?;
+
+    let files = fs::read_dir(temp_dir.path())?.count();
+    assert!(files > 10, "expected many log files, found {}", files);
+    for entry in fs::read_dir(temp_dir.path())? {
+        let len = entry?.metadata()?.len();
+        // a batch may go beyond the limit, a single entry only by itself
+        assert!(len < 4 * 1024, "log file of {} bytes", len);
+    }
+
+    drop(store);
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Opening with compact_on_open should reclaim the stale entries found on open right away, while
+// a plain open leaves them until enough writes pile up
+#[test]
+fn compact_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = |round: usize| format!("{}", round).repeat(10 * 1024);
+    let check = |store: &KvStore<String, String, BincodeCodec>| -> Result<()> {
+        for key_id in 0..40 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(value(4)));
+        }
+        Ok(())
+    };
+    // spread over several shards, no shard piles up enough stale bytes to compact on its own
+    let options = StoreOptions{shards: 4, ..StoreOptions::default()};
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+    for round in 0..5 {
+        for key_id in 0..40 {
+            store.set(format!("key{}", key_id), value(round))?;
+        }
+    }
+    drop(store);
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    check(&store)?;
+    let seeded = store.disk_usage()?;
+    assert!(store.uncompacted_bytes() > 1024 * 1024);
+    drop(store);
+
+    let options = StoreOptions{compact_on_open: true, ..StoreOptions::default()};
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options.clone())?;
+    assert_eq!(store.uncompacted_bytes(), 0);
+    assert!(store.disk_usage()? < seeded / 3, "{} bytes left of {}", store.disk_usage()?, seeded);
+    check(&store)?;
+    assert_eq!(store.metrics().compactions, 1);
+
+    // nothing to reclaim, nothing to compact
+    drop(store);
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+    assert_eq!(store.metrics().compactions, 0);
+    check(&store)?;
+
+    Ok(())
+}
+
+// Stores should read and write through buffers of any size, values larger than the buffer
+// included, and files written with one size should read back with another
+#[test]
+fn buffer_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = |key_id: usize| -> String {
+        (0..256 * 1024).map(|i| char::from(b'a' + ((i + key_id) % 26) as u8)).collect()
+    };
+    for (round, buffer_size) in [(0, 64), (1, 1024 * 1024)] {
+        let options = StoreOptions{buffer_size, ..StoreOptions::default()};
+        let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+        for key_id in 0..4 {
+            store.set(format!("key{}-{}", round, key_id), value(key_id))?;
+        }
+        for key_id in 0..4 {
+            for written in 0..=round {
+                assert_eq!(store.get(format!("key{}-{}", written, key_id))?, Some(value(key_id)));
+            }
+        }
+        store.compact()?;
+        assert_eq!(store.get(format!("key{}-3", round))?, Some(value(3)));
+    }
+
+    Ok(())
+}
+
+// A fan-out layout should put every log file in the subdirectory its id picks, find them all
+// again on reopen, and files should move into place when the layout changes between opens
+#[test]
+fn segment_fanout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // (subdirectory, file name) of every log file
+    let log_files = || -> Vec<(Option<String>, String)> {
+        WalkDir::new(temp_dir.path()).min_depth(1).into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().is_file() && entry.path().extension().is_some_and(|ext| ext == "log"))
+            .map(|entry| {
+                let parent = entry.path().parent().unwrap();
+                let subdir = (parent != temp_dir.path()).then(|| parent.file_name().unwrap().to_str().unwrap().to_owned());
+                (subdir, entry.file_name().to_str().unwrap().to_owned())
+            })
+            .collect()
+    };
+    let fanout = StoreOptions{segment_max_bytes: Some(512), segment_layout: SegmentLayout::FanOut(4), ..StoreOptions::default()};
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, fanout.clone())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let check = |store: &KvStore<String, String, BincodeCodec>| -> Result<()> {
+        for key_id in 0..200 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    let files = log_files();
+    assert!(files.len() > 10, "expected many log files, found {}", files.len());
+    for (subdir, name) in &files {
+        let file_id = name.trim_end_matches(".log").parse::<u32>().unwrap();
+        assert_eq!(subdir.as_deref(), Some((file_id % 4).to_string().as_str()), "{} is in the wrong place", name);
+    }
+
+    drop(store);
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, fanout.clone())?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+
+    // the files of a fan-out store are moved back up by a flat open, a read-only open cannot
+    drop(store);
+    let res = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, StoreOptions{read_only: true, ..StoreOptions::default()});
+    assert!(res.is_err());
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    check(&store)?;
+    assert!(log_files().iter().all(|(subdir, _)| subdir.is_none()));
+
+    Ok(())
+}
+
+// flush should make the writes of a store that syncs nothing on its own durable, including
+// those in files its writers have moved on from
+#[test]
+fn flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions{durability: Durability::None, segment_max_bytes: Some(512), ..StoreOptions::default()};
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.flush()?;
+    // flushing again with nothing written is harmless
+    store.flush()?;
+    drop(store);
+
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+
+    Ok(())
+}
+
+// Reads across more log files than max_open_readers should reopen the files they need rather
+// than keep all of them open
+#[test]
+fn max_open_readers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // every open starts a new log file
+    for file in 0..30 {
+        let store = KvStore::<String, String>::open(temp_dir.path())?;
+        for key_id in 0..10 {
+            store.set(format!("key{}-{}", file, key_id), format!("value{}-{}", file, key_id))?;
+        }
+    }
+
+    let options = StoreOptions{max_open_readers: Some(4), ..StoreOptions::default()};
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+    assert!(store.open_readers() <= 4);
+    for _ in 0..3 {
+        for file in 0..30 {
+            for key_id in 0..10 {
+                assert_eq!(store.get(format!("key{}-{}", file, key_id))?, Some(format!("value{}-{}", file, key_id)));
+            }
+            assert!(store.open_readers() <= 4);
+        }
+    }
+
+    // the active file stays open and readable while the others are swapped out
+    store.set("active".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key0-0".to_owned())?, Some("value0-0".to_owned()));
+    assert_eq!(store.get("active".to_owned())?, Some("value".to_owned()));
+    assert!(store.open_readers() <= 4);
+
+    Ok(())
+}
+
+// A store opened read-only should serve reads, refuse writes and leave its directory untouched
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let list_files = || {
+        let mut files = fs::read_dir(temp_dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    };
+    let before = list_files();
+
+    let store = KvStore::<String, String>::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.scan(..)?, vec![("key1".to_owned(), "value1".to_owned())]);
+    assert!(store.uncompacted_bytes() > 0);
+
+    assert!(matches!(store.set("key3".to_owned(), "value3".to_owned()), Err(Error::ReadOnly)));
+    assert!(matches!(store.remove("key1".to_owned()), Err(Error::ReadOnly)));
+    assert!(matches!(store.set_batch(vec![("key3".to_owned(), "value3".to_owned())]), Err(Error::ReadOnly)));
+    assert!(matches!(store.compact(), Err(Error::ReadOnly)));
+    assert!(matches!(store.clear(), Err(Error::ReadOnly)));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(list_files(), before);
+
+    // a missing store is not created
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::<String, String>::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+
+    Ok(())
+}
+
+// Dumping a directory should list every entry written, in order and with its location
+#[test]
+fn dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+    // the next open writes to a new file
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let mut records = Vec::new();
+    KvStore::<String, String>::dump(temp_dir.path(), &BincodeCodec, |record| {
+        records.push(record);
+        Ok(())
+    })?;
+
+    let entries = records.iter().map(|record| match &record.entry {
+        Entry::Set{key, val, ..} => format!("{} set {} {}", record.file_id, key, val),
+        Entry::Rm{key} => format!("{} rm {}", record.file_id, key),
+        Entry::Clear => format!("{} clear", record.file_id),
+        Entry::Begin => format!("{} begin", record.file_id),
+        Entry::Commit => format!("{} commit", record.file_id),
+    }).collect::<Vec<_>>();
+    assert_eq!(entries, vec!["1 set key1 value1", "1 set key2 value2", "1 rm key1", "2 set key2 value3"]);
+
+    // the entries of a file follow each other right after its header
+    for pair in records.windows(2).filter(|pair| pair[0].file_id == pair[1].file_id) {
+        assert_eq!(pair[0].end, pair[1].start);
+    }
+    let len = fs::metadata(temp_dir.path().join("1.log"))?.len();
+    assert_eq!(records[2].end, len);
+
+    Ok(())
+}
+
+// The log-order iterator should replay the exact sequence of sets and removes of an open store,
+// leaving out writes made after it was created, and keep its files through a compaction
+#[test]
+fn iter_log_order() -> Result<()> {
+    fn describe(entry: Result<Entry<String, String>>) -> String {
+        match entry.unwrap() {
+            Entry::Set{key, val, ..} => format!("set {} {}", key, val),
+            Entry::Rm{key} => format!("rm {}", key),
+            Entry::Clear => "clear".to_owned(),
+            Entry::Begin => "begin".to_owned(),
+            Entry::Commit => "commit".to_owned(),
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let entries = store.iter_log_order();
+    store.set("key3".to_owned(), "value5".to_owned())?;
+    store.compact()?;
+    assert_eq!(entries.map(describe).collect::<Vec<_>>(), vec![
+        "set key2 value1", "set key1 value2", "rm key2", "set key2 value3", "set key1 value4", "rm key1",
+    ]);
+
+    // the compaction copied the live keys in key order, removes are gone
+    store.set("key3".to_owned(), "value6".to_owned())?;
+    assert_eq!(store.iter_log_order().map(describe).collect::<Vec<_>>(), vec![
+        "set key2 value3", "set key3 value5", "set key3 value6",
+    ]);
+
+    Ok(())
+}
+
+#[test]
+fn keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert!(store.keys().is_empty());
+
+    for key in ["key3", "key1", "key4", "key2"] {
+        store.set(key.to_owned(), "value".repeat(1000))?;
+    }
+    store.remove("key2".to_owned())?;
+    store.set_with_ttl("key0".to_owned(), "value".to_owned(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+
+    assert_eq!(store.keys(), vec!["key1".to_owned(), "key3".to_owned(), "key4".to_owned()]);
+
+    Ok(())
+}
+
+#[test]
+fn first_and_last_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<u32, String>::open(temp_dir.path())?;
+    assert_eq!(store.first_key(), None);
+    assert_eq!(store.last_key(), None);
+
+    for key in [50, 10, 90, 30, 70] {
+        store.set(key, format!("value{}", key))?;
+    }
+    assert_eq!(store.first_key(), Some(10));
+    assert_eq!(store.last_key(), Some(90));
+
+    store.remove(10)?;
+    store.remove(90)?;
+    assert_eq!(store.first_key(), Some(30));
+    assert_eq!(store.last_key(), Some(70));
+
+    // expired keys at either end are not reported either
+    store.set_with_ttl(5, "value".to_owned(), Duration::from_millis(1))?;
+    store.set_with_ttl(95, "value".to_owned(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(store.first_key(), Some(30));
+    assert_eq!(store.last_key(), Some(70));
+
+    for key in [30, 50, 70] {
+        store.remove(key)?;
+    }
+    assert_eq!(store.first_key(), None);
+    assert_eq!(store.last_key(), None);
+
+    Ok(())
+}
+
+#[test]
+fn count_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<u32, String>::open(temp_dir.path())?;
+    assert_eq!(store.count_range(..), 0);
+
+    for key in 0..20 {
+        store.set(key, format!("value{}", key))?;
+    }
+    store.remove(7)?;
+    store.set_with_ttl(8, "value".to_owned(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+
+    assert_eq!(store.count_range(..), 18);
+    assert_eq!(store.count_range(5..10), 3);
+    assert_eq!(store.count_range(5..=10), 4);
+    assert_eq!(store.count_range(..5), 5);
+    assert_eq!(store.count_range(15..), 5);
+    assert_eq!(store.count_range(10..10), 0);
+    assert_eq!(store.count_range(7..9), 0);
+    assert_eq!(store.count_range(100..), 0);
+    assert_eq!(store.count_range(5..=10), store.scan(5..=10)?.len());
+
+    Ok(())
+}
+
+#[test]
+fn scan_page() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<u32, String>::open(temp_dir.path())?;
+    for key in 0..25 {
+        store.set(key, format!("value{}", key))?;
+    }
+
+    let mut pages = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = store.scan_page(cursor, 10)?;
+        cursor = page.last().map(|(key, _)| *key);
+        let last = page.len() < 10;
+        pages.push(page);
+        if last {
+            break;
+        }
+    }
+
+    assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), vec![10, 10, 5]);
+    assert_eq!(pages.concat(), store.scan(..)?);
+    assert_eq!(pages[1][0], (10, "value10".to_owned()));
+    assert!(store.scan_page(Some(24), 10)?.is_empty());
+    assert!(store.scan_page(None, 0)?.is_empty());
+
+    Ok(())
+}
+
+// The counters should reflect a known workload, and a custom sink should see the same events
+#[test]
+fn metrics() -> Result<()> {
+    #[derive(Debug, Default)]
+    struct Sink(kvs::AtomicMetrics);
+
+    impl kvs::Metrics for Sink {
+        fn record_get(&self, hit: bool) { self.0.record_get(hit) }
+        fn record_set(&self, bytes: u64) { self.0.record_set(bytes) }
+        fn record_remove(&self, bytes: u64) { self.0.record_remove(bytes) }
+        fn record_compaction(&self) { self.0.record_compaction() }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sink = Arc::new(Sink::default());
+    let options = StoreOptions{metrics: Some(sink.clone()), ..StoreOptions::default()};
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+    assert_eq!(store.metrics(), MetricsSnapshot::default());
+    let log_bytes = || -> u64 {
+        WalkDir::new(temp_dir.path()).into_iter()
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum()
+    };
+    let opened_bytes = log_bytes();
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set_batch(vec![("key10".to_owned(), "value10".to_owned()), ("key11".to_owned(), "value11".to_owned())])?;
+    store.remove("key0".to_owned())?;
+    assert!(store.remove("key0".to_owned()).is_err());
+    for key_id in 0..15 {
+        store.get(format!("key{}", key_id))?;
+    }
+    // every handle counts into the same counters
+    store.clone().get("key1".to_owned())?;
+    store.flush()?;
+    let written_bytes = log_bytes() - opened_bytes;
+    store.compact()?;
+
+    let expected = MetricsSnapshot{
+        gets: 16,
+        hits: 12,
+        misses: 4,
+        sets: 12,
+        removes: 1,
+        bytes_written: written_bytes,
+        compactions: 1,
+    };
+    assert_eq!(store.metrics(), expected);
+    assert_eq!(sink.0.snapshot(), expected);
+
+    // counting starts over with every open
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.metrics(), MetricsSnapshot::default());
+
+    Ok(())
+}
+
+// A directory should only be opened by one writable store at a time
+#[test]
+fn open_locks_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(temp_dir.path().join("LOCK").exists());
+
+    assert!(matches!(KvStore::<String, String>::open(temp_dir.path()), Err(Error::Locked(_))));
+    // reading files a writer may compact away is no safer
+    assert!(matches!(KvStore::<String, String>::open_read_only(temp_dir.path()), Err(Error::Locked(_))));
+
+    // the lock is held until the last handle is gone
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(KvStore::<String, String>::open(temp_dir.path()), Err(Error::Locked(_))));
+    drop(clone);
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // read-only stores share the lock, and keep writers out
+    let reader1 = KvStore::<String, String>::open_read_only(temp_dir.path())?;
+    let reader2 = KvStore::<String, String>::open_read_only(temp_dir.path())?;
+    assert_eq!(reader2.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(KvStore::<String, String>::open(temp_dir.path()), Err(Error::Locked(_))));
+    drop(reader1);
+    drop(reader2);
+    KvStore::<String, String>::open(temp_dir.path())?;
+
+    Ok(())
+}
+
+// A watcher should see every change of its key, in order, and nothing of other keys
+#[test]
+fn watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    let events = store.watch("key1".to_owned());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set_batch(vec![("key2".to_owned(), "value3".to_owned()), ("key1".to_owned(), "value4".to_owned())])?;
+    store.clear()?;
+
+    let timeout = Duration::from_secs(1);
+    assert_eq!(events.recv_timeout(timeout).unwrap(), Event::Set("value1".to_owned()));
+    assert_eq!(events.recv_timeout(timeout).unwrap(), Event::Removed);
+    assert_eq!(events.recv_timeout(timeout).unwrap(), Event::Set("value4".to_owned()));
+    assert_eq!(events.recv_timeout(timeout).unwrap(), Event::Removed);
+    assert!(events.try_recv().is_err());
+
+    // a dropped receiver is forgotten on the next change
+    drop(events);
+    store.set("key1".to_owned(), "value5".to_owned())?;
+
+    Ok(())
+}
+
+// All operations of a transaction should apply, in order, and survive a reopen
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    store.transaction(vec![
+        Op::Set("key1".to_owned(), "value1".to_owned()),
+        Op::Set("key2".to_owned(), "value2".to_owned()),
+        Op::Set("key3".to_owned(), "value3".to_owned()),
+        Op::Remove("key4".to_owned()),
+        Op::Set("key3".to_owned(), "value5".to_owned()),
+        // removing a missing key is not an error
+        Op::Remove("key6".to_owned()),
+    ])?;
+    store.transaction(Vec::new())?;
+
+    let check = |store: &KvStore<String, String>| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value5".to_owned()));
+        assert_eq!(store.get("key4".to_owned())?, None);
+        assert_eq!(store.get("key6".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// A crash before the commit marker is written should leave none of a transaction applied
+#[test]
+fn transaction_without_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.transaction(vec![
+        Op::Set("key1".to_owned(), "value2".to_owned()),
+        Op::Set("key2".to_owned(), "value2".to_owned()),
+        Op::Set("key3".to_owned(), "value3".to_owned()),
+    ])?;
+    drop(store);
+
+    // cut the file right before the commit marker, as if the store crashed while writing it
+    let mut commit = None;
+    KvStore::<String, String>::dump(temp_dir.path(), &BincodeCodec, |record| {
+        if let Entry::Commit = record.entry {
+            commit = Some((record.file_id, record.start));
+        }
+        Ok(())
+    })?;
+    let (file_id, start) = commit.expect("no commit marker written");
+    let file = fs::OpenOptions::new().write(true).open(temp_dir.path().join(format!("{}.log", file_id)))?;
+    file.set_len(start)?;
+    drop(file);
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    // the skipped entries are stale and the store goes on as usual
+    assert!(store.uncompacted_bytes() > 0);
+    store.set("key2".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// A transaction over keys of several shards should stay ordered with the writes of every shard
+#[test]
+fn sharded_transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions{shards: 4, ..StoreOptions::default()};
+    let store = KvStore::<String, String, _>::open_with_options(temp_dir.path(), BincodeCodec, options.clone())?;
+    let keys = (0..20).map(|key_id| format!("key{}", key_id)).collect::<Vec<_>>();
+    for key in &keys {
+        store.set(key.clone(), "before".to_owned())?;
+    }
+    store.transaction(keys.iter().map(|key| Op::Set(key.clone(), "during".to_owned())).collect())?;
+    for key in &keys[..10] {
+        store.set(key.clone(), "after".to_owned())?;
+    }
+
+    let check = |store: &KvStore<String, String>| -> Result<()> {
+        for (key_id, key) in keys.iter().enumerate() {
+            let expected = if key_id < 10 { "after" } else { "during" };
+            assert_eq!(store.get(key.clone())?, Some(expected.to_owned()));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), BincodeCodec, options)?)?;
+
+    Ok(())
+}
+
+// A versioned write should only go through while the key is at the version it expects
+#[test]
+fn set_if_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    let v1 = store.set("key1".to_owned(), "value1".to_owned())?;
+    let v2 = store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(v2 > v1);
+
+    // a stale version is rejected and writes nothing
+    assert!(!store.set_if_version("key1".to_owned(), "value3".to_owned(), v1)?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    // nothing else writes meanwhile, so the next version is the one after v2
+    assert!(store.set_if_version("key1".to_owned(), "value3".to_owned(), v2)?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert!(!store.set_if_version("key1".to_owned(), "value4".to_owned(), v2)?);
+    assert!(store.set_if_version("key1".to_owned(), "value4".to_owned(), v2 + 1)?);
+
+    // version 0 stands for a missing key
+    assert!(store.set_if_version("key2".to_owned(), "value1".to_owned(), 0)?);
+    assert!(!store.set_if_version("key2".to_owned(), "value2".to_owned(), 0)?);
+    store.remove("key2".to_owned())?;
+    assert!(!store.set_if_version("key2".to_owned(), "value3".to_owned(), v2 + 3)?);
+    assert!(store.set_if_version("key2".to_owned(), "value3".to_owned(), 0)?);
+
+    // versions survive compaction, and keep growing after a reopen
+    store.compact()?;
+    assert!(store.set_if_version("key1".to_owned(), "value5".to_owned(), v2 + 2)?);
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert!(store.set_if_version("key1".to_owned(), "value6".to_owned(), v2 + 5)?);
+    assert_eq!(store.set("key1".to_owned(), "value7".to_owned())?, v2 + 7);
+
+    Ok(())
+}
+
+// The metadata of a value should follow its overwrites, and its file through a compaction
+#[test]
+fn get_with_metadata() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get_with_metadata("key1".to_owned())?, None);
+
+    let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    let v1 = store.set("key1".to_owned(), "value1".to_owned())?;
+    let (val, meta1) = store.get_with_metadata("key1".to_owned())?.unwrap();
+    assert_eq!(val, "value1");
+    assert_eq!(meta1.version, v1);
+    assert_eq!(meta1.file_id, 1);
+    assert!(meta1.written_at >= before);
+
+    thread::sleep(Duration::from_millis(2));
+    let v2 = store.set("key1".to_owned(), "value2".to_owned())?;
+    let (val, meta2) = store.get_with_metadata("key1".to_owned())?.unwrap();
+    assert_eq!(val, "value2");
+    assert_eq!(meta2.version, v2);
+    assert!(meta2.version > meta1.version);
+    assert!(meta2.written_at > meta1.written_at);
+
+    // compaction moves the value to a new file and keeps the rest
+    store.compact()?;
+    let (val, meta3) = store.get_with_metadata("key1".to_owned())?.unwrap();
+    assert_eq!(val, "value2");
+    assert!(meta3.file_id > meta2.file_id);
+    assert_eq!((meta3.version, meta3.written_at), (meta2.version, meta2.written_at));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get_with_metadata("key1".to_owned())?, None);
+
+    Ok(())
+}
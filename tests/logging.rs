@@ -0,0 +1,52 @@
+use kvs::{KvStore, KvsEngine, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::Mutex;
+use tempfile::TempDir;
+
+// Keeps every record logged by the kvs crate, the logger is global so this file holds a single
+// test
+struct CapturingLogger {
+    records: Mutex<Vec<(Level, String)>>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with("kvs")
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.records.lock().unwrap().push((record.level(), record.args().to_string()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger{records: Mutex::new(Vec::new())};
+
+// A compaction should report its start and its outcome
+#[test]
+fn compaction_is_logged() -> Result<()> {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..3 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    store.compact()?;
+
+    let records = LOGGER.records.lock().unwrap();
+    assert!(records.iter().any(|(level, msg)| {
+        *level == Level::Trace && msg.starts_with("wrote \"key\" to file 1")
+    }));
+    assert!(records.iter().any(|(level, msg)| {
+        *level == Level::Info && msg.starts_with("compaction started: copying files below 2 into file 2")
+    }));
+    assert!(records.iter().any(|(level, msg)| {
+        *level == Level::Info && msg.starts_with("compaction finished: 1 files of")
+    }));
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use kvs::{KvStore, KvsClient, KvsServer, Result, ThreadPool, REQUEST_LOG_TARGET};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::net::TcpListener;
+use std::sync::Mutex;
+use std::thread;
+use tempfile::TempDir;
+
+// Keeps every request logged by the server, the logger is global so this file holds a single
+// test
+struct CapturingLogger {
+    records: Mutex<Vec<(Level, String)>>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target() == REQUEST_LOG_TARGET
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.records.lock().unwrap().push((record.level(), record.args().to_string()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger{records: Mutex::new(Vec::new())};
+
+// Every request should be logged once served, with its key, outcome and latency
+#[test]
+fn requests_are_logged() -> Result<()> {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Info);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.get("key1".to_owned())?;
+    assert!(client.remove("missing".to_owned()).is_err());
+    drop(client);
+    handle.stop();
+
+    let records = LOGGER.records.lock().unwrap();
+    assert_eq!(records.len(), 4);
+    assert!(records.iter().all(|(level, _)| *level == Level::Info));
+    assert!(records[0].1.starts_with("hello ok in "));
+    assert!(records[1].1.starts_with("set \"key1\" ok in "));
+    assert!(records[2].1.starts_with("get \"key1\" ok in "));
+    assert!(records[3].1.starts_with("rm \"missing\" err in "));
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use kvs::{BincodeCodec, KvStore, KvsEngine, Result, RetryingWriter, StoreOptions, WriteRetry};
+use std::io::{self, BufWriter, Write};
+use std::time::Duration;
+use tempfile::TempDir;
+
+// fails its first writes with the given error kind, then takes everything
+struct FlakyWriter {
+    failures: u32,
+    kind: io::ErrorKind,
+    attempts: u32,
+    written: Vec<u8>,
+}
+
+impl FlakyWriter {
+    fn new(failures: u32, kind: io::ErrorKind) -> FlakyWriter {
+        FlakyWriter{failures, kind, attempts: 0, written: Vec::new()}
+    }
+}
+
+impl Write for FlakyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.attempts += 1;
+        if self.attempts <= self.failures {
+            return Err(io::Error::new(self.kind, "flaky storage"));
+        }
+        self.written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+const RETRY: WriteRetry = WriteRetry{max_retries: 3, backoff: Duration::from_millis(1)};
+
+// Writes failing with a transient error should go through once the errors stop, every byte
+// written exactly once
+#[test]
+fn transient_errors_are_retried() {
+    let mut writer = BufWriter::new(RetryingWriter::new(FlakyWriter::new(3, io::ErrorKind::TimedOut), Some(RETRY)));
+    writer.write_all(b"entry").unwrap();
+    writer.flush().unwrap();
+
+    let flaky = writer.get_ref().get_ref();
+    assert_eq!(flaky.attempts, 4);
+    assert_eq!(flaky.written, b"entry");
+}
+
+// Retries should be bounded, and errors that are not transient or come without a policy should
+// pass through on the first attempt
+#[test]
+fn other_errors_pass_through() {
+    let mut writer = BufWriter::new(RetryingWriter::new(FlakyWriter::new(10, io::ErrorKind::StorageFull), Some(RETRY)));
+    writer.write_all(b"entry").unwrap();
+    assert_eq!(writer.flush().unwrap_err().kind(), io::ErrorKind::StorageFull);
+    assert_eq!(writer.get_ref().get_ref().attempts, 4);
+
+    let mut writer = RetryingWriter::new(FlakyWriter::new(1, io::ErrorKind::PermissionDenied), Some(RETRY));
+    assert_eq!(writer.write(b"entry").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(writer.get_ref().attempts, 1);
+
+    let mut writer = RetryingWriter::new(FlakyWriter::new(1, io::ErrorKind::TimedOut), None);
+    assert_eq!(writer.write(b"entry").unwrap_err().kind(), io::ErrorKind::TimedOut);
+    assert_eq!(writer.get_ref().attempts, 1);
+}
+
+// A store should write through the retrying writer whatever its policy
+#[test]
+fn store_with_write_retry() -> Result<()> {
+    for write_retry in [None, Some(RETRY)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = StoreOptions{write_retry, ..StoreOptions::default()};
+        let store = KvStore::<String, String>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.compact()?;
+        drop(store);
+
+        let store = KvStore::<String, String>::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    Ok(())
+}
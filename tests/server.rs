@@ -0,0 +1,780 @@
+use kvs::{BincodeCodec, Durability, Endpoint, Error, Event, KvStore, KvsClient, KvsEngine, KvsServer, MetricsExporter, Result, StoreOptions, ThreadPool, WireFormat, PROTOCOL_VERSION};
+use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::net::UnixListener;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// A stopped server should finish in-flight work and return from `serve`
+#[test]
+fn server_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(2));
+    let handle = server.handle();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        sender.send(server.serve(listener)).unwrap();
+    });
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    handle.stop();
+    receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("server did not shut down in time")?;
+
+    Ok(())
+}
+
+// A client that stays connected without sending anything must not keep a stopped server from
+// returning
+#[test]
+fn server_shutdown_with_idle_client() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(2));
+    let handle = server.handle();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        sender.send(server.serve(listener)).unwrap();
+    });
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let mut idle: KvsClient = KvsClient::connect(addr)?;
+    idle.ping()?;
+
+    handle.stop();
+    receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("server did not shut down in time")?;
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(idle.ping().is_err());
+
+    Ok(())
+}
+
+// Stopping a server before it starts listening should still make it return
+#[test]
+fn server_stopped_before_serving() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+    server.handle().stop();
+
+    server.serve(TcpListener::bind("127.0.0.1:0")?)
+}
+
+// A client sending garbage must not cost the server a worker
+#[test]
+fn server_survives_malformed_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    // a single worker, so a lost worker would leave the server unable to serve anyone
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut garbage = TcpStream::connect(addr)?;
+    garbage.write_all(b"{\"Get\": not json at all")?;
+    garbage.shutdown(Shutdown::Write)?;
+    let mut rest = Vec::new();
+    let _ = garbage.read_to_end(&mut rest);
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let res = KvsClient::connect(addr).and_then(|mut client| {
+            client.set("key1".to_owned(), "value1".to_owned())?;
+            client.get("key1".to_owned())
+        });
+        sender.send(res).unwrap();
+    });
+    let value = receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("server stopped serving after a malformed request")?;
+    assert_eq!(value, Some("value1".to_owned()));
+
+    handle.stop();
+    Ok(())
+}
+
+// get_many should answer in request order with a slot for every key, present or not
+#[test]
+fn client_get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+
+    let keys = vec!["key3".to_owned(), "key2".to_owned(), "key1".to_owned(), "key3".to_owned()];
+    assert_eq!(
+        client.get_many(keys)?,
+        vec![Some("value3".to_owned()), None, Some("value1".to_owned()), Some("value3".to_owned())]
+    );
+    assert_eq!(client.get_many(Vec::new())?, Vec::<Option<String>>::new());
+
+    // the connection keeps serving single requests afterwards
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(client);
+    handle.stop();
+    Ok(())
+}
+
+// stats should report on the store behind the server
+#[test]
+fn client_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    client.remove("key2".to_owned())?;
+
+    let stats = client.stats()?;
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.segments, 1);
+    assert!(stats.uncompacted_bytes > 0);
+    assert!(stats.disk_bytes > 0);
+
+    drop(client);
+    handle.stop();
+    Ok(())
+}
+
+// ping should succeed while the server is up and fail once it is gone
+#[test]
+fn client_ping() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        sender.send(server.serve(listener)).unwrap();
+    });
+
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    client.ping()?;
+    client.ping()?;
+    drop(client);
+
+    handle.stop();
+    receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("server did not shut down in time")?;
+    assert!(KvsClient::<String, String>::connect(addr).and_then(|mut client| client.ping()).is_err());
+
+    Ok(())
+}
+
+// A missing key should come back as the same error the engine returns, not as a plain message
+#[test]
+fn client_remove_missing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    match client.remove("missing".to_owned()) {
+        Err(Error::DoesNotExist{key}) => assert_eq!(key, "\"missing\""),
+        res => panic!("expected a does not exist error, got {:?}", res),
+    }
+
+    drop(client);
+    handle.stop();
+    Ok(())
+}
+
+// Messages larger than a single socket read should still arrive whole
+#[test]
+fn large_value_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let value = (0..1024 * 1024).map(|i| char::from(b'a' + (i % 26) as u8)).collect::<String>();
+    let mut client = KvsClient::connect(addr)?;
+    client.set("big".to_owned(), value.clone())?;
+    client.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("big".to_owned())?, Some(value));
+    assert_eq!(client.get("small".to_owned())?, Some("value".to_owned()));
+
+    drop(client);
+    handle.stop();
+    Ok(())
+}
+
+// A request over the size limit should be rejected without taking the server down
+#[test]
+fn server_rejects_oversized_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1))
+        .with_max_request_bytes(1024);
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    let err = client
+        .set("key1".to_owned(), "x".repeat(4096))
+        .expect_err("oversized request was accepted");
+    assert!(err.to_string().contains("exceeds the limit of 1024 bytes"), "{}", err);
+
+    // the connection is closed, but the server keeps serving new ones
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "x".repeat(512))?;
+    assert_eq!(client.get("key1".to_owned())?, Some("x".repeat(512)));
+
+    drop(client);
+    handle.stop();
+    Ok(())
+}
+
+// A server listening on a unix socket should serve clients connecting to its path, and stop
+// like a tcp one
+#[test]
+fn unix_socket_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let path = temp_dir.path().join("kvs.sock");
+    let listener = UnixListener::bind(&path)?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        sender.send(server.serve_unix(listener)).unwrap();
+    });
+
+    let mut client = KvsClient::connect(Endpoint::Unix(path.clone()))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    drop(client);
+
+    handle.stop();
+    receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("server did not shut down in time")?;
+
+    Ok(())
+}
+
+// A connection can be served from in-memory buffers, without any socket
+#[test]
+fn in_memory_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+
+    let mut requests = Vec::new();
+    for req in [r#"{"Set":{"key":"key1","val":"value1"}}"#, r#"{"Get":{"key":"key1"}}"#] {
+        requests.extend_from_slice(&(req.len() as u32).to_be_bytes());
+        requests.extend_from_slice(req.as_bytes());
+    }
+    let mut responses = Vec::new();
+    server.serve_connection(Cursor::new(requests), &mut responses)?;
+
+    let mut responses = Cursor::new(responses);
+    for expected in [r#""Ack""#, r#"{"Value":"value1"}"#] {
+        let mut len = [0; 4];
+        responses.read_exact(&mut len)?;
+        let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+        responses.read_exact(&mut payload)?;
+        assert_eq!(String::from_utf8(payload).unwrap(), expected);
+    }
+    assert_eq!(responses.position(), responses.get_ref().len() as u64);
+
+    Ok(())
+}
+
+// Mutations should be acknowledged, which a get of a missing key can never be mistaken for
+#[test]
+fn ack_and_missing_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+
+    let mut requests = Vec::new();
+    for req in [
+        r#"{"Set":{"key":"key1","val":"value1"}}"#,
+        r#"{"Get":{"key":"missing"}}"#,
+        r#"{"Rm":{"key":"key1"}}"#,
+        r#"{"Get":{"key":"key1"}}"#,
+        r#""Ping""#,
+    ] {
+        requests.extend_from_slice(&(req.len() as u32).to_be_bytes());
+        requests.extend_from_slice(req.as_bytes());
+    }
+    let mut responses = Vec::new();
+    server.serve_connection(Cursor::new(requests), &mut responses)?;
+
+    let mut responses = Cursor::new(responses);
+    for expected in [r#""Ack""#, r#"{"Value":null}"#, r#""Ack""#, r#"{"Value":null}"#, r#""Ack""#] {
+        let mut len = [0; 4];
+        responses.read_exact(&mut len)?;
+        let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+        responses.read_exact(&mut payload)?;
+        assert_eq!(String::from_utf8(payload).unwrap(), expected);
+    }
+
+    Ok(())
+}
+
+// A client presenting the server's token should be served as usual
+#[test]
+fn auth_token_accepted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1)).with_auth_token("secret");
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.authenticate("secret")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(client);
+    handle.stop();
+    Ok(())
+}
+
+// A wrong token, or no token at all, should fail and get the connection closed
+#[test]
+fn auth_token_rejected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1)).with_auth_token("secret");
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    match client.authenticate("guess") {
+        Err(Error::AuthFailed(_)) => {},
+        res => panic!("expected an auth error, got {:?}", res),
+    }
+    assert!(client.get("key1".to_owned()).is_err());
+
+    let mut client = KvsClient::connect(addr)?;
+    match client.set("key1".to_owned(), "value1".to_owned()) {
+        Err(Error::AuthFailed(_)) => {},
+        res => panic!("expected an auth error, got {:?}", res),
+    }
+    assert!(client.authenticate("secret").is_err());
+
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    client.authenticate("secret")?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    drop(client);
+    handle.stop();
+    Ok(())
+}
+
+// Every request and response should round-trip in every wire codec, errors included
+#[test]
+fn wire_codecs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    for codec in WireFormat::ALL {
+        let mut client: KvsClient = KvsClient::connect_with_codec(addr, codec)?;
+        let key = format!("key_{}", codec);
+        client.set(key.clone(), "value\u{0}\u{ff}".to_owned())?;
+        assert_eq!(client.get(key.clone())?, Some("value\u{0}\u{ff}".to_owned()));
+        assert_eq!(client.get_many(vec![key.clone(), "missing".to_owned()])?, vec![Some("value\u{0}\u{ff}".to_owned()), None]);
+        assert!(client.exists(key.clone())?);
+        client.ping()?;
+        client.remove(key.clone())?;
+        match client.remove(key) {
+            Err(Error::DoesNotExist{..}) => {},
+            res => panic!("expected a missing key error in {}, got {:?}", codec, res),
+        }
+    }
+
+    handle.stop();
+    Ok(())
+}
+
+// A client asking for a codec the server does not accept should fail to connect, the others
+// should still be served
+#[test]
+fn wire_codec_refused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1)).with_wire_codecs(&[WireFormat::Json]);
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    match KvsClient::<String, String>::connect_with_codec(addr, WireFormat::Bincode) {
+        Err(Error::Incompatible(msg)) => assert!(msg.contains("bincode"), "{}", msg),
+        res => panic!("expected an incompatible codec error, got {:?}", res.map(|_| ())),
+    }
+    let mut client: KvsClient = KvsClient::connect_with_codec(addr, WireFormat::Json)?;
+    client.ping()?;
+
+    drop(client);
+    handle.stop();
+    Ok(())
+}
+
+// A hello speaking another protocol version, or none at all, should get an explicit version
+// error rather than fail to decode
+#[test]
+fn protocol_version_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1));
+
+    let types = r#""key_type":"alloc::string::String","value_type":"alloc::string::String""#;
+    let newer = format!(r#"{{"Hello":{{"version":{},{}}}}}"#, PROTOCOL_VERSION + 1, types);
+    let unversioned = format!(r#"{{"Hello":{{{}}}}}"#, types);
+    let current = format!(r#"{{"Hello":{{"version":{},{}}}}}"#, PROTOCOL_VERSION, types);
+    let mut requests = Vec::new();
+    for req in [&newer, &unversioned, &current] {
+        requests.extend_from_slice(&(req.len() as u32).to_be_bytes());
+        requests.extend_from_slice(req.as_bytes());
+    }
+    let mut responses = Vec::new();
+    server.serve_connection(Cursor::new(requests), &mut responses)?;
+
+    let mut responses = Cursor::new(responses);
+    for expected in [
+        format!(r#"{{"Err":{{"ProtocolVersion":{{"client":{},"server":{}}}}}}}"#, PROTOCOL_VERSION + 1, PROTOCOL_VERSION),
+        format!(r#"{{"Err":{{"ProtocolVersion":{{"client":0,"server":{}}}}}}}"#, PROTOCOL_VERSION),
+        r#""Ack""#.to_owned(),
+    ] {
+        let mut len = [0; 4];
+        responses.read_exact(&mut len)?;
+        let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+        responses.read_exact(&mut payload)?;
+        assert_eq!(String::from_utf8(payload).unwrap(), expected);
+    }
+
+    Ok(())
+}
+
+// sends a bare http request and returns the status line and the body of the response
+fn http_get(addr: std::net::SocketAddr, path: &str) -> Result<(String, String)> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").expect("response without a blank line after the headers");
+    let status = head.lines().next().unwrap_or_default().to_owned();
+
+    Ok((status, body.to_owned()))
+}
+
+// A scrape should return the counters and gauges in the Prometheus text format
+#[test]
+fn metrics_exporter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let exporter = MetricsExporter::<String, String, _>::new(engine.clone());
+    let handle = exporter.handle();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        sender.send(exporter.serve(listener)).unwrap();
+    });
+
+    for key_id in 0..3 {
+        engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    engine.set("key0".to_owned(), "value".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    engine.get("key0".to_owned())?;
+    engine.get("key1".to_owned())?;
+
+    let (status, body) = http_get(addr, "/metrics")?;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let mut samples = Vec::new();
+    let mut types = Vec::new();
+    for line in body.lines() {
+        if let Some(comment) = line.strip_prefix("# ") {
+            let mut parts = comment.splitn(3, ' ');
+            let (keyword, name) = (parts.next().unwrap(), parts.next().expect("comment without a metric name"));
+            assert!(keyword == "HELP" || keyword == "TYPE", "unexpected comment {:?}", line);
+            if keyword == "TYPE" {
+                let kind = parts.next().unwrap();
+                assert!(kind == "counter" || kind == "gauge", "unexpected type {:?}", line);
+                types.push(name.to_owned());
+            }
+            continue;
+        }
+        let (series, value) = line.rsplit_once(' ').expect("sample without a value");
+        let value = value.parse::<f64>().expect("value is not a number");
+        let name = series.split('{').next().unwrap();
+        assert!(name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'), "invalid metric name {:?}", name);
+        assert!(types.iter().any(|declared| declared == name), "{} has no TYPE line before it", name);
+        samples.push((series.to_owned(), value));
+    }
+    let sample = |series: &str| samples.iter().find(|(s, _)| s == series).map(|(_, value)| *value);
+    assert_eq!(sample("kvs_operations_total{op=\"get\"}"), Some(2.0));
+    assert_eq!(sample("kvs_operations_total{op=\"set\"}"), Some(4.0));
+    assert_eq!(sample("kvs_operations_total{op=\"remove\"}"), Some(1.0));
+    assert_eq!(sample("kvs_get_hits_total"), Some(1.0));
+    assert_eq!(sample("kvs_get_misses_total"), Some(1.0));
+    assert_eq!(sample("kvs_compactions_total"), Some(0.0));
+    assert_eq!(sample("kvs_live_keys"), Some(2.0));
+    assert!(sample("kvs_uncompacted_bytes").unwrap() > 0.0);
+    assert!(sample("kvs_written_bytes_total").unwrap() > 0.0);
+    assert!(sample("kvs_segments").is_some());
+    assert!(sample("kvs_disk_bytes").is_some());
+
+    let (status, _) = http_get(addr, "/")?;
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+
+    handle.stop();
+    receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("exporter did not shut down in time")?;
+
+    Ok(())
+}
+
+// A watching client should be pushed the changes other clients make to its key
+#[test]
+fn watch_over_network() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(2));
+    let handle = server.handle();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        sender.send(server.serve(listener)).unwrap();
+    });
+
+    let mut events = KvsClient::<String, String>::connect(addr)?.watch("key1".to_owned())?;
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.remove("key1".to_owned())?;
+    drop(client);
+
+    assert_eq!(events.next().unwrap()?, Event::Set("value1".to_owned()));
+    assert_eq!(events.next().unwrap()?, Event::Removed);
+
+    // stopping the server ends the watch
+    handle.stop();
+    assert!(events.next().is_none());
+    receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("server did not shut down in time")?;
+
+    Ok(())
+}
+
+// Keys written to one database should be invisible from the others, a connection starting on
+// database 0 and switching with select
+#[test]
+fn select_database() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let first = KvStore::<String, String>::open(&temp_dir.path().join("db0"))?;
+    let second = KvStore::<String, String>::open(&temp_dir.path().join("db1"))?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(first, ThreadPool::new(2)).with_database(1, second);
+    let handle = server.handle();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        sender.send(server.serve(listener)).unwrap();
+    });
+
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.select(1)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    // a missing database is an error, and the connection stays on the one it was using
+    assert!(client.select(2).is_err());
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    client.select(0)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // a new connection starts on database 0
+    let mut other: KvsClient = KvsClient::connect(addr)?;
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop((client, other));
+
+    handle.stop();
+    receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("server did not shut down in time")?;
+    assert_eq!(KvStore::<String, String>::open(&temp_dir.path().join("db1"))?.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Requests from every worker at once should wait for a helper that is about to be free rather than
+// time out, as long as the engine keeps up. a write stuck after timing out holds on to one of the
+// helpers, so the workers sending requests back to back have to share the other one.
+#[test]
+fn request_timeout_busy_helpers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // a lone write waits out the whole commit window for company
+    let options = StoreOptions{
+        durability: Durability::GroupCommit{window: Duration::from_secs(3), max_writes: 64},
+        ..StoreOptions::default()
+    };
+    let engine = KvStore::<String, String>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(2))
+        .with_request_timeout(Duration::from_millis(500));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(client.set("key1".to_owned(), "value1".to_owned()), Err(Error::Timeout)));
+
+    let start = Instant::now();
+    let clients = (0..2).map(|_| thread::spawn(move || -> Result<()> {
+        let mut client: KvsClient = KvsClient::connect(addr)?;
+        for _ in 0..500 {
+            client.get("key2".to_owned())?;
+        }
+        Ok(())
+    })).collect::<Vec<_>>();
+    for client in clients {
+        client.join().unwrap()?;
+    }
+    // the write was still holding its helper all along
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    handle.stop();
+    Ok(())
+}
+
+// number of threads of this process with the given name
+fn threads_named(name: &str) -> usize {
+    fs::read_dir("/proc/self/task").unwrap()
+        .filter_map(|task| fs::read_to_string(task.unwrap().path().join("comm")).ok())
+        .filter(|comm| comm.trim_end() == name)
+        .count()
+}
+
+// A request the engine is too slow to serve should be answered with a timeout and cost only its
+// own connection. While the helper is still busy with it, requests calling the engine should be
+// refused rather than served on more threads, and the write should still land in the background.
+#[test]
+fn request_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // a lone write waits out the whole commit window for company
+    let options = StoreOptions{
+        durability: Durability::GroupCommit{window: Duration::from_secs(2), max_writes: 64},
+        ..StoreOptions::default()
+    };
+    let engine = KvStore::<String, String>::open_with_options(temp_dir.path(), BincodeCodec, options)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(1))
+        .with_request_timeout(Duration::from_millis(200));
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    let start = Instant::now();
+    match client.set("key1".to_owned(), "value1".to_owned()) {
+        Err(Error::Timeout) => {},
+        res => panic!("expected a timeout, got {:?}", res),
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert!(client.ping().is_err());
+
+    // the only worker is free again while the write is still waiting, and requests that do not
+    // call the engine are served as usual. the others wait out the timeout for the helper.
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    client.ping()?;
+    for _ in 0..3 {
+        let start = Instant::now();
+        match client.get("key1".to_owned()) {
+            Err(Error::Timeout) => {},
+            res => panic!("expected a timeout, got {:?}", res),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+    client.ping()?;
+    assert_eq!(threads_named("kvs-request"), 1);
+
+    // the write that timed out is applied all the same
+    thread::sleep(Duration::from_secs(3));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(client);
+    handle.stop();
+    Ok(())
+}
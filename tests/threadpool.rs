@@ -0,0 +1,227 @@
+use kvs::ThreadPool;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn pool_size() {
+    for size in [1, 4, 10] {
+        assert_eq!(ThreadPool::new(size).size(), size);
+    }
+}
+
+#[test]
+fn pool_runs_all_jobs() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let pool = ThreadPool::new(4);
+    for _ in 0..100 {
+        let counter = Arc::clone(&counter);
+        pool.execute(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    // dropping the pool waits for the queued jobs to finish
+    drop(pool);
+    assert_eq!(counter.load(Ordering::SeqCst), 100);
+}
+
+// Many tiny jobs mostly measure dispatch, with workers receiving concurrently they should all
+// be through well within the limit
+#[test]
+fn pool_runs_many_trivial_jobs() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let pool = ThreadPool::new(8);
+    let start = Instant::now();
+    for _ in 0..100_000 {
+        let counter = Arc::clone(&counter);
+        pool.execute(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    drop(pool);
+    assert_eq!(counter.load(Ordering::SeqCst), 100_000);
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+// Workers should be named after their index, so that they can be told apart in backtraces
+#[test]
+fn pool_names_workers() {
+    let pool = ThreadPool::new(2);
+    let (sender, receiver) = mpsc::channel();
+    pool.execute(move || {
+        sender.send(thread::current().name().map(str::to_owned)).unwrap();
+    });
+
+    let name = receiver.recv_timeout(Duration::from_secs(10)).expect("job did not complete");
+    assert!(name.expect("worker has no name").starts_with("kvs-worker-"));
+}
+
+// A panicking job should not cost the pool its worker
+#[test]
+fn pool_survives_panicking_job() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let pool = ThreadPool::new(1);
+    pool.execute(|| panic!("job failed"));
+    for _ in 0..5 {
+        let counter = Arc::clone(&counter);
+        pool.execute(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    drop(pool);
+    assert_eq!(counter.load(Ordering::SeqCst), 5);
+}
+
+// join_pending should return once every queued job is done, panicked ones included, and leave
+// the pool ready for more
+#[test]
+fn pool_join_pending() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let pool = ThreadPool::new(2);
+    pool.join_pending();
+
+    pool.execute(|| panic!("job failed"));
+    for _ in 0..6 {
+        let counter = Arc::clone(&counter);
+        pool.execute(move || {
+            thread::sleep(Duration::from_millis(50));
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    assert!(pool.pending() > 0);
+    pool.join_pending();
+    assert_eq!(counter.load(Ordering::SeqCst), 6);
+    assert_eq!(pool.pending(), 0);
+
+    let counter_clone = Arc::clone(&counter);
+    pool.execute(move || {
+        counter_clone.fetch_add(1, Ordering::SeqCst);
+    });
+    pool.join_pending();
+    assert_eq!(counter.load(Ordering::SeqCst), 7);
+}
+
+// Runs `jobs` sleeping jobs on the pool and returns how many of them ran at the same time
+fn max_concurrency(pool: &ThreadPool, jobs: usize) -> usize {
+    let running = Arc::new(AtomicUsize::new(0));
+    let max = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..jobs {
+        let running = Arc::clone(&running);
+        let max = Arc::clone(&max);
+        let sender = sender.clone();
+        pool.execute(move || {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            running.fetch_sub(1, Ordering::SeqCst);
+            sender.send(()).unwrap();
+        });
+    }
+    for _ in 0..jobs {
+        receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("job did not complete");
+    }
+    max.load(Ordering::SeqCst)
+}
+
+#[test]
+fn pool_grow() {
+    let mut pool = ThreadPool::new(2);
+    assert_eq!(max_concurrency(&pool, 8), 2);
+
+    pool.resize(4);
+    assert_eq!(pool.size(), 4);
+    assert_eq!(max_concurrency(&pool, 8), 4);
+}
+
+#[test]
+fn pool_shrink() {
+    let mut pool = ThreadPool::new(4);
+    assert_eq!(max_concurrency(&pool, 8), 4);
+
+    pool.resize(1);
+    assert_eq!(pool.size(), 1);
+    assert_eq!(max_concurrency(&pool, 4), 1);
+
+    // a shrunk pool still drains its queue on drop
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..10 {
+        let counter = Arc::clone(&counter);
+        pool.execute(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    drop(pool);
+    assert_eq!(counter.load(Ordering::SeqCst), 10);
+}
+
+// Occupies the only worker of the pool until the returned sender is dropped or sent to
+fn block_worker(pool: &ThreadPool) -> mpsc::Sender<()> {
+    let (started_sender, started_receiver) = mpsc::channel();
+    let (release_sender, release_receiver) = mpsc::channel::<()>();
+    pool.execute(move || {
+        started_sender.send(()).unwrap();
+        let _ = release_receiver.recv();
+    });
+    started_receiver
+        .recv_timeout(Duration::from_secs(10))
+        .expect("job did not start");
+    release_sender
+}
+
+#[test]
+fn pool_rejects_jobs_when_queue_is_full() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let pool = ThreadPool::with_capacity(1, 2);
+    let release = block_worker(&pool);
+
+    for _ in 0..2 {
+        let counter = Arc::clone(&counter);
+        assert!(pool.try_execute(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }).is_ok());
+    }
+    let rejected = {
+        let counter = Arc::clone(&counter);
+        pool.try_execute(move || {
+            counter.fetch_add(10, Ordering::SeqCst);
+        })
+    };
+    let job = rejected.expect_err("queue should be full");
+
+    // the rejected job is handed back intact
+    drop(release);
+    drop(pool);
+    assert_eq!(counter.load(Ordering::SeqCst), 2);
+    job();
+    assert_eq!(counter.load(Ordering::SeqCst), 12);
+}
+
+#[test]
+fn pool_execute_blocks_until_queue_has_room() {
+    let pool = Arc::new(ThreadPool::with_capacity(1, 1));
+    let release = block_worker(&pool);
+    pool.execute(|| {});
+
+    let (sender, receiver) = mpsc::channel();
+    let blocked = {
+        let pool = Arc::clone(&pool);
+        thread::spawn(move || {
+            pool.execute(|| {});
+            sender.send(()).unwrap();
+        })
+    };
+    assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+
+    drop(release);
+    receiver
+        .recv_timeout(Duration::from_secs(10))
+        .expect("execute did not return after a slot freed up");
+    blocked.join().unwrap();
+}
@@ -0,0 +1,37 @@
+use kvs::rustls::RootCertStore;
+use kvs::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use kvs::{KvStore, KvsClient, KvsServer, Result, ThreadPool};
+use std::net::TcpListener;
+use std::thread;
+use tempfile::TempDir;
+
+// A client trusting the server's self-signed certificate should round-trip requests over tls and
+// close the session cleanly, and a client that does not trust it should fail to connect
+#[test]
+fn tls_round_trip() -> Result<()> {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let cert = certified.cert.der().clone();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<String, String>::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::<String, String, _>::new(engine, ThreadPool::new(2)).with_tls(vec![cert.clone()], key)?;
+    let handle = server.handle();
+    thread::spawn(move || server.serve(listener));
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let mut client = KvsClient::connect_tls(addr, "localhost", roots)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    // ends the session with a close_notify, which the server reads as the end of the stream
+    client.close()?;
+
+    assert!(KvsClient::<String, String>::connect_tls(addr, "localhost", RootCertStore::empty()).is_err());
+
+    handle.stop();
+    Ok(())
+}